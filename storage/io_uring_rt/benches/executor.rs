@@ -0,0 +1,81 @@
+use std::{io::Write, path::PathBuf, rc::Rc};
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use uring_rt::uring::{
+    fs::File,
+    rt::{Runtime, RuntimeBuilder},
+};
+
+const TASKS: usize = 256;
+const BLOCK: usize = 4096;
+
+/// Spawn one task per read, so the cost of scheduling is measured with IO.
+fn bench_spawn_read(c: &mut Criterion) {
+    let path = PathBuf::from("data").join("bench_executor");
+    scopeguard::defer! {
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    };
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+    let mut file = std::fs::File::create(path.clone()).unwrap();
+    file.write_all(&vec![1u8; TASKS * BLOCK]).unwrap();
+    drop(file);
+
+    let mut group = c.benchmark_group("spawn-read-4k");
+
+    let rt = RuntimeBuilder::new().entries(TASKS as u32).build().unwrap();
+    let file = Rc::new(rt.block_on(File::open(path.clone())).unwrap());
+    group.bench_function("tokio", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                let handles = (0..TASKS)
+                    .map(|i| {
+                        let file = file.clone();
+                        tokio::task::spawn_local(async move {
+                            file.read_at(vec![0u8; BLOCK], (i * BLOCK) as u64).await
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for h in handles {
+                    h.await.unwrap().0.unwrap();
+                }
+            })
+        })
+    });
+    close(&rt, file);
+
+    let rt = RuntimeBuilder::new()
+        .entries(TASKS as u32)
+        .executor(true)
+        .build()
+        .unwrap();
+    let file = Rc::new(rt.block_on(File::open(path.clone())).unwrap());
+    group.bench_function("executor", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                let handles = (0..TASKS)
+                    .map(|i| {
+                        let file = file.clone();
+                        uring_rt::spawn_local(async move {
+                            file.read_at(vec![0u8; BLOCK], (i * BLOCK) as u64).await
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for h in handles {
+                    h.await.unwrap().0.unwrap();
+                }
+            })
+        })
+    });
+    close(&rt, file);
+
+    group.finish();
+}
+
+fn close(rt: &Runtime, file: Rc<File>) {
+    let file = Rc::into_inner(file).unwrap();
+    rt.block_on(file.close()).unwrap();
+}
+
+criterion_group!(benches, bench_spawn_read);
+criterion_main!(benches);
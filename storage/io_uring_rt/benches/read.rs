@@ -4,7 +4,10 @@ use std::{
 };
 
 use criterion::{Criterion, criterion_group, criterion_main};
-use uring_rt::uring::rt::default_rt;
+use uring_rt::uring::{
+    buf::FixedBufPool,
+    rt::{RuntimeBuilder, SubmitPolicy, default_rt},
+};
 
 fn gen_buffer() -> Vec<u8> {
     let mut buf = vec![0u8; 4 * 1024 * 1024]; // 4MB
@@ -62,5 +65,112 @@ fn bench_uring_read(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_block_read, bench_uring_read);
+fn bench_concurrent_read_4k(c: &mut Criterion) {
+    const READS: usize = 1024;
+    const BLOCK: usize = 4096;
+
+    let path = PathBuf::from("data").join("bench_concurrent_read");
+    scopeguard::defer! {
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    };
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+    let buf = gen_buffer(); // 4MB, just READS * BLOCK
+    let mut file = std::fs::File::create(path.clone()).unwrap();
+    file.write_all(&buf).unwrap();
+    drop(file);
+
+    let mut group = c.benchmark_group("concurrent-read-4k");
+    for policy in [
+        SubmitPolicy::Immediate,
+        SubmitPolicy::Threshold(64),
+        SubmitPolicy::OnPark,
+    ] {
+        let rt = RuntimeBuilder::new()
+            .entries(READS as u32)
+            .submit_policy(policy)
+            .build()
+            .unwrap();
+        let file = rt.block_on(uring_rt::uring::fs::File::open(path.clone()));
+        let file = file.unwrap();
+
+        let read_all = || {
+            rt.block_on(async {
+                let reads = (0..READS).map(|i| file.read_at(vec![0u8; BLOCK], (i * BLOCK) as u64));
+                futures::future::join_all(reads).await;
+            })
+        };
+
+        let before = rt.submit_calls();
+        read_all();
+        println!(
+            "{:?}: {} io_uring_enter calls for {} reads",
+            policy,
+            rt.submit_calls() - before,
+            READS
+        );
+
+        group.bench_function(format!("{:?}", policy), |b| b.iter(read_all));
+        rt.block_on(file.close()).unwrap();
+    }
+    group.finish();
+}
+
+fn bench_fixed_read(c: &mut Criterion) {
+    const READS: usize = 64;
+
+    let path = PathBuf::from("data").join("bench_fixed_read");
+    scopeguard::defer! {
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    };
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+    let buf = gen_buffer();
+    let mut file = std::fs::File::create(path.clone()).unwrap();
+    file.write_all(&buf).unwrap();
+    drop(file);
+
+    let rt = RuntimeBuilder::new().entries(READS as u32).build().unwrap();
+    let file = rt.block_on(uring_rt::uring::fs::File::open(path.clone()));
+    let file = file.unwrap();
+
+    let mut group = c.benchmark_group("fixed-read");
+    for block in [4096, 16384, 65536] {
+        let pool = FixedBufPool::new((0..READS).map(|_| Vec::with_capacity(block)));
+        rt.block_on(async { pool.register() }).unwrap();
+
+        group.bench_function(format!("read-{}", block), |b| {
+            b.iter(|| {
+                rt.block_on(async {
+                    let reads =
+                        (0..READS).map(|i| file.read_at(vec![0u8; block], (i * block) as u64));
+                    futures::future::join_all(reads).await;
+                })
+            })
+        });
+        group.bench_function(format!("read-fixed-{}", block), |b| {
+            b.iter(|| {
+                rt.block_on(async {
+                    let reads = (0..READS).map(|i| {
+                        let buf = pool.try_next(block).unwrap();
+                        file.read_fixed_at(buf, (i * block) as u64)
+                    });
+                    futures::future::join_all(reads).await;
+                })
+            })
+        });
+
+        rt.block_on(async { pool.unregister() }).unwrap();
+    }
+    group.finish();
+    rt.block_on(file.close()).unwrap();
+}
+
+criterion_group!(
+    benches,
+    bench_block_read,
+    bench_uring_read,
+    bench_concurrent_read_4k,
+    bench_fixed_read
+);
 criterion_main!(benches);
@@ -0,0 +1,152 @@
+//! Errors of the runtime and its operations.
+
+use std::io;
+
+use rustix::io_uring::IoringOp;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors of the runtime and its operations.
+///
+/// It converts from and into [`io::Error`], so `?` works in functions returning
+/// either of them. Errors created inside the runtime, like [`Error::NoRuntime`],
+/// survive a round trip through [`io::Error`].
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// Called outside of [`Runtime::block_on`](crate::uring::rt::Runtime::block_on),
+    /// or the runtime has been dropped or shut down.
+    #[error("no runtime, or it is shut down")]
+    NoRuntime,
+
+    /// Kernel does not accept more entries, the completion queue is full and
+    /// nothing could be reaped.
+    #[error("the ring is full")]
+    RingFull,
+
+    /// The table of ops has no free slot, see
+    /// [`RuntimeBuilder::max_ops`](crate::uring::rt::RuntimeBuilder::max_ops).
+    #[error("the table of ops is full")]
+    OpTableFull,
+
+    /// Kernel does not support the opcode, or it is disabled by
+    /// [`RuntimeBuilder::disable_opcode`](crate::uring::rt::RuntimeBuilder::disable_opcode).
+    #[error("opcode {0:?} is not supported")]
+    UnsupportedOpcode(IoringOp),
+
+    /// The feature is not supported, e.g. by the thread pool backend.
+    #[error("{0} is not supported")]
+    Unsupported(&'static str),
+
+    /// The operation completed with `ECANCELED`.
+    #[error("operation cancelled")]
+    Cancelled,
+
+    /// The deadline of the operation passed, see `Prepared::timeout`.
+    #[error("operation timed out")]
+    TimedOut,
+
+    #[error(transparent)]
+    Io(io::Error),
+}
+
+impl Error {
+    /// The kind of the [`io::Error`] it converts into.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::NoRuntime => io::ErrorKind::Other,
+            Error::RingFull | Error::OpTableFull => io::ErrorKind::ResourceBusy,
+            Error::UnsupportedOpcode(_) | Error::Unsupported(_) => io::ErrorKind::Unsupported,
+            Error::Cancelled => io::ErrorKind::Other,
+            Error::TimedOut => io::ErrorKind::TimedOut,
+            Error::Io(e) => e.kind(),
+        }
+    }
+
+    /// The errno, `ECANCELED` for [`Error::Cancelled`].
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Error::Cancelled => Some(rustix::io::Errno::CANCELED.raw_os_error()),
+            Error::Io(e) => e.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    /// A copy of the error, an [`Error::Io`] keeps the errno, or the kind and
+    /// message.
+    pub(crate) fn duplicate(&self) -> Error {
+        match self {
+            Error::NoRuntime => Error::NoRuntime,
+            Error::RingFull => Error::RingFull,
+            Error::OpTableFull => Error::OpTableFull,
+            Error::UnsupportedOpcode(op) => Error::UnsupportedOpcode(*op),
+            Error::Unsupported(what) => Error::Unsupported(what),
+            Error::Cancelled => Error::Cancelled,
+            Error::TimedOut => Error::TimedOut,
+            Error::Io(e) => Error::Io(match e.raw_os_error() {
+                Some(code) => io::Error::from_raw_os_error(code),
+                None => io::Error::new(e.kind(), e.to_string()),
+            }),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            let inner = e.into_inner().unwrap();
+            return *inner.downcast::<Error>().unwrap();
+        }
+
+        match e.raw_os_error() {
+            Some(code) if code == rustix::io::Errno::CANCELED.raw_os_error() => Error::Cancelled,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<rustix::io::Errno> for Error {
+    fn from(errno: rustix::io::Errno) -> Self {
+        io::Error::from(errno).into()
+    }
+}
+
+impl From<std::ffi::NulError> for Error {
+    fn from(e: std::ffi::NulError) -> Self {
+        io::Error::from(e).into()
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            Error::Cancelled => rustix::io::Errno::CANCELED.into(),
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::Error;
+
+    #[test]
+    fn test_io_round_trip() {
+        let err = Error::from(io::Error::from(Error::NoRuntime));
+        assert!(matches!(err, Error::NoRuntime));
+
+        let err = Error::from(io::Error::from(rustix::io::Errno::CANCELED));
+        assert!(matches!(err, Error::Cancelled));
+        assert_eq!(
+            io::Error::from(err).raw_os_error(),
+            Some(rustix::io::Errno::CANCELED.raw_os_error())
+        );
+
+        let err = Error::from(io::Error::from(rustix::io::Errno::NOENT));
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::NotFound);
+    }
+}
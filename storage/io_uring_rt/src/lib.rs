@@ -1,8 +1,14 @@
 #![allow(unused)]
 
+pub mod error;
 pub mod uring;
 pub mod utils;
 
+pub use error::{Error, Result};
+
+pub use uring::task::{JoinHandle, spawn, spawn_local};
+pub use uring::time;
+
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
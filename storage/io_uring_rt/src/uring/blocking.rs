@@ -0,0 +1,217 @@
+//! A thread pool running blocking syscalls, for ops which kernel does not
+//! support on the ring, or every op of the thread pool backend.
+
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::VecDeque,
+    io,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{
+        Arc, Condvar, Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, oneshot};
+
+/// Max number of threads of the pool, they are spawned on demand.
+const MAX_THREADS: usize = 64;
+
+/// Idle threads exit after this.
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/// Polls the result of the task, and completes the op with it.
+type PollResult<T> = Box<dyn FnMut(&mut T, &mut Context<'_>) -> Poll<io::Result<u32>>>;
+
+struct Pool {
+    state: Mutex<PoolState>,
+    cond: Condvar,
+}
+
+struct PoolState {
+    queue: VecDeque<Task>,
+    threads: usize,
+    idle: usize,
+}
+
+static POOL: OnceLock<Arc<Pool>> = OnceLock::new();
+
+impl Pool {
+    fn get() -> &'static Arc<Pool> {
+        POOL.get_or_init(|| {
+            Arc::new(Pool {
+                state: Mutex::new(PoolState {
+                    queue: VecDeque::new(),
+                    threads: 0,
+                    idle: 0,
+                }),
+                cond: Condvar::new(),
+            })
+        })
+    }
+
+    fn execute(self: &Arc<Self>, task: Task) {
+        let mut state = self.state.lock().unwrap();
+        state.queue.push_back(task);
+
+        // Tasks may wait for each other, e.g. both ends of a pipe, so every task
+        // gets a thread while it could.
+        if state.queue.len() > state.idle && state.threads < MAX_THREADS {
+            let id = state.threads;
+            let pool = self.clone();
+            let res = std::thread::Builder::new()
+                .name(format!("uring-blocking-{id}"))
+                .spawn(move || pool.run());
+            match res {
+                Ok(_) => state.threads += 1,
+                Err(e) => tracing::error!("failed to spawn blocking thread: {}", e),
+            }
+        }
+        if state.idle > 0 {
+            self.cond.notify_one();
+        }
+    }
+
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(task) = state.queue.pop_front() {
+                drop(state);
+                // The result sender is dropped with the task, so the op resolves
+                // to an error.
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(task));
+                state = self.state.lock().unwrap();
+                continue;
+            }
+
+            state.idle += 1;
+            let (guard, res) = self.cond.wait_timeout(state, KEEP_ALIVE).unwrap();
+            state = guard;
+            state.idle -= 1;
+            if res.timed_out() && state.queue.is_empty() {
+                state.threads -= 1;
+                return;
+            }
+        }
+    }
+}
+
+thread_local! {
+    /// Data of dropped ops still running on the pool, which may use its memory.
+    static ORPHANS: RefCell<Vec<Orphan>> = const { RefCell::new(Vec::new()) };
+}
+
+struct Orphan {
+    data: Option<Box<dyn Any>>,
+    done: Arc<AtomicBool>,
+}
+
+impl Drop for Orphan {
+    fn drop(&mut self) {
+        // Only when the thread exits, the memory is leaked rather than freed in use.
+        if !self.done.load(Ordering::Acquire) {
+            std::mem::forget(self.data.take());
+        }
+    }
+}
+
+/// An op running on the pool, the data of the op stays on the current thread.
+///
+/// If it is dropped before the task finishes, the data is kept until then, like
+/// ignored ops of the ring.
+pub(crate) struct Blocking<T: 'static> {
+    data: Option<T>,
+    poll: PollResult<T>,
+    done: Arc<AtomicBool>,
+}
+
+/// Run `f` on the pool, its result is given to `complete` with the data of the op
+/// on the current thread, which returns the result of the completion.
+///
+/// `permit` of the in-flight limit is held until `f` returns.
+pub(crate) fn spawn<T, R, F, G>(
+    data: T,
+    f: F,
+    complete: G,
+    permit: Option<OwnedSemaphorePermit>,
+) -> Blocking<T>
+where
+    R: Send + 'static,
+    F: FnOnce() -> io::Result<R> + Send + 'static,
+    G: FnOnce(&mut T, R) -> u32 + 'static,
+{
+    ORPHANS.with(|orphans| {
+        orphans
+            .borrow_mut()
+            .retain(|orphan| !orphan.done.load(Ordering::Acquire))
+    });
+
+    let (tx, mut rx) = oneshot::channel();
+    let done = Arc::new(AtomicBool::new(false));
+    let task_done = done.clone();
+    Pool::get().execute(Box::new(move || {
+        let res = f();
+        drop(permit);
+        task_done.store(true, Ordering::Release);
+        let _ = tx.send(res);
+    }));
+
+    let mut complete = Some(complete);
+    Blocking {
+        data: Some(data),
+        poll: Box::new(move |data, cx| {
+            let res = ready!(Pin::new(&mut rx).poll(cx))
+                .unwrap_or_else(|_| Err(io::Error::other("blocking task panicked")));
+            let complete = complete.take().expect("polled after completion");
+            Poll::Ready(res.map(|r| complete(data, r)))
+        }),
+        done,
+    }
+}
+
+impl<T> Blocking<T> {
+    pub(crate) fn poll(&mut self, cx: &mut Context<'_>) -> Poll<(T, io::Result<u32>)> {
+        let data = self.data.as_mut().expect("polled after completion");
+        let res = ready!((self.poll)(data, cx));
+        Poll::Ready((self.data.take().unwrap(), res))
+    }
+}
+
+impl<T> Drop for Blocking<T> {
+    fn drop(&mut self) {
+        if let Some(data) = self.data.take()
+            && !self.done.load(Ordering::Acquire)
+        {
+            ORPHANS.with(|orphans| {
+                orphans.borrow_mut().push(Orphan {
+                    data: Some(Box::new(data)),
+                    done: self.done.clone(),
+                })
+            });
+        }
+    }
+}
+
+/// A pointer to the memory of an op, which is kept alive until the task finishes.
+#[derive(Clone, Copy)]
+pub(crate) struct SendPtr(pub(crate) *mut u8);
+
+// Safety: the memory is only used by the task, see `Blocking`.
+unsafe impl Send for SendPtr {}
+
+impl SendPtr {
+    pub(crate) fn get(self) -> *mut u8 {
+        self.0
+    }
+}
+
+/// The error of ops which could not run on the pool, e.g. on direct descriptors.
+pub(crate) fn unsupported() -> io::Error {
+    crate::Error::Unsupported("the op on the blocking pool").into()
+}
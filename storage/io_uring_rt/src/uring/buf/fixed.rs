@@ -0,0 +1,376 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    future::poll_fn,
+    io,
+    ops::{Deref, DerefMut},
+    rc::{Rc, Weak},
+    task::{Poll, Waker},
+};
+
+use rustix_uring::types::iovec;
+
+use crate::uring::driver::{CONTEXT, Driver};
+
+/// Buffers registered with the ring, shared by the collection and checked out
+/// buffers.
+struct Buffers {
+    bufs: Vec<Vec<u8>>,
+    /// Length of the data in each buffer, kept when it is checked in.
+    lens: Vec<usize>,
+    checked_out: Vec<bool>,
+
+    /// Indices of free buffers by capacity, only used by the pool.
+    free: BTreeMap<usize, Vec<u16>>,
+    waiters: Vec<Waker>,
+
+    /// The driver they are registered with.
+    registered: Option<Weak<RefCell<Driver>>>,
+}
+
+impl Buffers {
+    fn new(bufs: impl IntoIterator<Item = Vec<u8>>) -> Self {
+        let mut lens = vec![];
+        let bufs = bufs
+            .into_iter()
+            .map(|mut buf| {
+                lens.push(buf.len());
+                // Fully initialized, so reads could fill the whole capacity.
+                buf.resize(buf.capacity(), 0);
+                buf
+            })
+            .collect::<Vec<_>>();
+        assert!(bufs.len() <= u16::MAX as usize, "too many buffers");
+
+        let mut free = BTreeMap::<usize, Vec<u16>>::new();
+        for (i, buf) in bufs.iter().enumerate().rev() {
+            free.entry(buf.len()).or_default().push(i as u16);
+        }
+
+        Self {
+            checked_out: vec![false; bufs.len()],
+            bufs,
+            lens,
+            free,
+            waiters: vec![],
+            registered: None,
+        }
+    }
+
+    fn register(&mut self) -> io::Result<()> {
+        if self.registered.is_some() {
+            return Err(rustix::io::Errno::BUSY.into());
+        }
+
+        let handle = CONTEXT.with(|cx| cx.handle().ok_or_else(|| cx.no_driver()))?;
+        let iovecs = self
+            .bufs
+            .iter_mut()
+            .map(|buf| iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            })
+            .collect::<Vec<_>>();
+
+        // Safety: buffers are kept alive until they are unregistered, or by
+        // checked out buffers which may be used by ops.
+        unsafe {
+            handle
+                .borrow()
+                .uring
+                .submitter()
+                .register_buffers(&iovecs)?
+        };
+        self.registered = Some(Rc::downgrade(&handle));
+        Ok(())
+    }
+
+    fn unregister(&mut self) -> io::Result<()> {
+        let Some(driver) = self.registered.take() else {
+            return Ok(());
+        };
+        if let Some(driver) = driver.upgrade() {
+            driver.borrow().uring.submitter().unregister_buffers()?;
+        }
+        Ok(())
+    }
+
+    fn check_out(this: &Rc<RefCell<Self>>, index: u16) -> Option<FixedBuf> {
+        let mut buffers = this.borrow_mut();
+        let i = index as usize;
+        if i >= buffers.bufs.len() || buffers.checked_out[i] {
+            return None;
+        }
+
+        buffers.checked_out[i] = true;
+        let cap = buffers.bufs[i].len();
+        if let Some(free) = buffers.free.get_mut(&cap) {
+            free.retain(|&j| j != index);
+        }
+
+        Some(FixedBuf {
+            buffers: this.clone(),
+            index,
+            ptr: buffers.bufs[i].as_mut_ptr(),
+            cap,
+            len: buffers.lens[i],
+        })
+    }
+
+    /// Index of the smallest free buffer whose capacity is at least `cap`.
+    fn find_free(&self, cap: usize) -> Option<u16> {
+        self.free
+            .range(cap..)
+            .find_map(|(_, free)| free.last().copied())
+    }
+
+    fn check_in(&mut self, index: u16, len: usize) {
+        let i = index as usize;
+        self.checked_out[i] = false;
+        self.lens[i] = len;
+        self.free.entry(self.bufs[i].len()).or_default().push(index);
+
+        for waker in self.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for Buffers {
+    fn drop(&mut self) {
+        let Some(driver) = self.registered.take().and_then(|d| d.upgrade()) else {
+            return;
+        };
+        // The driver may be busy if the last buffer is dropped by a completion,
+        // kernel keeps the pages pinned until the ring is dropped then.
+        if let Ok(driver) = driver.try_borrow() {
+            let _ = driver.uring.submitter().unregister_buffers();
+        }
+    }
+}
+
+/// A set of buffers registered with the ring, checked out by index.
+///
+/// A ring has only one set of registered buffers, so it is either a registry or
+/// a [`FixedBufPool`].
+#[derive(Clone)]
+pub struct FixedBufRegistry {
+    buffers: Rc<RefCell<Buffers>>,
+}
+
+impl FixedBufRegistry {
+    /// The capacity of each buffer is the capacity of the vector, and its data is
+    /// kept.
+    pub fn new(bufs: impl IntoIterator<Item = Vec<u8>>) -> Self {
+        Self {
+            buffers: Rc::new(RefCell::new(Buffers::new(bufs))),
+        }
+    }
+
+    /// Register the buffers with the ring of the current runtime.
+    ///
+    /// Fails with `EBUSY` if the ring already has registered buffers.
+    pub fn register(&self) -> crate::Result<()> {
+        Ok(self.buffers.borrow_mut().register()?)
+    }
+
+    pub fn unregister(&self) -> crate::Result<()> {
+        Ok(self.buffers.borrow_mut().unregister()?)
+    }
+
+    /// Check out the buffer at `index`, `None` if it is checked out already.
+    pub fn check_out(&self, index: u16) -> Option<FixedBuf> {
+        Buffers::check_out(&self.buffers, index)
+    }
+}
+
+/// A pool of buffers registered with the ring, checked out by capacity.
+#[derive(Clone)]
+pub struct FixedBufPool {
+    buffers: Rc<RefCell<Buffers>>,
+}
+
+impl FixedBufPool {
+    /// The capacity of each buffer is the capacity of the vector, and its data is
+    /// kept.
+    pub fn new(bufs: impl IntoIterator<Item = Vec<u8>>) -> Self {
+        Self {
+            buffers: Rc::new(RefCell::new(Buffers::new(bufs))),
+        }
+    }
+
+    /// Register the buffers with the ring of the current runtime.
+    ///
+    /// Fails with `EBUSY` if the ring already has registered buffers.
+    pub fn register(&self) -> crate::Result<()> {
+        Ok(self.buffers.borrow_mut().register()?)
+    }
+
+    pub fn unregister(&self) -> crate::Result<()> {
+        Ok(self.buffers.borrow_mut().unregister()?)
+    }
+
+    /// Check out the smallest free buffer whose capacity is at least `cap`.
+    pub fn try_next(&self, cap: usize) -> Option<FixedBuf> {
+        let index = self.buffers.borrow().find_free(cap)?;
+        Buffers::check_out(&self.buffers, index)
+    }
+
+    /// Like [`FixedBufPool::try_next`], but wait for a buffer to be checked in.
+    ///
+    /// Fails if no buffer in the pool is large enough.
+    pub async fn next(&self, cap: usize) -> crate::Result<FixedBuf> {
+        if !self
+            .buffers
+            .borrow()
+            .bufs
+            .iter()
+            .any(|buf| buf.len() >= cap)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no buffer in the pool is large enough",
+            )
+            .into());
+        }
+
+        poll_fn(|cx| match self.try_next(cap) {
+            Some(buf) => Poll::Ready(Ok(buf)),
+            None => {
+                self.buffers.borrow_mut().waiters.push(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// A buffer checked out from a [`FixedBufRegistry`] or [`FixedBufPool`].
+///
+/// It derefs to the data, whose length is at most its capacity. It is checked
+/// in when dropped, after the op using it has completed.
+pub struct FixedBuf {
+    buffers: Rc<RefCell<Buffers>>,
+    index: u16,
+    ptr: *mut u8,
+    cap: usize,
+    len: usize,
+}
+
+impl FixedBuf {
+    /// Index of the buffer in the registered buffers.
+    pub fn buf_index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// # Panics
+    ///
+    /// Panics if it exceeds the capacity.
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        assert!(self.len + data.len() <= self.cap, "exceeds the capacity");
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.add(self.len), data.len());
+        }
+        self.len += data.len();
+    }
+
+    /// The whole buffer, ops read into it.
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub(crate) fn set_len(&mut self, len: usize) {
+        assert!(len <= self.cap);
+        self.len = len;
+    }
+}
+
+impl Deref for FixedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for FixedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for FixedBuf {
+    fn drop(&mut self) {
+        self.buffers.borrow_mut().check_in(self.index, self.len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::uring::rt::default_rt;
+
+    use super::{FixedBufPool, FixedBufRegistry};
+
+    #[test]
+    fn test_registry_check_out() {
+        let registry = FixedBufRegistry::new([b"hello".to_vec(), Vec::with_capacity(16)]);
+
+        let mut buf = registry.check_out(1).unwrap();
+        assert!(registry.check_out(1).is_none());
+        assert!(registry.check_out(2).is_none());
+        assert_eq!(buf.capacity(), 16);
+        assert!(buf.is_empty());
+        buf.extend_from_slice(b"world");
+        drop(buf);
+
+        // Data is kept after checked in.
+        assert_eq!(&registry.check_out(1).unwrap()[..], b"world");
+        assert_eq!(&registry.check_out(0).unwrap()[..], b"hello");
+    }
+
+    #[test]
+    fn test_pool_next() {
+        let pool = FixedBufPool::new([Vec::with_capacity(4096), Vec::with_capacity(65536)]);
+
+        let small = pool.try_next(1024).unwrap();
+        assert_eq!(small.capacity(), 4096);
+        let large = pool.try_next(1024).unwrap();
+        assert_eq!(large.capacity(), 65536);
+        assert!(pool.try_next(1024).is_none());
+
+        default_rt().unwrap().block_on(async {
+            assert!(pool.next(1 << 20).await.is_err());
+
+            let waiting = pool.next(4096);
+            drop(small);
+            assert_eq!(waiting.await.unwrap().capacity(), 4096);
+        });
+        drop(large);
+    }
+
+    #[test]
+    fn test_register_twice() {
+        default_rt().unwrap().block_on(async {
+            let pool = FixedBufPool::new([Vec::with_capacity(4096)]);
+            pool.register().unwrap();
+
+            let registry = FixedBufRegistry::new([Vec::with_capacity(4096)]);
+            let err = registry.register().unwrap_err();
+            assert_eq!(
+                err.raw_os_error(),
+                Some(rustix::io::Errno::BUSY.raw_os_error())
+            );
+
+            pool.unregister().unwrap();
+            registry.register().unwrap();
+        });
+    }
+}
@@ -0,0 +1,5 @@
+mod fixed;
+mod ring;
+
+pub use fixed::{FixedBuf, FixedBufPool, FixedBufRegistry};
+pub use ring::{BorrowedBuf, BufRing, BufRingBuilder};
@@ -0,0 +1,291 @@
+use std::{
+    alloc::Layout,
+    cell::{Cell, RefCell},
+    io,
+    ops::Deref,
+    rc::{Rc, Weak},
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use rustix_uring::types::BufRingEntry;
+
+use crate::uring::driver::{CONTEXT, Driver};
+
+/// Alignment of the ring memory, kernel requires it to be page aligned.
+const PAGE_SIZE: usize = 4096;
+
+/// A ring of buffers provided to the kernel, as a buffer group.
+///
+/// Reads naming the group do not carry a buffer, kernel picks one when data is
+/// ready and the completion returns it as a [`BorrowedBuf`], which is given
+/// back to the ring when dropped.
+#[derive(Clone)]
+pub struct BufRing {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    bgid: u16,
+    entries: u16,
+    buf_len: usize,
+
+    ring: *mut BufRingEntry,
+    ring_layout: Layout,
+    bufs: *mut u8,
+    bufs_layout: Layout,
+
+    /// Tail of the ring, published to the kernel after buffers are pushed.
+    tail: Cell<u16>,
+
+    /// The driver it is registered with.
+    driver: Weak<RefCell<Driver>>,
+}
+
+/// Builder of [`BufRing`].
+#[derive(Debug, Clone)]
+pub struct BufRingBuilder {
+    bgid: u16,
+    entries: u16,
+    buf_len: usize,
+}
+
+impl BufRingBuilder {
+    /// `bgid` is the id of the buffer group, unique in a ring.
+    pub fn new(bgid: u16) -> Self {
+        Self {
+            bgid,
+            entries: 64,
+            buf_len: 4096,
+        }
+    }
+
+    /// Number of buffers, a power of two up to 32768, default is 64.
+    pub fn entries(&mut self, entries: u16) -> &mut Self {
+        self.entries = entries;
+        self
+    }
+
+    /// Length of each buffer, default is 4096.
+    pub fn buf_len(&mut self, buf_len: usize) -> &mut Self {
+        self.buf_len = buf_len;
+        self
+    }
+
+    /// Allocate the buffers and register them with the ring of the current
+    /// runtime.
+    pub fn build(&self) -> crate::Result<BufRing> {
+        if !self.entries.is_power_of_two() || self.buf_len == 0 || self.buf_len > u32::MAX as usize
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "entries must be a power of two, and buf_len must fit in u32",
+            )
+            .into());
+        }
+
+        let handle = CONTEXT.with(|cx| cx.handle().ok_or_else(|| cx.no_driver()))?;
+
+        let entries = self.entries as usize;
+        let ring_layout = Layout::array::<BufRingEntry>(entries)
+            .and_then(|l| l.align_to(PAGE_SIZE))
+            .map_err(io::Error::other)?;
+        let bufs_layout = entries
+            .checked_mul(self.buf_len)
+            .and_then(|size| Layout::from_size_align(size, 64).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "buffers too large"))?;
+
+        // Safety: both layouts have non-zero sizes.
+        let ring = unsafe { std::alloc::alloc_zeroed(ring_layout) } as *mut BufRingEntry;
+        let bufs = unsafe { std::alloc::alloc_zeroed(bufs_layout) };
+        if ring.is_null() || bufs.is_null() {
+            std::alloc::handle_alloc_error(ring_layout);
+        }
+
+        let mut inner = Inner {
+            bgid: self.bgid,
+            entries: self.entries,
+            buf_len: self.buf_len,
+            ring,
+            ring_layout,
+            bufs,
+            bufs_layout,
+            tail: Cell::new(0),
+            driver: Weak::new(),
+        };
+
+        // Safety: memory is freed after it is unregistered, by `Inner::drop`.
+        unsafe {
+            handle.borrow().uring.submitter().register_buf_ring(
+                ring.cast(),
+                self.entries,
+                self.bgid,
+            )?
+        };
+        inner.driver = Rc::downgrade(&handle);
+
+        for bid in 0..self.entries {
+            inner.push(bid);
+        }
+        inner.publish();
+
+        Ok(BufRing {
+            inner: Rc::new(inner),
+        })
+    }
+}
+
+impl BufRing {
+    /// A ring of 64 buffers of 4096 bytes, see [`BufRingBuilder`].
+    pub fn new(bgid: u16) -> crate::Result<Self> {
+        BufRingBuilder::new(bgid).build()
+    }
+
+    pub fn builder(bgid: u16) -> BufRingBuilder {
+        BufRingBuilder::new(bgid)
+    }
+
+    /// Id of the buffer group.
+    pub fn bgid(&self) -> u16 {
+        self.inner.bgid
+    }
+
+    pub fn entries(&self) -> u16 {
+        self.inner.entries
+    }
+
+    pub fn buf_len(&self) -> usize {
+        self.inner.buf_len
+    }
+
+    /// Take the buffer picked by kernel for a completion, `None` if it did not
+    /// pick one.
+    pub(crate) fn take(&self, flags: rustix_uring::cqueue::Flags, len: u32) -> Option<BorrowedBuf> {
+        let bid = rustix_uring::cqueue::buffer_select(flags)?;
+        assert!(bid < self.inner.entries, "invalid buffer id {bid}");
+        assert!(len as usize <= self.inner.buf_len);
+
+        Some(BorrowedBuf {
+            ring: self.inner.clone(),
+            bid,
+            len: len as usize,
+        })
+    }
+}
+
+impl Inner {
+    /// Push buffer `bid` to the tail, kernel sees it after [`Inner::publish`].
+    fn push(&self, bid: u16) {
+        let tail = self.tail.get();
+        let mask = self.entries - 1;
+
+        // Safety: the slot at the tail is not owned by kernel, and `bid` is in range.
+        unsafe {
+            let entry = &mut *self.ring.add((tail & mask) as usize);
+            entry.set_addr(self.bufs.add(bid as usize * self.buf_len).cast());
+            entry.set_len(self.buf_len as u32);
+            entry.set_bid(bid);
+        }
+        self.tail.set(tail.wrapping_add(1));
+    }
+
+    fn publish(&self) {
+        // Safety: the tail overlaps the first entry, and is only written by us.
+        unsafe {
+            let tail = BufRingEntry::tail(self.ring) as *mut u16;
+            AtomicU16::from_ptr(tail).store(self.tail.get(), Ordering::Release);
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // Ops using the group hold the ring, so kernel does not pick buffers from now.
+        if let Some(driver) = self.driver.upgrade() {
+            match driver.try_borrow() {
+                Ok(driver) => {
+                    let _ = driver.uring.submitter().unregister_buf_ring(self.bgid);
+                }
+                // Dropped by a completion, kernel may still access the memory
+                // until the ring is dropped, so it is leaked.
+                Err(_) => return,
+            }
+        }
+
+        unsafe {
+            std::alloc::dealloc(self.ring.cast(), self.ring_layout);
+            std::alloc::dealloc(self.bufs, self.bufs_layout);
+        }
+    }
+}
+
+/// A buffer picked by kernel from a [`BufRing`], it derefs to the data read.
+///
+/// It is given back to the ring when dropped.
+pub struct BorrowedBuf {
+    ring: Rc<Inner>,
+    bid: u16,
+    len: usize,
+}
+
+impl BorrowedBuf {
+    /// Id of the buffer in the ring.
+    pub fn bid(&self) -> u16 {
+        self.bid
+    }
+}
+
+impl Deref for BorrowedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // Safety: kernel does not write the buffer until it is given back.
+        unsafe {
+            std::slice::from_raw_parts(
+                self.ring.bufs.add(self.bid as usize * self.ring.buf_len),
+                self.len,
+            )
+        }
+    }
+}
+
+impl AsRef<[u8]> for BorrowedBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl std::fmt::Debug for BorrowedBuf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BorrowedBuf")
+            .field("bid", &self.bid)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl Drop for BorrowedBuf {
+    fn drop(&mut self) {
+        self.ring.push(self.bid);
+        self.ring.publish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::uring::rt::default_rt;
+
+    use super::BufRing;
+
+    #[test]
+    fn test_invalid_entries() {
+        default_rt().unwrap().block_on(async {
+            assert!(BufRing::builder(0).entries(3).build().is_err());
+            assert!(BufRing::builder(0).buf_len(0).build().is_err());
+        });
+    }
+
+    #[test]
+    fn test_outside_runtime() {
+        assert!(BufRing::new(0).is_err());
+    }
+}
@@ -1,65 +1,666 @@
-use std::{cell::RefCell, os::fd::AsRawFd, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    os::fd::{AsRawFd, RawFd},
+    rc::Rc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use rustix::io;
-use rustix_uring::{IoUring, cqueue};
-use tracing::instrument;
+use rustix::io_uring::{IoringOp, io_uring_sqe};
+use rustix_uring::{
+    IoUring, Probe, cqueue, opcode, squeue,
+    types::{CancelBuilder, SubmitArgs, Timespec},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{Span, field, instrument};
 
-use crate::utils::slab::Slab;
+use crate::{Error, utils::slab::Slab};
 
-use super::op::Lifecycle;
+use super::{
+    metrics::{LatencyHistogram, RuntimeMetrics},
+    op::Lifecycle,
+    rt::{DROP_TIMEOUT, OpReport, ShutdownReport, SubmitPolicy},
+    sim::Submission,
+};
+
+/// Initial capacity of the table of ops, it is shrunk back once a burst of ops
+/// grows it beyond 4 times and they are all removed.
+pub(crate) const OPS_CAPACITY: usize = 256;
+
+/// `user_data` of an entry, the key of an op or a tag of internal entries.
+///
+/// The key of an op is its index in the slab and the generation of the slot, so
+/// a late completion does not complete the op reusing the slot. The timeout
+/// linked to an op has its key with the highest bit set. Tags use the generation
+/// `u32::MAX`, which keys never have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UserData {
+    Op {
+        index: usize,
+        generation: u32,
+    },
+    /// Cancellation of an op.
+    Cancel,
+    /// Timeout linked to the op with the key.
+    LinkTimeout {
+        index: usize,
+        generation: u32,
+    },
+    /// Any other entry whose completion is ignored.
+    Ignored,
+}
+
+impl UserData {
+    const TAG: u64 = (u32::MAX as u64) << 32;
+    const LINK_TIMEOUT: u32 = 1 << 31;
+    /// Generations are below it, so keys of linked timeouts are not tags.
+    const MAX_GENERATION: u32 = Self::LINK_TIMEOUT - 1;
+
+    pub(crate) const fn encode(self) -> u64 {
+        match self {
+            UserData::Op { index, generation } => ((generation as u64) << 32) | index as u64,
+            UserData::Cancel => Self::TAG | 2,
+            UserData::LinkTimeout { index, generation } => {
+                (((Self::LINK_TIMEOUT | generation) as u64) << 32) | index as u64
+            }
+            UserData::Ignored => Self::TAG,
+        }
+    }
+
+    pub(crate) const fn decode(user_data: u64) -> Self {
+        let high = (user_data >> 32) as u32;
+        let index = user_data as u32 as usize;
+        if high == u32::MAX {
+            return match index {
+                2 => UserData::Cancel,
+                _ => UserData::Ignored,
+            };
+        }
+        if high & Self::LINK_TIMEOUT != 0 {
+            return UserData::LinkTimeout {
+                index,
+                generation: high & !Self::LINK_TIMEOUT,
+            };
+        }
+        UserData::Op {
+            index,
+            generation: high,
+        }
+    }
+}
 
 pub(crate) struct Driver {
     pub(crate) ops: Ops,
     pub(crate) uring: IoUring,
+
+    /// Reap completions by waiting in [`Driver::park`], instead of being notified
+    /// through the ring fd.
+    pub(crate) reap_on_park: bool,
+    /// Set when a task is woken, so [`Driver::park`] does not wait in the ring.
+    pub(crate) woken: Arc<AtomicBool>,
+
+    pub(crate) submit_policy: SubmitPolicy,
+    /// Entries pushed but not submitted yet.
+    pending: usize,
+    /// Number of `io_uring_enter` calls made to submit entries.
+    pub(crate) submit_calls: u64,
+
+    /// The registered file table, if any.
+    files: Option<FileTable>,
+
+    /// Opcodes supported by kernel, `None` if probing is not supported.
+    probe: Option<Probe>,
+    /// Opcodes treated as unsupported anyway.
+    pub(crate) disabled: Vec<IoringOp>,
+
+    pub(crate) metrics: Metrics,
+    /// Ops between their submission and their last completion, by index.
+    in_flight: Vec<Option<InFlight>>,
+    /// Shut down, no more entries are pushed.
+    closed: bool,
+    /// Entries recorded instead of being submitted, by the simulated backend.
+    recorded: Option<Vec<Submission>>,
+}
+
+/// An op in flight, for its latency and span.
+struct InFlight {
+    opcode: IoringOp,
+    at: Instant,
+    /// The `op` span, closed by the last completion.
+    span: Span,
+}
+
+/// Counters of the driver, besides `submit_calls`.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) submitted: u64,
+    pub(crate) completed: u64,
+    pub(crate) ignored: u64,
+    pub(crate) cancelled: u64,
+    pub(crate) stale: u64,
+    pub(crate) sq_full_flushes: u64,
+    pub(crate) submit_busy_retries: u64,
+
+    latencies: HashMap<IoringOp, LatencyHistogram>,
+}
+
+/// A sparse registered file table, whose slots are allocated by the driver.
+struct FileTable {
+    free: Vec<u32>,
 }
 
 impl Driver {
     pub(crate) fn new(builder: &rustix_uring::Builder, entries: u32) -> std::io::Result<Self> {
+        let uring = builder.build(entries)?;
+        let mut probe = Probe::new();
+        let probe = match uring.submitter().register_probe(&mut probe) {
+            Ok(()) => Some(probe),
+            Err(e) => {
+                tracing::warn!("failed to probe opcodes: {}", e);
+                None
+            }
+        };
+
         Ok(Self {
-            uring: builder.build(entries)?,
+            uring,
             ops: Ops::new(),
+            reap_on_park: false,
+            woken: Arc::default(),
+            submit_policy: SubmitPolicy::Immediate,
+            pending: 0,
+            submit_calls: 0,
+            files: None,
+            probe,
+            disabled: vec![],
+            metrics: Metrics::default(),
+            in_flight: vec![],
+            closed: false,
+            recorded: None,
         })
     }
 
+    /// Record entries instead of submitting them, they are completed by a
+    /// [`Simulator`](super::sim::Simulator).
+    pub(crate) fn simulate(&mut self) {
+        self.recorded = Some(vec![]);
+    }
+
+    pub(crate) fn is_simulated(&self) -> bool {
+        self.recorded.is_some()
+    }
+
+    /// Entries recorded and not finally completed.
+    pub(crate) fn recorded(&self) -> &[Submission] {
+        self.recorded.as_deref().unwrap_or_default()
+    }
+
+    pub(crate) fn recorded_mut(&mut self) -> &mut Vec<Submission> {
+        self.recorded.as_mut().expect("not simulated")
+    }
+
+    /// Whether kernel supports `opcode`, ops which are not supported may fall
+    /// back to the blocking pool.
+    pub(crate) fn is_supported(&self, opcode: IoringOp) -> bool {
+        !self.disabled.contains(&opcode)
+            && self
+                .probe
+                .as_ref()
+                .is_some_and(|probe| probe.is_supported(opcode))
+    }
+
+    /// Whether `opcode` is known to fail, it is disabled or the probe does not
+    /// list it. Without a probe, kernel decides.
+    fn is_rejected(&self, opcode: IoringOp) -> bool {
+        self.disabled.contains(&opcode)
+            || self
+                .probe
+                .as_ref()
+                .is_some_and(|probe| !probe.is_supported(opcode))
+    }
+
+    /// Register a sparse file table with `n` empty slots.
+    pub(crate) fn register_files(&mut self, n: u32) -> std::io::Result<()> {
+        self.uring.submitter().register_files_sparse(n)?;
+        self.files = Some(FileTable {
+            free: (0..n).rev().collect(),
+        });
+        Ok(())
+    }
+
+    /// Allocate an empty slot of the file table.
+    ///
+    /// Fails with `ENXIO` if no table is registered, or `ENFILE` if it is full.
+    pub(crate) fn alloc_file_slot(&mut self) -> std::io::Result<u32> {
+        let files = self.files.as_mut().ok_or(io::Errno::NXIO)?;
+        Ok(files.free.pop().ok_or(io::Errno::NFILE)?)
+    }
+
+    /// Give back a slot which is empty in kernel.
+    pub(crate) fn free_file_slot(&mut self, slot: u32) {
+        if let Some(files) = self.files.as_mut() {
+            files.free.push(slot);
+        }
+    }
+
+    /// Put `fd` into an empty slot, the slot holds its own reference of the file.
+    pub(crate) fn register_file(&mut self, fd: RawFd) -> std::io::Result<u32> {
+        let slot = self.alloc_file_slot()?;
+        if let Err(e) = self.uring.submitter().register_files_update(slot, &[fd]) {
+            self.free_file_slot(slot);
+            return Err(e.into());
+        }
+        Ok(slot)
+    }
+
+    /// Remove the file in `slot`, so the slot could be reused.
+    ///
+    /// In-flight ops on the file hold their own references.
+    pub(crate) fn unregister_file(&mut self, slot: u32) -> std::io::Result<()> {
+        self.uring.submitter().register_files_update(slot, &[-1])?;
+        self.free_file_slot(slot);
+        Ok(())
+    }
+
     /// Once submit, will submit sq and check cq.
     /// If some completed, call to complete.
     pub(crate) fn submit(&mut self) -> std::io::Result<()> {
         loop {
+            self.submit_calls += 1;
             match self.uring.submit() {
-                Ok(_) => {
+                Ok(n) => {
+                    tracing::trace!(entries = n, "submitted");
+                    self.pending = 0;
                     self.uring.submission().sync();
                     return Ok(());
                 }
-                Err(e) => match e {
-                    io::Errno::BUSY | io::Errno::AGAIN => self.tick(),
-                    io::Errno::INTR => return Err(std::io::Error::from(e)),
-                    _ => continue,
-                },
+                Err(io::Errno::BUSY | io::Errno::AGAIN) => {
+                    self.metrics.submit_busy_retries += 1;
+                    // Kernel waits for completions to be reaped, if there are
+                    // none the ring could not make progress.
+                    if self.uring.completion().is_empty() {
+                        return Err(Error::RingFull.into());
+                    }
+                    self.tick()
+                }
+                Err(e) => return Err(e.into()),
             }
         }
     }
 
     pub(crate) fn tick(&mut self) {
-        let mut cq = self.uring.completion();
-        cq.sync();
+        loop {
+            let mut cq = self.uring.completion();
+            cq.sync();
+            if cq.is_empty() {
+                return;
+            }
+            tracing::trace!(completions = cq.len(), overflow = cq.overflow(), "reaping");
+
+            for cqe in cq {
+                Self::dispatch(
+                    &mut self.ops,
+                    &mut self.metrics,
+                    &mut self.in_flight,
+                    cqe.user_data_u64(),
+                    cqe.result(),
+                    cqe.flags(),
+                );
+            }
+
+            // Completions beyond the completion queue wait in kernel, until the
+            // ring is entered to get events, e.g. after a burst of ops.
+            if !self.uring.submission().cq_overflow() {
+                return;
+            }
+            self.submit_calls += 1;
+            if self.uring.submit().is_ok() {
+                self.pending = 0;
+            }
+        }
+    }
 
-        for cqe in cq {
-            if cqe.user_data_u64() == u64::MAX {
-                // Mark ignored IO.
-                continue;
+    /// Deliver a completion which is not from the ring, e.g. by the simulator.
+    pub(crate) fn complete_cqe(&mut self, user_data: u64, res: i32, flags: cqueue::Flags) {
+        Self::dispatch(
+            &mut self.ops,
+            &mut self.metrics,
+            &mut self.in_flight,
+            user_data,
+            res,
+            flags,
+        );
+    }
+
+    /// Complete the op of a completion, completions of internal entries and
+    /// stale ones are dropped.
+    fn dispatch(
+        ops: &mut Ops,
+        metrics: &mut Metrics,
+        in_flight: &mut [Option<InFlight>],
+        user_data: u64,
+        res: i32,
+        flags: cqueue::Flags,
+    ) {
+        let index = match UserData::decode(user_data) {
+            UserData::Op { index, generation } if ops.is_live(index, generation) => index,
+            UserData::Op { index, generation } => {
+                metrics.stale += 1;
+                tracing::warn!(index, generation, res, "dropped a stale completion");
+                return;
+            }
+            UserData::Cancel => {
+                tracing::trace!("cancellation completed: {}", res);
+                return;
+            }
+            // `ETIME` if expired, which cancels the linked op.
+            UserData::LinkTimeout { index, generation } => {
+                tracing::trace!("linked timeout completed: {}", res);
+                if ops.is_live(index, generation) {
+                    ops.link_completed(index, res == -io::Errno::TIME.raw_os_error());
+                }
+                return;
             }
+            UserData::Ignored => return,
+        };
 
-            let index = cqe.user_data_u64();
-            let result = match cqe.result() {
-                n if n < 0 => Err(std::io::Error::from_raw_os_error(-n)),
-                n => Ok(n as u32),
+        Self::completed(metrics, in_flight, index, res, flags);
+        ops.complete(index, result(res), flags);
+    }
+
+    /// Push an entry to the submission queue, and submit it according to the policy.
+    ///
+    /// If the submission queue is full, it is submitted first. An error means the
+    /// entry was not pushed, if the submission after push failed, the entry is
+    /// submitted later.
+    pub(crate) fn push(&mut self, sqe: &squeue::Entry) -> std::io::Result<()> {
+        self.push_all(std::slice::from_ref(sqe))
+    }
+
+    /// Push entries together, so linked entries are in the same submission.
+    pub(crate) fn push_all(&mut self, sqes: &[squeue::Entry]) -> std::io::Result<()> {
+        if self.closed {
+            return Err(Error::NoRuntime.into());
+        }
+        if sqes.len() > self.uring.submission().capacity() {
+            return Err(Error::RingFull.into());
+        }
+        if let Some(opcode) = sqes.iter().map(opcode).find(|&op| self.is_rejected(op)) {
+            return Err(Error::UnsupportedOpcode(opcode).into());
+        }
+        if let Some(recorded) = &mut self.recorded {
+            recorded.extend(sqes.iter().map(Submission::new));
+            sqes.iter().for_each(|sqe| self.submitted(sqe));
+            return Ok(());
+        }
+
+        while self.sq_remaining() < sqes.len() {
+            self.metrics.sq_full_flushes += 1;
+            self.submit()?;
+            if self.sq_remaining() < sqes.len() && self.uring.params().is_setup_sqpoll() {
+                // The kernel thread has not consumed them yet.
+                self.uring.submitter().squeue_wait()?;
+            }
+        }
+
+        {
+            let mut sq = self.uring.submission();
+            if unsafe { sq.push_multiple(sqes).is_err() } {
+                unreachable!("submission queue is full after submit");
+            }
+        }
+        sqes.iter().for_each(|sqe| self.submitted(sqe));
+
+        if let Err(e) = self.pushed(sqes.len()) {
+            tracing::warn!("submit failed: {}, retry later", e);
+        }
+        Ok(())
+    }
+
+    /// Ask kernel to cancel the op with the given index.
+    ///
+    /// The op still completes, with `ECANCELED` if the cancellation succeeded.
+    pub(crate) fn cancel(&mut self, index: usize) -> std::io::Result<()> {
+        let key = self.ops.user_data(index);
+        let sqe = opcode::AsyncCancel2::new(CancelBuilder::user_data_u64(key))
+            .build()
+            .user_data(UserData::Cancel.encode());
+        self.push(&sqe)
+    }
+
+    /// Called after an entry was pushed to the submission queue, submit it
+    /// according to the policy.
+    fn pushed(&mut self, n: usize) -> std::io::Result<()> {
+        self.pending += n;
+
+        match self.submit_policy {
+            SubmitPolicy::Immediate => self.submit(),
+            SubmitPolicy::Threshold(n) if self.pending >= n => self.submit(),
+            SubmitPolicy::Threshold(_) | SubmitPolicy::OnPark => Ok(()),
+        }
+    }
+
+    /// Called before the runtime parks the thread.
+    pub(crate) fn park(&mut self) {
+        self.shrink_idle();
+        let woken = self.woken.swap(false, Ordering::SeqCst);
+        if self.reap_on_park && self.num_op() > 0 && !self.is_simulated() {
+            self.submit_calls += 1;
+            self.pending = 0;
+            if !woken && self.num_op() == self.ops.num_background {
+                // Ops in the background are only reaped, the runtime may wait for
+                // timers of tokio instead. Completions, e.g. of deferred task work,
+                // are flushed by getting events with no timeout.
+                let ts = Timespec::from(Duration::ZERO);
+                let args = SubmitArgs::new().timespec(&ts);
+                let _ = self.uring.submitter().submit_with_args(1, &args);
+            } else {
+                let _ = self.uring.submit_and_wait(if woken { 0 } else { 1 });
+            }
+            self.tick();
+        } else if self.pending > 0 {
+            let _ = self.submit();
+        }
+    }
+
+    /// Like [`Driver::park`], but keep reaping until a task is woken, since tokio
+    /// parks the thread after it and does not see completions then.
+    pub(crate) fn park_until_woken(&mut self) {
+        loop {
+            let woken = self.woken.load(Ordering::SeqCst);
+            self.park();
+            if woken
+                || self.woken.load(Ordering::SeqCst)
+                || !self.reap_on_park
+                || self.num_op() == self.ops.num_background
+            {
+                return;
+            }
+        }
+    }
+
+    /// Cancel all ops in flight, and reap completions until they are done or the
+    /// timeout, no more entries are pushed after it.
+    ///
+    /// Ops not completed in time are leaked.
+    pub(crate) fn shutdown(&mut self, timeout: Duration) -> ShutdownReport {
+        let deadline = Instant::now() + timeout;
+        let ops = self.in_flight_ops().collect::<Vec<_>>();
+        for &(index, _) in &ops {
+            let _ = self.cancel(index);
+        }
+        self.closed = true;
+        if !ops.is_empty() {
+            tracing::debug!("shutting down with {} ops in flight", ops.len());
+        }
+
+        // Nothing completes the simulated entries.
+        while !self.is_simulated() && self.in_flight_ops().next().is_some() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                break;
+            }
+
+            let ts = Timespec::from(timeout);
+            let args = SubmitArgs::new().timespec(&ts);
+            self.submit_calls += 1;
+            match self.uring.submitter().submit_with_args(1, &args) {
+                Ok(_) => {}
+                Err(io::Errno::TIME | io::Errno::INTR | io::Errno::BUSY) => {}
+                Err(e) => {
+                    tracing::warn!("failed to wait for completions: {}", e);
+                    break;
+                }
+            }
+            self.tick();
+        }
+
+        let mut report = ShutdownReport::default();
+        for (index, op) in ops {
+            match self.in_flight.get(index) {
+                Some(Some(_)) => {
+                    // Kernel may still use the data.
+                    if let Some(Lifecycle::Ignored(..)) = self.ops.lifecycle.get(index) {
+                        std::mem::forget(self.ops.remove(index));
+                    }
+                    report.leaked.push(op);
+                }
+                _ => report.cancelled.push(op),
+            }
+        }
+        report
+    }
+
+    /// Indices of ops in flight, with their reports.
+    fn in_flight_ops(&self) -> impl Iterator<Item = (usize, OpReport)> + '_ {
+        self.ops.lifecycle.iter().filter_map(|(index, _)| {
+            let op = self.in_flight.get(index)?.as_ref()?;
+            let report = OpReport {
+                opcode: op.opcode,
+                elapsed: op.at.elapsed(),
             };
-            let flags = cqe.flags();
+            Some((index, report))
+        })
+    }
 
-            self.ops.complete(index as usize, result, flags);
+    /// A snapshot of the counters.
+    pub(crate) fn snapshot(&mut self) -> RuntimeMetrics {
+        let m = &self.metrics;
+        RuntimeMetrics {
+            submitted: m.submitted,
+            completed: m.completed,
+            ignored: m.ignored,
+            cancelled: m.cancelled,
+            stale: m.stale,
+            permit_waiters: self
+                .ops
+                .limit
+                .as_ref()
+                .map_or(0, |limit| limit.waiters.get()),
+            in_flight: self.in_flight_ops().count(),
+            submit_calls: self.submit_calls,
+            sq_full_flushes: m.sq_full_flushes,
+            submit_busy_retries: m.submit_busy_retries,
+            cq_overflow: self.uring.completion().overflow(),
+            latencies: m.latencies.clone(),
         }
     }
 
+    /// Called for each entry pushed, it opens the span of the op.
+    fn submitted(&mut self, sqe: &squeue::Entry) {
+        let UserData::Op { index, .. } = UserData::decode(sqe.get_user_data().u64_()) else {
+            return;
+        };
+        self.metrics.submitted += 1;
+
+        let sqe = raw_sqe(sqe);
+        // Safety: unions of plain integers, they are read as offset and length.
+        let (offset, len) = unsafe { (sqe.off_or_addr2.off, sqe.len.len) };
+        let span = tracing::debug_span!(
+            "op",
+            opcode = ?sqe.opcode,
+            fd = sqe.fd,
+            offset,
+            len,
+            index,
+            result = field::Empty,
+            errno = field::Empty,
+            elapsed = field::Empty,
+        );
+
+        if self.in_flight.len() <= index {
+            self.in_flight.resize_with(index + 1, || None);
+        }
+        self.in_flight[index] = Some(InFlight {
+            opcode: sqe.opcode,
+            at: Instant::now(),
+            span,
+        });
+    }
+
+    /// Called for each completion of an op, the last one closes its span.
+    fn completed(
+        metrics: &mut Metrics,
+        in_flight: &mut [Option<InFlight>],
+        index: usize,
+        result: i32,
+        flags: cqueue::Flags,
+    ) {
+        metrics.completed += 1;
+        if result == -io::Errno::CANCELED.raw_os_error() {
+            metrics.cancelled += 1;
+        }
+
+        let Some(slot) = in_flight.get_mut(index) else {
+            return;
+        };
+        if cqueue::more(flags) {
+            if let Some(op) = slot {
+                op.span.in_scope(|| tracing::trace!(result, "completion"));
+            }
+            return;
+        }
+        let Some(op) = slot.take() else {
+            return;
+        };
+
+        let elapsed = op.at.elapsed();
+        metrics
+            .latencies
+            .entry(op.opcode)
+            .or_default()
+            .record(elapsed);
+
+        match result {
+            n if n < 0 => op.span.record("errno", -n),
+            n => op.span.record("result", n),
+        };
+        op.span.record("elapsed", field::debug(elapsed));
+        op.span.in_scope(|| tracing::debug!("completed"));
+    }
+
+    /// Give back the memory of a burst of ops once they are all removed.
+    fn shrink_idle(&mut self) {
+        let lifecycle = &self.ops.lifecycle;
+        if lifecycle.is_empty()
+            && lifecycle.remaining().is_none()
+            && lifecycle.capacity() > 4 * OPS_CAPACITY
+        {
+            self.ops.shrink();
+            self.in_flight.clear();
+            self.in_flight.shrink_to(OPS_CAPACITY);
+        }
+    }
+
+    fn sq_remaining(&mut self) -> usize {
+        let sq = self.uring.submission();
+        sq.capacity() - sq.len()
+    }
+
     fn num_op(&self) -> usize {
         self.ops.lifecycle.len()
     }
@@ -67,38 +668,264 @@ impl Driver {
 
 impl Drop for Driver {
     fn drop(&mut self) {
-        while self.num_op() > 0 {
-            let _ = self.uring.submit_and_wait(1);
-            self.tick();
+        if !self.closed {
+            let report = self.shutdown(DROP_TIMEOUT);
+            if !report.leaked.is_empty() {
+                tracing::warn!("{} ops are leaked by the driver", report.leaked.len());
+            }
+        }
+    }
+}
+
+pub(crate) fn raw_sqe(sqe: &squeue::Entry) -> &io_uring_sqe {
+    // Safety: `Entry` is a transparent wrapper of the sqe.
+    unsafe { &*(sqe as *const squeue::Entry).cast::<io_uring_sqe>() }
+}
+
+/// The result of a completion, a negative one is an errno.
+fn result(res: i32) -> std::io::Result<u32> {
+    match res {
+        n if n < 0 => Err(std::io::Error::from_raw_os_error(-n)),
+        n => Ok(n as u32),
+    }
+}
+
+/// The opcode of an entry.
+pub(crate) fn opcode(sqe: &squeue::Entry) -> IoringOp {
+    raw_sqe(sqe).opcode
+}
+
+/// The limit of ops in flight, see [`RuntimeBuilder::max_in_flight`](super::rt::RuntimeBuilder::max_in_flight).
+#[derive(Clone)]
+pub(crate) struct Limit {
+    pub(crate) permits: Arc<Semaphore>,
+    /// Number of ops waiting for a permit.
+    waiters: Rc<Cell<usize>>,
+}
+
+/// Counted as a waiter of the limit until dropped.
+pub(crate) struct Waiter(Rc<Cell<usize>>);
+
+impl Limit {
+    pub(crate) fn new(n: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(n)),
+            waiters: Rc::default(),
         }
     }
+
+    pub(crate) fn waiter(&self) -> Waiter {
+        self.waiters.set(self.waiters.get() + 1);
+        Waiter(self.waiters.clone())
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+/// The timeout linked to an op, see [`Ops::link_timeout`].
+enum LinkState {
+    None,
+    /// Not completed yet, with the completion of the op if it is held.
+    Pending(Option<(std::io::Result<u32>, cqueue::Flags)>),
+    Expired,
 }
 
 pub(crate) struct Ops {
     pub(crate) lifecycle: Slab<Lifecycle>,
+    /// Generation of each slot, bumped when its op is removed.
+    generations: Vec<u32>,
+    /// Slots holding a permit of the limit, given back when the op is removed.
+    permitted: Vec<bool>,
+    /// The timeout linked to the op of each slot.
+    links: Vec<LinkState>,
+    /// Slots of ops in the background, see [`Ops::set_background`].
+    background: Vec<bool>,
+    num_background: usize,
+    /// Generation of new slots, above the ones of trimmed slots.
+    next_generation: u32,
+    pub(crate) limit: Option<Limit>,
+    /// Ignored ops removed while the driver is borrowed, see [`drop_released`].
+    released: Vec<Lifecycle>,
 }
 
 impl Ops {
     fn new() -> Self {
         Self {
-            lifecycle: Slab::with_capacity(256),
+            lifecycle: Slab::with_capacity(OPS_CAPACITY),
+            generations: Vec::with_capacity(OPS_CAPACITY),
+            permitted: Vec::with_capacity(OPS_CAPACITY),
+            links: Vec::with_capacity(OPS_CAPACITY),
+            background: Vec::with_capacity(OPS_CAPACITY),
+            num_background: 0,
+            next_generation: 0,
+            limit: None,
+            released: vec![],
+        }
+    }
+
+    /// A table of at most `n` ops, allocated up front so it never reallocates,
+    /// see [`RuntimeBuilder::max_ops`](super::rt::RuntimeBuilder::max_ops).
+    pub(crate) fn bounded(n: usize) -> Self {
+        Self {
+            lifecycle: Slab::bounded(n),
+            generations: Vec::with_capacity(n),
+            permitted: Vec::with_capacity(n),
+            links: Vec::with_capacity(n),
+            background: Vec::with_capacity(n),
+            ..Self::new()
+        }
+    }
+
+    /// Fails with [`Error::OpTableFull`] if the table is bounded and full.
+    pub(crate) fn insert(&mut self, lifecycle: Lifecycle) -> std::io::Result<usize> {
+        let index = self
+            .lifecycle
+            .try_insert(lifecycle)
+            .map_err(|_| Error::OpTableFull)?;
+        assert!(index < u32::MAX as usize, "too many ops");
+        if index == self.generations.len() {
+            self.generations.push(self.next_generation);
+            self.permitted.push(false);
+            self.links.push(LinkState::None);
+            self.background.push(false);
+        }
+        Ok(index)
+    }
+
+    /// Shrink the empty table to `OPS_CAPACITY` slots.
+    ///
+    /// Generations of the slots left are kept, and new slots start above the
+    /// trimmed ones, so keys of removed ops are not reused.
+    fn shrink(&mut self) {
+        debug_assert!(self.lifecycle.is_empty());
+        self.lifecycle.shrink_to(OPS_CAPACITY);
+
+        let len = self.generations.len().min(OPS_CAPACITY);
+        if let Some(max) = self.generations[len..].iter().max() {
+            self.next_generation = (max + 1) % UserData::MAX_GENERATION;
+        }
+        self.generations.truncate(len);
+        self.generations.shrink_to(OPS_CAPACITY);
+        self.permitted.truncate(len);
+        self.permitted.shrink_to(OPS_CAPACITY);
+        self.links.truncate(len);
+        self.links.shrink_to(OPS_CAPACITY);
+        self.background.truncate(len);
+        self.background.shrink_to(OPS_CAPACITY);
+    }
+
+    /// Whether `n` more ops could be inserted.
+    pub(crate) fn has_room(&self, n: usize) -> bool {
+        self.lifecycle
+            .remaining()
+            .is_none_or(|remaining| remaining >= n)
+    }
+
+    /// The runtime does not wait in the ring for the op at `index` alone, only
+    /// reaps it when it parks, e.g. a read of an eventfd which wakes it.
+    pub(crate) fn set_background(&mut self, index: usize) {
+        if !std::mem::replace(&mut self.background[index], true) {
+            self.num_background += 1;
         }
     }
 
+    /// Keep `permit` until the op at `index` is removed.
+    pub(crate) fn hold(&mut self, index: usize, permit: OwnedSemaphorePermit) {
+        permit.forget();
+        self.permitted[index] = true;
+    }
+
+    pub(crate) fn remove(&mut self, index: usize) -> Lifecycle {
+        let lifecycle = self.lifecycle.remove(index);
+        self.generations[index] = (self.generations[index] + 1) % UserData::MAX_GENERATION;
+        self.links[index] = LinkState::None;
+        if std::mem::take(&mut self.background[index]) {
+            self.num_background -= 1;
+        }
+        if std::mem::take(&mut self.permitted[index])
+            && let Some(limit) = &self.limit
+        {
+            limit.permits.add_permits(1);
+        }
+        lifecycle
+    }
+
+    /// The `user_data` of the op at `index`.
+    pub(crate) fn user_data(&self, index: usize) -> u64 {
+        UserData::Op {
+            index,
+            generation: self.generations[index],
+        }
+        .encode()
+    }
+
+    /// The `user_data` of a timeout linked to the op at `index`, the op is not
+    /// completed until the timeout is.
+    pub(crate) fn link_timeout(&mut self, index: usize) -> u64 {
+        self.links[index] = LinkState::Pending(None);
+        UserData::LinkTimeout {
+            index,
+            generation: self.generations[index],
+        }
+        .encode()
+    }
+
+    /// Whether the timeout linked to the op at `index` has expired.
+    pub(crate) fn is_expired(&self, index: usize) -> bool {
+        matches!(self.links[index], LinkState::Expired)
+    }
+
+    /// The timeout linked to the op at `index` is completed, complete the op
+    /// if it was held.
+    fn link_completed(&mut self, index: usize, expired: bool) {
+        let state = if expired {
+            LinkState::Expired
+        } else {
+            LinkState::None
+        };
+        if let LinkState::Pending(Some((result, flags))) =
+            std::mem::replace(&mut self.links[index], state)
+        {
+            self.complete(index, result, flags);
+        }
+    }
+
+    /// Whether the op with the key is not removed.
+    fn is_live(&self, index: usize, generation: u32) -> bool {
+        self.lifecycle.get(index).is_some() && self.generations[index] == generation
+    }
+
     pub(crate) fn complete(
         &mut self,
         index: usize,
         result: std::io::Result<u32>,
         flags: cqueue::Flags,
     ) {
+        // Completions of the op and its timeout are not ordered.
+        if let LinkState::Pending(held @ None) = &mut self.links[index] {
+            *held = Some((result, flags));
+            return;
+        }
         if self.lifecycle[index].complete(result, flags) {
-            self.lifecycle.remove(index);
+            let ignored = self.remove(index);
+            self.released.push(ignored);
         }
     }
 }
 
 pub(crate) struct Context {
-    inner: RefCell<Option<Rc<RefCell<Driver>>>>,
+    inner: RefCell<Option<Current>>,
+}
+
+/// The backend of the current runtime.
+enum Current {
+    Uring(Rc<RefCell<Driver>>),
+    /// No ring, ops run on the blocking pool, with the limit of ops in flight.
+    ThreadPool(Option<Limit>),
 }
 
 impl Context {
@@ -108,8 +935,14 @@ impl Context {
         }
     }
 
-    pub fn set(&self, driver: Rc<RefCell<Driver>>) {
-        let res = self.inner.borrow_mut().replace(driver);
+    /// Set the driver, `None` for the thread pool backend, which has its own
+    /// `limit`.
+    pub fn set(&self, driver: Option<Rc<RefCell<Driver>>>, limit: Option<Limit>) {
+        let current = match driver {
+            Some(driver) => Current::Uring(driver),
+            None => Current::ThreadPool(limit),
+        };
+        let res = self.inner.borrow_mut().replace(current);
         assert!(res.is_none(), "Driver context already set");
     }
 
@@ -119,7 +952,33 @@ impl Context {
     }
 
     pub fn handle(&self) -> Option<Rc<RefCell<Driver>>> {
-        self.inner.borrow().as_ref().cloned()
+        match self.inner.borrow().as_ref() {
+            Some(Current::Uring(driver)) => Some(driver.clone()),
+            _ => None,
+        }
+    }
+
+    /// Whether the current runtime runs ops on the thread pool.
+    pub fn is_thread_pool(&self) -> bool {
+        matches!(*self.inner.borrow(), Some(Current::ThreadPool(_)))
+    }
+
+    /// The limit of ops in flight of the current runtime.
+    pub(crate) fn limit(&self) -> Option<Limit> {
+        match self.inner.borrow().as_ref() {
+            Some(Current::Uring(driver)) => driver.borrow().ops.limit.clone(),
+            Some(Current::ThreadPool(limit)) => limit.clone(),
+            None => None,
+        }
+    }
+
+    /// The error of ops which need a ring while there is none.
+    pub fn no_driver(&self) -> std::io::Error {
+        if self.is_thread_pool() {
+            Error::Unsupported("the op on the thread pool backend").into()
+        } else {
+            Error::NoRuntime.into()
+        }
     }
 }
 
@@ -127,6 +986,32 @@ thread_local! {
     pub(crate) static CONTEXT: Context = const { Context::new() };
 }
 
+/// Run `f` with the driver of the current runtime.
+pub(crate) fn with_current<R>(
+    f: impl FnOnce(&mut Driver) -> std::io::Result<R>,
+) -> std::io::Result<R> {
+    CONTEXT.with(|cx| {
+        let handle = cx.handle().ok_or_else(|| cx.no_driver())?;
+        let res = f(&mut handle.borrow_mut());
+        drop_released(&handle);
+        res
+    })
+}
+
+/// Drop the data of ignored ops completed while the driver was borrowed.
+///
+/// It must not be borrowed, since dropping the data may push entries, e.g. the
+/// close of the last reference of a [`SharedFd`](super::fs::shared_fd::SharedFd).
+pub(crate) fn drop_released(driver: &RefCell<Driver>) {
+    loop {
+        let released = std::mem::take(&mut driver.borrow_mut().ops.released);
+        if released.is_empty() {
+            return;
+        }
+        drop(released);
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct Handle {
     pub(crate) inner: Rc<RefCell<Driver>>,
@@ -137,3 +1022,36 @@ impl AsRawFd for Handle {
         self.inner.borrow().uring.as_raw_fd()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::UserData;
+
+    #[test]
+    fn test_user_data() {
+        let keys = [
+            UserData::Op {
+                index: 0,
+                generation: 0,
+            },
+            UserData::Op {
+                index: u32::MAX as usize - 1,
+                generation: UserData::MAX_GENERATION - 1,
+            },
+            UserData::Cancel,
+            UserData::LinkTimeout {
+                index: 1,
+                generation: 0,
+            },
+            UserData::LinkTimeout {
+                index: u32::MAX as usize - 1,
+                generation: UserData::MAX_GENERATION - 1,
+            },
+            UserData::Ignored,
+        ];
+        for key in keys {
+            assert_eq!(UserData::decode(key.encode()), key);
+        }
+        assert_eq!(UserData::decode(u64::MAX), UserData::Ignored);
+    }
+}
@@ -0,0 +1,430 @@
+//! A single-threaded executor parking on the io_uring, without tokio.
+//!
+//! When no task is ready, the thread waits for completions in `io_uring_enter`.
+//! Tasks woken from other threads are notified through an eventfd, which is
+//! read by an op in flight on the ring.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    future::Future,
+    io,
+    os::fd::{AsRawFd, OwnedFd},
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    rc::{Rc, Weak},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread::ThreadId,
+};
+
+use rustix::event::{EventfdFlags, eventfd};
+use rustix_uring::{opcode, types};
+use tokio::sync::oneshot;
+
+use crate::{Error, utils::slab::Slab};
+
+use super::{
+    driver::{Driver, drop_released},
+    op::Op,
+};
+
+pub use super::task::JoinHandle;
+
+/// Id of the future passed to [`Executor::block_on`].
+const MAIN: usize = usize::MAX;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Rc<Local>>> = const { RefCell::new(None) };
+}
+
+pub(crate) struct Executor {
+    local: Rc<Local>,
+    /// Whether the eventfd reader has been spawned.
+    notifier: Cell<bool>,
+}
+
+struct Local {
+    tasks: RefCell<Slab<Task>>,
+    shared: Arc<Shared>,
+}
+
+struct Task {
+    /// Taken out while it is polled.
+    future: Option<Pin<Box<dyn Future<Output = ()>>>>,
+    waker: Option<Arc<TaskWaker>>,
+}
+
+/// State shared with wakers, which may be on other threads.
+struct Shared {
+    ready: Mutex<VecDeque<Arc<TaskWaker>>>,
+    eventfd: OwnedFd,
+    /// The eventfd has been written and not read yet.
+    notified: AtomicBool,
+    owner: ThreadId,
+}
+
+struct TaskWaker {
+    id: usize,
+    /// Already in the ready queue.
+    queued: AtomicBool,
+    /// Aborted by its handle, it is dropped instead of polled.
+    aborted: AtomicBool,
+    shared: Arc<Shared>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if self.queued.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let shared = &self.shared;
+        shared.ready.lock().unwrap().push_back(self.clone());
+
+        // The owner thread may be parked in `io_uring_enter`.
+        if std::thread::current().id() != shared.owner
+            && !shared.notified.swap(true, Ordering::SeqCst)
+        {
+            let _ = rustix::io::write(&shared.eventfd, &1_u64.to_ne_bytes());
+        }
+    }
+}
+
+impl Executor {
+    pub(crate) fn new() -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            ready: Mutex::new(VecDeque::new()),
+            eventfd: eventfd(0, EventfdFlags::CLOEXEC)?,
+            notified: AtomicBool::new(false),
+            owner: std::thread::current().id(),
+        });
+
+        Ok(Self {
+            local: Rc::new(Local {
+                tasks: RefCell::new(Slab::new()),
+                shared,
+            }),
+            notifier: Cell::new(false),
+        })
+    }
+
+    /// Run `fut` to completion, with spawned tasks, the driver context must be set.
+    pub(crate) fn block_on<F: Future>(&self, driver: &RefCell<Driver>, fut: F) -> F::Output {
+        struct CurrentGuard;
+
+        impl Drop for CurrentGuard {
+            fn drop(&mut self) {
+                CURRENT.with(|c| c.borrow_mut().take());
+            }
+        }
+
+        CURRENT.with(|c| {
+            let res = c.borrow_mut().replace(self.local.clone());
+            assert!(res.is_none(), "executor already running on this thread");
+        });
+        let _g = CurrentGuard;
+
+        if !self.notifier.replace(true) {
+            Local::spawn(&self.local, read_eventfd(self.local.shared.clone()));
+        }
+
+        let main = Arc::new(TaskWaker {
+            id: MAIN,
+            queued: AtomicBool::new(true),
+            aborted: AtomicBool::new(false),
+            shared: self.local.shared.clone(),
+        });
+        let waker = Waker::from(main.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = std::pin::pin!(fut);
+
+        loop {
+            if main.queued.swap(false, Ordering::SeqCst)
+                && let Poll::Ready(output) = fut.as_mut().poll(&mut cx)
+            {
+                return output;
+            }
+
+            self.local.run_ready();
+
+            let idle = !main.queued.load(Ordering::SeqCst)
+                && self.local.shared.ready.lock().unwrap().is_empty();
+            if idle {
+                driver.borrow_mut().park();
+            } else {
+                driver.borrow_mut().tick();
+            }
+            drop_released(driver);
+        }
+    }
+
+    /// Spawn a task, it runs when the executor is driven by [`Executor::block_on`].
+    pub(crate) fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        Local::spawn(&self.local, future)
+    }
+
+    /// Drop all tasks, the driver context must be set since they may hold ops.
+    pub(crate) fn shutdown(&self) {
+        let tasks = std::mem::take(&mut *self.local.tasks.borrow_mut());
+        drop(tasks);
+        self.local.shared.ready.lock().unwrap().clear();
+    }
+}
+
+impl Local {
+    fn spawn<F>(this: &Rc<Self>, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let future = Box::pin(async move {
+            let _ = tx.send(future.await);
+        });
+
+        let mut tasks = this.tasks.borrow_mut();
+        let id = tasks.insert(Task {
+            future: Some(future),
+            waker: None,
+        });
+        let waker = Arc::new(TaskWaker {
+            id,
+            queued: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+            shared: this.shared.clone(),
+        });
+        tasks.get_mut(id).unwrap().waker = Some(waker.clone());
+        drop(tasks);
+
+        waker.clone().wake();
+        JoinHandle::executor(RawHandle {
+            rx,
+            waker,
+            local: Rc::downgrade(this),
+        })
+    }
+
+    /// Remove the task of `waker`, `None` if it has finished or is being polled.
+    fn remove(&self, waker: &Arc<TaskWaker>) -> Option<Task> {
+        let mut tasks = self.tasks.borrow_mut();
+        match tasks.get(waker.id) {
+            Some(task)
+                if task.future.is_some()
+                    && task.waker.as_ref().is_some_and(|w| Arc::ptr_eq(w, waker)) =>
+            {
+                Some(tasks.remove(waker.id))
+            }
+            _ => None,
+        }
+    }
+
+    /// Poll tasks which are ready now, tasks woken meanwhile are left to the next round.
+    fn run_ready(&self) {
+        let ready = std::mem::take(&mut *self.shared.ready.lock().unwrap());
+        for waker in ready {
+            if waker.id != MAIN {
+                self.run_task(waker);
+            }
+        }
+    }
+
+    fn run_task(&self, waker: Arc<TaskWaker>) {
+        let future = match self.tasks.borrow_mut().get_mut(waker.id) {
+            // The slot may be reused by another task.
+            Some(task) if task.waker.as_ref().is_some_and(|w| Arc::ptr_eq(w, &waker)) => {
+                task.future.take()
+            }
+            _ => None,
+        };
+        let Some(mut future) = future else {
+            return;
+        };
+
+        waker.queued.store(false, Ordering::SeqCst);
+        let id = waker.id;
+        let cx_waker = Waker::from(waker.clone());
+        let mut cx = Context::from_waker(&cx_waker);
+
+        match std::panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(&mut cx))) {
+            // Aborted by itself.
+            Ok(Poll::Pending) if waker.aborted.load(Ordering::SeqCst) => {
+                let task = self.tasks.borrow_mut().remove(id);
+                drop((task, future));
+            }
+            Ok(Poll::Pending) => {
+                self.tasks.borrow_mut().get_mut(id).unwrap().future = Some(future);
+            }
+            Ok(Poll::Ready(())) => {
+                let task = self.tasks.borrow_mut().remove(id);
+                drop(task);
+            }
+            Err(_) => {
+                tracing::error!("task {} panicked", id);
+                let task = self.tasks.borrow_mut().remove(id);
+                drop(task);
+            }
+        }
+    }
+}
+
+/// Keep a read of the eventfd in flight, so remote wakes complete an op and the
+/// parked thread returns from `io_uring_enter`.
+async fn read_eventfd(shared: Arc<Shared>) {
+    let fd = shared.eventfd.as_raw_fd();
+
+    loop {
+        let op = Op::submit_with(vec![0_u8; 8], |buf| {
+            opcode::Read::new(types::Fd(fd), buf.as_mut_ptr(), buf.len() as u32).build()
+        });
+        let comp = match op {
+            Ok(op) => op.await,
+            Err(e) => {
+                tracing::error!("failed to read eventfd: {}", e);
+                return;
+            }
+        };
+
+        shared.notified.store(false, Ordering::SeqCst);
+        if let Err(e) = comp.result {
+            tracing::error!("failed to read eventfd: {}", e);
+            return;
+        }
+    }
+}
+
+/// Spawn on the executor running on this thread, the future is given back if
+/// there is none.
+pub(crate) fn try_spawn<F>(future: F) -> Result<JoinHandle<F::Output>, F>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    match CURRENT.with(|c| c.borrow().clone()) {
+        Some(local) => Ok(Local::spawn(&local, future)),
+        None => Err(future),
+    }
+}
+
+/// The executor side of a [`JoinHandle`].
+pub(crate) struct RawHandle<T> {
+    rx: oneshot::Receiver<T>,
+    waker: Arc<TaskWaker>,
+    local: Weak<Local>,
+}
+
+impl<T> RawHandle<T> {
+    pub(crate) fn abort(&self) {
+        self.waker.aborted.store(true, Ordering::SeqCst);
+        // Removed outside of the borrow, dropping it may drop other tasks.
+        let task = self
+            .local
+            .upgrade()
+            .and_then(|local| local.remove(&self.waker));
+        drop(task);
+    }
+
+    /// Resolves to an error if the task was dropped before completion, for
+    /// example it panicked or the runtime was dropped.
+    pub(crate) fn poll(&mut self, cx: &mut Context<'_>) -> Poll<crate::Result<T>> {
+        Pin::new(&mut self.rx).poll(cx).map_err(|_| {
+            if self.waker.aborted.load(Ordering::SeqCst) {
+                Error::Cancelled
+            } else {
+                io::Error::other("task was dropped").into()
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, rc::Rc, time::Duration};
+
+    use tempfile::tempfile;
+
+    use crate::uring::{
+        fs::File,
+        rt::{Runtime, RuntimeBuilder},
+    };
+
+    use crate::spawn_local;
+
+    fn executor_rt() -> Runtime {
+        RuntimeBuilder::new().executor(true).build().unwrap()
+    }
+
+    #[test]
+    fn test_spawn_local() {
+        executor_rt().block_on(async {
+            let mut file = tempfile().unwrap();
+            file.write_all(b"hello world").unwrap();
+            let file = Rc::new(File::from_std_fd(file));
+
+            let handles = (0..4)
+                .map(|i| {
+                    let file = file.clone();
+                    spawn_local(async move { file.read_at(vec![0; 5], i).await })
+                })
+                .collect::<Vec<_>>();
+
+            for (i, h) in handles.into_iter().enumerate() {
+                let (res, buf) = h.await.unwrap();
+                assert_eq!(res.unwrap(), 5);
+                assert_eq!(&buf[..], &b"hello world"[i..i + 5]);
+            }
+        });
+    }
+
+    #[test]
+    fn test_remote_wake() {
+        executor_rt().block_on(async {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            let thread = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                tx.send(42).unwrap();
+            });
+
+            assert_eq!(rx.await.unwrap(), 42);
+            thread.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_task_panicked() {
+        let rt = executor_rt();
+        rt.block_on(async {
+            let handle = spawn_local(async { panic!("boom") });
+            assert!(handle.await.is_err());
+
+            // The executor is still running.
+            assert_eq!(spawn_local(async { 42 }).await.unwrap(), 42);
+        });
+    }
+
+    #[test]
+    fn test_sleep() {
+        executor_rt().block_on(async {
+            let start = std::time::Instant::now();
+            crate::time::sleep(Duration::from_millis(10)).await.unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(10));
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_spawn_outside() {
+        drop(spawn_local(async {}));
+    }
+}
@@ -0,0 +1,40 @@
+use rustix_uring::{
+    opcode,
+    types::{self, CancelBuilder},
+};
+
+use crate::uring::op::{CompleteAble, Completion, Op, Prepared};
+
+use super::shared_fd::{SharedFd, with_fd};
+
+pub(crate) struct Cancel {
+    fd: SharedFd,
+}
+
+impl Op<Cancel> {
+    /// Cancel all in-flight ops on the fd.
+    ///
+    /// Ops on the blocking pool could not be cancelled, so it cancels none there.
+    /// It is not limited, since it releases the ops holding permits.
+    pub(crate) fn cancel_fd(fd: &SharedFd) -> Prepared<Cancel> {
+        Op::prepare_with(Cancel { fd: fd.clone() }, |cancel| {
+            let builder = with_fd!(cancel.fd, |fd| CancelBuilder::fd(fd).all());
+            opcode::AsyncCancel2::new(builder).build()
+        })
+        .fallback(|| Ok(()), |_, ()| 0)
+        .unlimited()
+    }
+}
+
+impl CompleteAble for Cancel {
+    type Output = crate::Result<usize>;
+
+    fn handle_completion(comp: Completion<Self>) -> Self::Output {
+        match comp.result {
+            Ok(n) => Ok(n as usize),
+            // Nothing to cancel.
+            Err(e) if e.raw_os_error() == Some(rustix::io::Errno::NOENT.raw_os_error()) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
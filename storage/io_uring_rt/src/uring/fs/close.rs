@@ -2,17 +2,15 @@ use std::os::fd::RawFd;
 
 use rustix_uring::types;
 
-use crate::uring::{driver::CONTEXT, op::Op};
+use crate::uring::op::Op;
 
 pub(crate) struct Close;
 
 impl Op<Close> {
-    pub(crate) fn close(fd: RawFd) -> std::io::Result<Self> {
-        CONTEXT.with(|c| {
-            let c = c.handle().expect("no context");
-            Op::submit_with(Close, |_| {
-                rustix_uring::opcode::Close::new(types::Fd(fd)).build()
-            })
+    /// Fails without a ring, the fd is closed synchronously then.
+    pub(crate) fn close(fd: RawFd) -> crate::Result<Self> {
+        Op::submit_with(Close, |_| {
+            rustix_uring::opcode::Close::new(types::Fd(fd)).build()
         })
     }
 }
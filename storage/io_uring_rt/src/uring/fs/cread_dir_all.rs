@@ -16,8 +16,8 @@ impl Inner {
         }
     }
 
-    async fn mkdir<P: AsRef<Path>>(&self, p: P) -> std::io::Result<()> {
-        Op::mkdir(p, self.mode)?.complete().await
+    async fn mkdir<P: AsRef<Path>>(&self, p: P) -> crate::Result<()> {
+        Op::mkdir(p, self.mode).await
     }
 
     pub fn set_mode(&mut self, mode: Mode) {
@@ -60,7 +60,7 @@ impl DirBuilder {
         self
     }
 
-    pub async fn create<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+    pub async fn create<P: AsRef<Path>>(&self, path: P) -> crate::Result<()> {
         if self.recursive {
             self.recurse_create_dir_all(path.as_ref()).await
         } else {
@@ -71,7 +71,7 @@ impl DirBuilder {
     fn recurse_create_dir_all<'a>(
         &'a self,
         path: &'a Path,
-    ) -> Pin<Box<dyn Future<Output = std::io::Result<()>> + 'a>> {
+    ) -> Pin<Box<dyn Future<Output = crate::Result<()>> + 'a>> {
         Box::pin(async move {
             if path == Path::new("") {
                 return Ok(());
@@ -86,7 +86,7 @@ impl DirBuilder {
             match path.parent() {
                 Some(p) => self.recurse_create_dir_all(p).await?,
                 None => {
-                    return Err(std::io::Error::other("create dir all failed"));
+                    return Err(std::io::Error::other("create dir all failed").into());
                 }
             }
 
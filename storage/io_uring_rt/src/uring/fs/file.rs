@@ -3,39 +3,87 @@ use std::{
     path::Path,
 };
 
-use crate::uring::{fs::OpenOptions, op::Op, prelude::BufResult};
+use crate::uring::{
+    buf::{BufRing, FixedBuf},
+    fs::OpenOptions,
+    op::{Op, Prepared},
+    prelude::BufResult,
+};
 
-use super::{AsIoVec, AsIoVecMut, metadata::Metadata, shared_fd::SharedFd};
+use super::{
+    AsIoVec, AsIoVecMut,
+    fsync::Fsync,
+    metadata::Metadata,
+    read::Read,
+    read_fixed::ReadFixed,
+    read_provided::{ReadMulti, ReadProvided},
+    shared_fd::SharedFd,
+    write::Write,
+    write_fixed::WriteFixed,
+};
 
 pub struct File {
     fd: SharedFd,
 }
 
 impl File {
-    pub async fn read_at<T>(&self, buf: T, offset: u64) -> BufResult<T>
+    /// Read into `buf` at `offset`, resolves to a [`BufResult`].
+    ///
+    /// It could be bounded by a deadline, e.g. `file.read_at(buf, 0).timeout(duration)`.
+    pub fn read_at<T>(&self, buf: T, offset: u64) -> Prepared<Read<T>>
     where
         T: AsIoVecMut,
     {
-        let op = Op::read_at(&self.fd, buf, offset).unwrap();
-        op.complete().await
+        Op::read_at(&self.fd, buf, offset)
     }
 
-    pub async fn write_at<T>(&self, buf: T, offset: u64) -> BufResult<T>
+    /// Write `buf` at `offset`, resolves to a [`BufResult`].
+    ///
+    /// It could be bounded by a deadline, e.g. `file.write_at(buf, 0).timeout(duration)`.
+    pub fn write_at<T>(&self, buf: T, offset: u64) -> Prepared<Write<T>>
     where
         T: AsIoVec,
     {
-        let op = Op::write_at(&self.fd, buf, offset).unwrap();
-        op.complete().await
+        Op::write_at(&self.fd, buf, offset)
+    }
+
+    /// Read into a registered buffer at `offset`, up to its capacity.
+    ///
+    /// The length of the buffer is set to the bytes read.
+    pub fn read_fixed_at(&self, buf: FixedBuf, offset: u64) -> Prepared<ReadFixed> {
+        Op::read_fixed_at(&self.fd, buf, offset)
+    }
+
+    /// Write the data of a registered buffer at `offset`.
+    pub fn write_fixed_at(&self, buf: FixedBuf, offset: u64) -> Prepared<WriteFixed> {
+        Op::write_fixed_at(&self.fd, buf, offset)
+    }
+
+    /// Read at `offset` into a buffer picked by kernel from `ring`, resolves to
+    /// `None` at the end of file.
+    ///
+    /// The buffer is given back to the ring when dropped.
+    pub fn read_provided_at(&self, ring: &BufRing, offset: u64) -> Prepared<ReadProvided> {
+        Op::read_provided_at(&self.fd, ring, offset)
+    }
+
+    /// Keep reading into buffers picked by kernel from `ring`, until the end of
+    /// file, with a multishot read.
+    ///
+    /// The file must be pollable, like a pipe or a socket. Requires Linux 6.7.
+    pub fn read_multi(&self, ring: &BufRing) -> crate::Result<ReadMulti> {
+        let op = Op::read_multi(&self.fd, ring)?;
+        Ok(ReadMulti::new(op, ring.clone()))
     }
 
-    pub async fn open<P>(path: P) -> std::io::Result<Self>
+    pub async fn open<P>(path: P) -> crate::Result<Self>
     where
         P: AsRef<Path>,
     {
         OpenOptions::new().read(true).open(path).await
     }
 
-    pub async fn create<P>(path: P) -> std::io::Result<Self>
+    pub async fn create<P>(path: P) -> crate::Result<Self>
     where
         P: AsRef<Path>,
     {
@@ -47,16 +95,19 @@ impl File {
             .await
     }
 
-    pub async fn metadata(&self) -> std::io::Result<Metadata> {
-        Op::statx_using_fd(&self.fd)?.complete().await
+    pub async fn metadata(&self) -> crate::Result<Metadata> {
+        Op::statx_using_fd(&self.fd).await
     }
 
-    pub async fn sync_all(&self) -> std::io::Result<()> {
-        Op::sync_all(&self.fd)?.complete().await
+    /// Flush data and metadata to the disk, resolves to `io::Result<()>`.
+    pub fn sync_all(&self) -> Prepared<Fsync> {
+        Op::sync_all(&self.fd)
     }
 
-    pub async fn sync_data(&self) -> std::io::Result<()> {
-        Op::sync_data(&self.fd)?.complete().await
+    /// Flush data to the disk, and metadata only if it is needed to read the
+    /// data, resolves to `io::Result<()>`.
+    pub fn sync_data(&self) -> Prepared<Fsync> {
+        Op::sync_data(&self.fd)
     }
 
     /// # Safety
@@ -75,7 +126,34 @@ impl File {
         unsafe { Self::from_raw_fd(raw_fd) }
     }
 
-    pub async fn close(mut self) -> std::io::Result<()> {
+    /// Move the file into the registered file table of the runtime, so the kernel
+    /// does not look up the fd on each operation.
+    ///
+    /// The raw fd is closed, it fails if there are in-flight operations on it.
+    /// See [`RuntimeBuilder::registered_files`].
+    ///
+    /// [`RuntimeBuilder::registered_files`]: crate::uring::rt::RuntimeBuilder::registered_files
+    pub fn register(&mut self) -> crate::Result<()> {
+        Ok(self.fd.register()?)
+    }
+
+    /// Whether the file is in the registered file table.
+    pub fn is_registered(&self) -> bool {
+        self.fd.raw_fd().is_none()
+    }
+
+    /// Cancel all in-flight operations on this file, returns how many were cancelled.
+    ///
+    /// Cancelled operations complete with `ECANCELED`.
+    pub async fn cancel_all(&self) -> crate::Result<usize> {
+        Op::cancel_fd(&self.fd).await
+    }
+
+    pub(super) fn fd(&self) -> &SharedFd {
+        &self.fd
+    }
+
+    pub async fn close(mut self) -> crate::Result<()> {
         self.fd.close().await;
         Ok(())
     }
@@ -92,6 +170,7 @@ mod tests {
     use core::slice;
     use std::{
         alloc::Layout,
+        cell::Cell,
         io::{Read, Write},
         marker::PhantomData,
         ops::{Deref, DerefMut},
@@ -100,16 +179,23 @@ mod tests {
             unix::fs::MetadataExt,
         },
         ptr::NonNull,
+        rc::Rc,
+        time::Duration,
         vec,
     };
 
+    use futures::StreamExt;
     use rustix::fs::OFlags;
     use static_assertions::assert_impl_all;
     use tempfile::tempfile;
 
-    use crate::uring::{
-        fs::{OpenOptions, shared_fd::SharedFd},
-        rt::{Runtime, default_rt},
+    use crate::{
+        Error,
+        uring::{
+            buf::{BufRing, FixedBufPool, FixedBufRegistry},
+            fs::{OpenOptions, shared_fd::SharedFd},
+            rt::{Runtime, RuntimeBuilder, default_rt},
+        },
     };
 
     use super::File;
@@ -179,6 +265,30 @@ mod tests {
         }
     }
 
+    /// Buffer which marks itself dropped.
+    struct FlaggedBuffer {
+        buf: Vec<u8>,
+        dropped: Rc<Cell<bool>>,
+    }
+
+    impl AsRef<[u8]> for FlaggedBuffer {
+        fn as_ref(&self) -> &[u8] {
+            &self.buf
+        }
+    }
+
+    impl AsMut<[u8]> for FlaggedBuffer {
+        fn as_mut(&mut self) -> &mut [u8] {
+            &mut self.buf
+        }
+    }
+
+    impl Drop for FlaggedBuffer {
+        fn drop(&mut self) {
+            self.dropped.set(true);
+        }
+    }
+
     #[test]
     fn test_file_close() {
         let path = tempfile::tempdir().unwrap();
@@ -280,6 +390,19 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_open_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+
+        default_rt().unwrap().block_on(async {
+            let Err(err) = File::open(dir.path().join("missing")).await else {
+                panic!("opened a missing file");
+            };
+            assert!(matches!(err, Error::Io(_)));
+            assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        });
+    }
+
     #[test]
     fn test_create_and_write() {
         let path = tempfile::tempdir().unwrap();
@@ -415,6 +538,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::collapsible_if)]
     fn test_fd_closed() {
         let mut tempfile = tempfile::NamedTempFile::new().unwrap();
         tempfile.write_all(b"hello world").unwrap();
@@ -423,7 +547,7 @@ mod tests {
         let rt = default_rt().unwrap();
         rt.block_on(async move {
             let file = File::open(tempfile.path()).await.unwrap();
-            let fd = file.fd.raw_fd();
+            let fd = file.fd.raw_fd().unwrap();
             file.close().await.unwrap();
 
             let raw_meta = tempfile.as_file().metadata().unwrap();
@@ -441,4 +565,364 @@ mod tests {
             }
         });
     }
+
+    #[test]
+    fn test_select_timeout_releases_buffer() {
+        let (rx, tx) = std::io::pipe().unwrap();
+
+        let rt = default_rt().unwrap();
+        rt.block_on(async move {
+            let file = File::from_std_fd(std::fs::File::from(std::os::fd::OwnedFd::from(rx)));
+            let dropped = Rc::new(Cell::new(false));
+            let buf = FlaggedBuffer {
+                buf: vec![0; 16],
+                dropped: dropped.clone(),
+            };
+
+            tokio::select! {
+                _ = file.read_at(buf, 0) => panic!("nothing was written to the pipe"),
+                _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+            }
+
+            // Nothing is written, the buffer is released because the read was
+            // cancelled in kernel.
+            tokio::time::timeout(Duration::from_secs(1), async {
+                while !dropped.get() {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            })
+            .await
+            .expect("buffer was not released");
+
+            drop(tx);
+        });
+    }
+
+    #[test]
+    fn test_drop_file_of_ignored_op() {
+        let (rx, tx) = std::io::pipe().unwrap();
+        let mut tempfile = tempfile().unwrap();
+        tempfile.write_all(b"hello").unwrap();
+
+        let rt = default_rt().unwrap();
+        rt.block_on(async move {
+            let file = File::from_std_fd(std::fs::File::from(std::os::fd::OwnedFd::from(rx)));
+            tokio::select! {
+                _ = file.read_at(vec![0; 16], 0) => panic!("nothing was written to the pipe"),
+                _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+            }
+            // The ignored read holds the last reference, it is closed when the
+            // cancelled read completes.
+            drop(file);
+
+            let file = File::from_std_fd(tempfile);
+            let (res, buf) =
+                tokio::time::timeout(Duration::from_secs(1), file.read_at(vec![0; 5], 0))
+                    .await
+                    .expect("the driver is stuck");
+            assert_eq!(res.unwrap(), 5);
+            assert_eq!(&buf[..], b"hello");
+            drop(tx);
+        });
+    }
+
+    #[test]
+    fn test_cancel_all() {
+        let (rx, tx) = std::io::pipe().unwrap();
+
+        let rt = default_rt().unwrap();
+        rt.block_on(async move {
+            let file = Rc::new(File::from_std_fd(std::fs::File::from(
+                std::os::fd::OwnedFd::from(rx),
+            )));
+
+            let f = file.clone();
+            let read = tokio::task::spawn_local(async move { f.read_at(vec![0_u8; 16], 0).await });
+            tokio::task::yield_now().await;
+
+            assert_eq!(file.cancel_all().await.unwrap(), 1);
+            let (res, _) = read.await.unwrap();
+            assert_eq!(
+                res.unwrap_err().raw_os_error(),
+                Some(rustix::io::Errno::CANCELED.raw_os_error())
+            );
+
+            // Nothing left to cancel.
+            assert_eq!(file.cancel_all().await.unwrap(), 0);
+            drop(tx);
+        });
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let (rx, tx) = std::io::pipe().unwrap();
+
+        let rt = default_rt().unwrap();
+        rt.block_on(async move {
+            let file = File::from_std_fd(std::fs::File::from(std::os::fd::OwnedFd::from(rx)));
+
+            let (res, buf) = file
+                .read_at(vec![0_u8; 16], 0)
+                .timeout(Duration::from_millis(10))
+                .await;
+            assert!(matches!(res, Err(Error::TimedOut)));
+            assert_eq!(buf.len(), 16);
+
+            drop(tx);
+        });
+    }
+
+    #[test]
+    fn test_cancel_before_timeout() {
+        let (rx, tx) = std::io::pipe().unwrap();
+
+        let rt = default_rt().unwrap();
+        rt.block_on(async move {
+            let file = Rc::new(File::from_std_fd(std::fs::File::from(
+                std::os::fd::OwnedFd::from(rx),
+            )));
+
+            let f = file.clone();
+            let read = tokio::task::spawn_local(async move {
+                f.read_at(vec![0_u8; 16], 0)
+                    .timeout(Duration::from_secs(10))
+                    .await
+            });
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            // Cancelled by others, the timeout has not expired.
+            assert_eq!(file.cancel_all().await.unwrap(), 1);
+            let (res, _) = read.await.unwrap();
+            assert!(matches!(res, Err(Error::Cancelled)));
+            drop(tx);
+        });
+    }
+
+    #[test]
+    fn test_read_before_timeout() {
+        let mut tempfile = tempfile::NamedTempFile::new().unwrap();
+        tempfile.write_all(b"hello world").unwrap();
+
+        let rt = default_rt().unwrap();
+        rt.block_on(async move {
+            let file = File::open(tempfile.path()).await.unwrap();
+
+            let (res, buf) = file
+                .read_at(vec![0_u8; 11], 0)
+                .timeout(Duration::from_secs(10))
+                .await;
+            assert_eq!(res.unwrap(), 11);
+            assert_eq!(&buf[..], b"hello world");
+        });
+    }
+
+    #[test]
+    fn test_register() {
+        let rt = RuntimeBuilder::new().registered_files(1).build().unwrap();
+        rt.block_on(async {
+            let mut std_file = tempfile().unwrap();
+            std_file.write_all(b"hello world").unwrap();
+
+            let mut file = File::from_std_fd(std_file);
+            file.register().unwrap();
+            assert!(file.is_registered());
+
+            let (res, buf) = file.read_at(vec![0; 11], 0).await;
+            assert_eq!(res.unwrap(), 11);
+            assert_eq!(&buf[..], b"hello world");
+
+            let (res, _) = file.write_at(b"HELLO", 0).await;
+            assert_eq!(res.unwrap(), 5);
+            file.sync_all().await.unwrap();
+            assert_eq!(
+                file.metadata().await.err().unwrap().kind(),
+                std::io::ErrorKind::Unsupported
+            );
+
+            // The only slot is in use.
+            let mut other = File::from_std_fd(tempfile().unwrap());
+            let err = other.register().unwrap_err();
+            assert_eq!(
+                err.raw_os_error(),
+                Some(rustix::io::Errno::NFILE.raw_os_error())
+            );
+
+            // And reused after close.
+            file.close().await.unwrap();
+            other.register().unwrap();
+            other.close().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_register_without_table() {
+        default_rt().unwrap().block_on(async {
+            let mut file = File::from_std_fd(tempfile().unwrap());
+            let err = file.register().unwrap_err();
+            assert_eq!(
+                err.raw_os_error(),
+                Some(rustix::io::Errno::NXIO.raw_os_error())
+            );
+            assert!(!file.is_registered());
+        });
+    }
+
+    #[test]
+    fn test_open_fixed_file() {
+        let mut tempfile = tempfile::NamedTempFile::new().unwrap();
+        tempfile.write_all(b"hello world").unwrap();
+
+        let rt = RuntimeBuilder::new().registered_files(4).build().unwrap();
+        rt.block_on(async move {
+            let file = OpenOptions::new()
+                .read(true)
+                .fixed_file(true)
+                .open(tempfile.path())
+                .await
+                .unwrap();
+            assert!(file.is_registered());
+
+            let (res, buf) = file.read_at(vec![0; 11], 0).await;
+            assert_eq!(res.unwrap(), 11);
+            assert_eq!(&buf[..], b"hello world");
+            file.close().await.unwrap();
+
+            let res = OpenOptions::new()
+                .read(true)
+                .fixed_file(true)
+                .open(tempfile.path().join("not_found"))
+                .await;
+            assert!(res.is_err());
+        });
+    }
+
+    #[test]
+    fn test_read_write_fixed() {
+        let rt = RuntimeBuilder::new().registered_files(1).build().unwrap();
+        rt.block_on(async {
+            let pool = FixedBufPool::new([Vec::with_capacity(4096), Vec::with_capacity(65536)]);
+            pool.register().unwrap();
+
+            let mut file = File::from_std_fd(tempfile().unwrap());
+            file.register().unwrap();
+
+            let mut buf = pool.try_next(4096).unwrap();
+            buf.extend_from_slice(b"hello world");
+            let (res, buf) = file.write_fixed_at(buf, 0).await;
+            assert_eq!(res.unwrap(), 11);
+            drop(buf);
+
+            let buf = pool.next(4096).await.unwrap();
+            let (res, buf) = file.read_fixed_at(buf, 0).await;
+            assert_eq!(res.unwrap(), 11);
+            assert_eq!(&buf[..], b"hello world");
+            drop(buf);
+
+            file.close().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_fixed_buf_back_after_ignored() {
+        let (rx, tx) = std::io::pipe().unwrap();
+
+        default_rt().unwrap().block_on(async move {
+            let registry = FixedBufRegistry::new([Vec::with_capacity(4096)]);
+            registry.register().unwrap();
+
+            let file = File::from_std_fd(std::fs::File::from(std::os::fd::OwnedFd::from(rx)));
+            let buf = registry.check_out(0).unwrap();
+            let read = file.read_fixed_at(buf, 0);
+            let res = crate::time::timeout(Duration::from_millis(10), read).await;
+            assert!(res.is_err());
+
+            // The op was ignored and cancelled, the buffer is checked in on completion.
+            crate::time::sleep(Duration::from_millis(10)).await.unwrap();
+            assert!(registry.check_out(0).is_some());
+            drop(tx);
+        });
+    }
+
+    fn pipe_file() -> (File, std::io::PipeWriter) {
+        let (rx, tx) = std::io::pipe().unwrap();
+        let file = File::from_std_fd(std::fs::File::from(std::os::fd::OwnedFd::from(rx)));
+        (file, tx)
+    }
+
+    #[test]
+    fn test_read_provided() {
+        default_rt().unwrap().block_on(async {
+            let ring = BufRing::builder(1).entries(2).buf_len(16).build().unwrap();
+            let (file, mut tx) = pipe_file();
+
+            // More reads than buffers, they are given back when dropped.
+            for data in [&b"hello"[..], b"world", b"again"] {
+                tx.write_all(data).unwrap();
+                let buf = file.read_provided_at(&ring, 0).await.unwrap().unwrap();
+                assert_eq!(&buf[..], data);
+            }
+
+            tx.write_all(b"one").unwrap();
+            let first = file.read_provided_at(&ring, 0).await.unwrap().unwrap();
+            tx.write_all(b"two").unwrap();
+            let second = file.read_provided_at(&ring, 0).await.unwrap().unwrap();
+            assert_ne!(first.bid(), second.bid());
+
+            // Both buffers are borrowed.
+            tx.write_all(b"three").unwrap();
+            let err = file.read_provided_at(&ring, 0).await.unwrap_err();
+            assert_eq!(
+                err.raw_os_error(),
+                Some(rustix::io::Errno::NOBUFS.raw_os_error())
+            );
+
+            drop((first, second, tx));
+            assert_eq!(
+                &file.read_provided_at(&ring, 0).await.unwrap().unwrap()[..],
+                b"three"
+            );
+            assert!(file.read_provided_at(&ring, 0).await.unwrap().is_none());
+            file.close().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_read_multi() {
+        default_rt().unwrap().block_on(async {
+            let ring = BufRing::builder(2).entries(4).buf_len(16).build().unwrap();
+            let (file, mut tx) = pipe_file();
+            let mut stream = file.read_multi(&ring).unwrap();
+
+            for data in [&b"hello"[..], b"world", b"again", b"and again"] {
+                tx.write_all(data).unwrap();
+                let buf = stream.next().await.unwrap().unwrap();
+                assert_eq!(&buf[..], data);
+            }
+
+            drop(tx);
+            assert!(stream.next().await.is_none());
+            drop(stream);
+            file.close().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_drop_read_multi() {
+        default_rt().unwrap().block_on(async {
+            let ring = BufRing::builder(3).entries(1).buf_len(16).build().unwrap();
+            let (file, mut tx) = pipe_file();
+
+            tx.write_all(b"hello").unwrap();
+            let stream = file.read_multi(&ring).unwrap();
+            crate::time::sleep(Duration::from_millis(10)).await.unwrap();
+            drop(stream);
+
+            // The buffer of the completion nobody took is given back.
+            tx.write_all(b"world").unwrap();
+            let buf = file.read_provided_at(&ring, 0).await.unwrap().unwrap();
+            assert_eq!(&buf[..], b"world");
+            drop((buf, tx));
+            file.close().await.unwrap();
+        });
+    }
 }
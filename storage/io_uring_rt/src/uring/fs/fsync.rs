@@ -1,45 +1,66 @@
-use std::{future::poll_fn, pin::Pin, task::Poll};
+use std::{future::poll_fn, os::fd::BorrowedFd, pin::Pin, task::Poll};
 
 use rustix_uring::{opcode, types};
 
-use crate::uring::op::{CompleteAble, Op};
+use crate::uring::{
+    blocking::unsupported,
+    op::{CompleteAble, Op, Prepared},
+};
 
-use super::shared_fd::SharedFd;
+use super::shared_fd::{SharedFd, with_fd};
 
 pub struct Fsync {
     fd: SharedFd,
 }
 
 impl Op<Fsync> {
-    pub fn sync_all(fd: &SharedFd) -> std::io::Result<Self> {
+    pub(crate) fn sync_all(fd: &SharedFd) -> Prepared<Fsync> {
         Self::fsync(fd)
     }
 
-    fn fsync(fd: &SharedFd) -> std::io::Result<Self> {
+    fn fsync(fd: &SharedFd) -> Prepared<Fsync> {
         let data = Fsync { fd: fd.clone() };
+        let raw_fd = fd.raw_fd();
 
-        Op::submit_with(data, |fsync| {
-            opcode::Fsync::new(rustix_uring::types::Fd(fsync.fd.raw_fd())).build()
+        Op::prepare_with(data, |fsync| {
+            with_fd!(fsync.fd, |fd| opcode::Fsync::new(fd).build())
         })
+        .fallback(
+            move || {
+                let fd = unsafe { BorrowedFd::borrow_raw(raw_fd.ok_or_else(unsupported)?) };
+                Ok(rustix::fs::fsync(fd)?)
+            },
+            |_, ()| 0,
+        )
     }
 
-    pub fn sync_data(fd: &SharedFd) -> std::io::Result<Self> {
+    pub(crate) fn sync_data(fd: &SharedFd) -> Prepared<Fsync> {
         Self::fdatasync(fd)
     }
 
-    fn fdatasync(fd: &SharedFd) -> std::io::Result<Self> {
+    fn fdatasync(fd: &SharedFd) -> Prepared<Fsync> {
         let data = Fsync { fd: fd.clone() };
+        let raw_fd = fd.raw_fd();
 
-        Op::submit_with(data, |fsync| {
-            opcode::Fsync::new(rustix_uring::types::Fd(fsync.fd.raw_fd()))
-                .flags(types::FsyncFlags::DATASYNC)
-                .build()
+        Op::prepare_with(data, |fsync| {
+            with_fd!(fsync.fd, |fd| {
+                opcode::Fsync::new(fd)
+                    .flags(types::FsyncFlags::DATASYNC)
+                    .build()
+            })
         })
+        .fallback(
+            move || {
+                let fd = unsafe { BorrowedFd::borrow_raw(raw_fd.ok_or_else(unsupported)?) };
+                Ok(rustix::fs::fdatasync(fd)?)
+            },
+            |_, ()| 0,
+        )
     }
 }
 
 impl CompleteAble for Fsync {
-    type Output = std::io::Result<()>;
+    type Output = crate::Result<()>;
 
     fn handle_completion(comp: crate::uring::op::Completion<Self>) -> Self::Output {
         comp.result.map(|_| ())
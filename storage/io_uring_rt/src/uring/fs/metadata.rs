@@ -1,9 +1,9 @@
-use std::mem::MaybeUninit;
+use std::{mem::MaybeUninit, os::fd::BorrowedFd};
 
 use rustix::fs::AtFlags;
 use rustix_uring::{opcode, types};
 
-use crate::uring::op::{CompleteAble, Op};
+use crate::uring::op::{CompleteAble, Op, Prepared};
 
 use super::shared_fd::SharedFd;
 
@@ -38,26 +38,45 @@ pub(crate) struct Statx {
 }
 
 impl Op<Statx> {
-    pub(crate) fn statx_using_fd(fd: &SharedFd) -> std::io::Result<Self> {
+    /// Statx does not accept direct descriptors, so it is unsupported for
+    /// registered files.
+    pub(crate) fn statx_using_fd(fd: &SharedFd) -> Prepared<Statx> {
         let flags = AtFlags::STATX_SYNC_AS_STAT | AtFlags::EMPTY_PATH;
         let statx = Statx {
             fd: fd.clone(),
             buf: Box::new(MaybeUninit::uninit()),
         };
 
-        Op::submit_with(statx, |statx| {
-            let fd = statx.fd.raw_fd();
+        let Some(raw_fd) = fd.raw_fd() else {
+            let err = crate::Error::Unsupported("statx on a registered file");
+            return Prepared::failed(statx, err.into());
+        };
+
+        Op::prepare_with(statx, |statx| {
+            let fd = statx.fd.raw_fd().unwrap();
             let statx_buf = statx.buf.as_mut_ptr();
 
             opcode::Statx::new(types::Fd(fd), c"".as_ptr(), statx_buf)
                 .flags(flags)
                 .build()
         })
+        .fallback(
+            move || {
+                // The fd is kept open by the op until the call is done.
+                let fd = unsafe { BorrowedFd::borrow_raw(raw_fd) };
+                let mask = rustix::fs::StatxFlags::BASIC_STATS;
+                Ok(rustix::fs::statx(fd, "", flags, mask)?)
+            },
+            |statx, attr| {
+                statx.buf.write(attr);
+                0
+            },
+        )
     }
 }
 
 impl CompleteAble for Statx {
-    type Output = std::io::Result<Metadata>;
+    type Output = crate::Result<Metadata>;
 
     fn handle_completion(comp: crate::uring::op::Completion<Self>) -> Self::Output {
         let res = comp.result?;
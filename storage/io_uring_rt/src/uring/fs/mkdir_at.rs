@@ -10,27 +10,38 @@ use std::{
 use rustix::fs::{CWD, Mode};
 use rustix_uring::{opcode, types};
 
-use crate::uring::op::{CompleteAble, Completion, Op};
+use crate::uring::op::{CompleteAble, Completion, Op, Prepared};
 
 pub struct Mkdir {
     pub(crate) path: CString,
 }
 
 impl Op<Mkdir> {
-    pub fn mkdir<P: AsRef<Path>>(path: P, mode: Mode) -> std::io::Result<Self> {
-        let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+    pub fn mkdir<P: AsRef<Path>>(path: P, mode: Mode) -> Prepared<Mkdir> {
+        let path = match CString::new(path.as_ref().as_os_str().as_bytes()) {
+            Ok(path) => path,
+            Err(e) => {
+                let path = CString::default();
+                return Prepared::failed(Mkdir { path }, e.into());
+            }
+        };
 
-        Op::submit_with(Mkdir { path }, |mkdir| {
+        let blocking_path = path.clone();
+        Op::prepare_with(Mkdir { path }, |mkdir| {
             let ptr = mkdir.path.as_c_str().as_ptr();
             opcode::MkDirAt::new(types::Fd(CWD.as_raw_fd()), ptr)
                 .mode(mode)
                 .build()
         })
+        .fallback(
+            move || Ok(rustix::fs::mkdir(blocking_path, mode)?),
+            |_, ()| 0,
+        )
     }
 }
 
 impl CompleteAble for Mkdir {
-    type Output = std::io::Result<()>;
+    type Output = crate::Result<()>;
 
     fn handle_completion(comp: Completion<Self>) -> Self::Output {
         comp.result.map(|_| ())
@@ -1,3 +1,4 @@
+mod cancel;
 mod close;
 mod cread_dir_all;
 mod file;
@@ -7,9 +8,13 @@ mod mkdir_at;
 mod open;
 mod open_options;
 mod read;
+mod read_fixed;
+mod read_provided;
 mod removed;
 mod rename;
+mod stream;
 mod write;
+mod write_fixed;
 
 pub(crate) mod shared_fd;
 
@@ -17,9 +22,12 @@ use std::{future::poll_fn, path::Path, pin::Pin};
 
 pub use file::File;
 pub use open_options::OpenOptions;
+pub use read_provided::ReadMulti;
+use rename::Rename;
 use rustix::fs::Mode;
+pub use stream::FileStream;
 
-use super::op::{Completion, Op};
+use super::op::{Completion, Op, Prepared};
 
 pub trait AsIoVec: Unpin + 'static {
     fn as_io_vec(&self) -> (*mut u8, usize);
@@ -56,16 +64,14 @@ pub trait AsIoVecMut: AsIoVec {
 
 impl<T> AsIoVecMut for T where T: AsMut<[u8]> + AsIoVec {}
 
-pub async fn mkdir<P>(path: P) -> std::io::Result<()>
+pub async fn mkdir<P>(path: P) -> crate::Result<()>
 where
     P: AsRef<Path>,
 {
-    Op::mkdir(&path, rustix::fs::Mode::from(0o777))?
-        .complete()
-        .await
+    Op::mkdir(&path, rustix::fs::Mode::from(0o777)).await
 }
 
-pub async fn create_dir_all<P>(path: P) -> std::io::Result<()>
+pub async fn create_dir_all<P>(path: P) -> crate::Result<()>
 where
     P: AsRef<Path>,
 {
@@ -75,26 +81,29 @@ where
         .await
 }
 
-pub async fn remove_dir<P>(path: P) -> std::io::Result<()>
+pub async fn remove_dir<P>(path: P) -> crate::Result<()>
 where
     P: AsRef<Path>,
 {
-    Op::unlink_dir(&path)?.complete().await
+    Op::unlink_dir(&path).await
 }
 
-pub async fn remove_file<P>(path: P) -> std::io::Result<()>
+pub async fn remove_file<P>(path: P) -> crate::Result<()>
 where
     P: AsRef<Path>,
 {
-    Op::unlink_file(&path)?.complete().await
+    Op::unlink_file(&path).await
 }
 
-pub async fn rename<P, Q>(from: P, to: Q) -> std::io::Result<()>
+/// Rename `from` to `to`, resolves to `io::Result<()>`.
+///
+/// It could be linked after other operations, see [`link`](crate::uring::link::link).
+pub fn rename<P, Q>(from: P, to: Q) -> Prepared<Rename>
 where
     P: AsRef<Path>,
     Q: AsRef<Path>,
 {
-    Op::rename(from, to)?.complete().await
+    Op::rename(from, to)
 }
 
 #[cfg(test)]
@@ -102,7 +111,12 @@ mod test {
     use tempfile::tempdir;
     use tokio::fs::{remove_dir, remove_file};
 
-    use crate::uring::{fs::rename, rt::default_rt};
+    use rustix_uring::opcode;
+
+    use crate::uring::{
+        fs::{File, OpenOptions, rename},
+        rt::{Backend, RuntimeBuilder, default_rt},
+    };
 
     use super::{create_dir_all, mkdir};
 
@@ -245,4 +259,85 @@ mod test {
             assert!(new_path.is_file());
         });
     }
+
+    #[test]
+    fn test_blocking_fallback() {
+        let rt = RuntimeBuilder::new()
+            .disable_opcode(opcode::MkDirAt::CODE)
+            .disable_opcode(opcode::RenameAt::CODE)
+            .disable_opcode(opcode::UnlinkAt::CODE)
+            .disable_opcode(opcode::Statx::CODE)
+            .build()
+            .unwrap();
+        assert!(!rt.is_supported(opcode::MkDirAt::CODE));
+        assert!(rt.is_supported(opcode::Read::CODE));
+
+        let tempdir = tempdir().unwrap();
+        let dir = tempdir.path().join("dir");
+        let path = dir.join("test.txt");
+        let new_path = dir.join("test2.txt");
+
+        rt.block_on(async {
+            mkdir(&dir).await.unwrap();
+            assert!(dir.is_dir());
+            assert!(mkdir(&dir).await.is_err());
+
+            std::fs::write(&path, b"hello").unwrap();
+            let file = File::from_std_fd(std::fs::File::open(&path).unwrap());
+            assert_eq!(file.metadata().await.unwrap().size(), 5);
+            file.close().await.unwrap();
+
+            rename(&path, &new_path).await.unwrap();
+            assert!(!path.exists());
+            super::remove_file(&new_path).await.unwrap();
+            assert!(!new_path.exists());
+            super::remove_dir(&dir).await.unwrap();
+            assert!(!dir.exists());
+        });
+    }
+
+    #[test]
+    fn test_thread_pool_backend() {
+        let rt = RuntimeBuilder::new()
+            .backend(Backend::ThreadPool)
+            .build()
+            .unwrap();
+        assert_eq!(rt.backend(), Backend::ThreadPool);
+        assert!(!rt.is_supported(opcode::Read::CODE));
+
+        let tempdir = tempdir().unwrap();
+        let dir = tempdir.path().join("dir");
+        let path = dir.join("test.txt");
+        let new_path = dir.join("test2.txt");
+
+        rt.block_on(async {
+            create_dir_all(&dir).await.unwrap();
+
+            let file = File::create(&path).await.unwrap();
+            let (res, _) = file.write_at(b"hello world".to_vec(), 0).await;
+            assert_eq!(res.unwrap(), 11);
+            file.sync_all().await.unwrap();
+            assert_eq!(file.cancel_all().await.unwrap(), 0);
+            assert_eq!(file.metadata().await.unwrap().size(), 11);
+            file.close().await.unwrap();
+
+            rename(&path, &new_path).await.unwrap();
+            let file = OpenOptions::new().read(true).open(&new_path).await.unwrap();
+            let (res, buf) = file.read_at(vec![0; 5], 6).await;
+            assert_eq!(res.unwrap(), 5);
+            assert_eq!(&buf[..], b"world");
+            // Ring specific features are not available.
+            let mut file = file;
+            assert_eq!(
+                file.register().unwrap_err().kind(),
+                std::io::ErrorKind::Unsupported
+            );
+            file.close().await.unwrap();
+
+            assert!(OpenOptions::new().read(true).open(&path).await.is_err());
+            super::remove_file(&new_path).await.unwrap();
+            super::remove_dir(&dir).await.unwrap();
+            assert!(!dir.exists());
+        });
+    }
 }
@@ -1,45 +1,92 @@
 use std::{
     ffi::CString,
     os::{
-        fd::{AsRawFd, RawFd},
+        fd::{AsRawFd, IntoRawFd, RawFd},
         unix::ffi::OsStrExt,
     },
     path::Path,
 };
 
 use rustix::fs::CWD;
-use rustix_uring::{opcode, types};
+use rustix_uring::{
+    opcode,
+    types::{self, DestinationSlot},
+};
 
-use crate::uring::op::{CompleteAble, Completion, Op};
+use crate::uring::{
+    driver::with_current,
+    op::{CompleteAble, Completion, Op, Prepared},
+};
 
 use super::{File, OpenOptions, shared_fd::SharedFd};
 
 pub struct Open {
     path: CString,
+    /// Slot of the registered file table, for a direct descriptor. It is taken
+    /// when the op is pushed, and given back when dropped unless it is opened.
+    slot: Option<u32>,
 }
 
 impl Op<Open> {
-    pub fn open<P: AsRef<Path>>(path: P, opts: &OpenOptions) -> std::io::Result<Self> {
-        let flag = opts.gen_flags()?;
-        let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+    pub fn open<P: AsRef<Path>>(path: P, opts: &OpenOptions) -> Prepared<Open> {
+        let args = || -> std::io::Result<_> {
+            let flag = opts.gen_flags()?;
+            let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+            Ok((flag, path))
+        };
+        let (flag, path) = match args() {
+            Ok(args) => args,
+            Err(e) => {
+                let path = CString::default();
+                return Prepared::failed(Open { path, slot: None }, e);
+            }
+        };
 
-        Op::submit_with(Open { path }, |open| {
+        let (blocking_path, mode) = (path.clone(), opts.mode);
+        let entry = move |open: &Open| {
             let ptr = open.path.as_c_str().as_ptr();
             opcode::OpenAt::new(types::Fd(CWD.as_raw_fd()), ptr)
+                .file_index(open.slot.map(|slot| {
+                    DestinationSlot::try_from_slot_target(slot).expect("invalid file slot")
+                }))
                 .flags(flag)
-                .mode(opts.mode)
+                .mode(mode)
                 .build()
-        })
+        };
+        let op = Op::prepare_with(Open { path, slot: None }, |open| entry(open));
+
+        // A direct descriptor is only opened by the ring.
+        if opts.fixed_file {
+            return op.on_push(move |open, driver| {
+                open.slot = Some(driver.alloc_file_slot()?);
+                Ok(entry(open))
+            });
+        }
+        op.fallback(
+            move || Ok(rustix::fs::openat(CWD, blocking_path, flag, mode)?),
+            |_, fd| fd.into_raw_fd() as u32,
+        )
     }
 }
 
 impl CompleteAble for Open {
-    type Output = std::io::Result<File>;
+    type Output = crate::Result<File>;
+
+    fn handle_completion(mut comp: Completion<Self>) -> Self::Output {
+        let fd = match (comp.result?, comp.data.slot.take()) {
+            (_, Some(slot)) => SharedFd::new_fixed(slot),
+            (raw_fd, None) => SharedFd::new(raw_fd as RawFd),
+        };
+        Ok(File::from(fd))
+    }
+}
 
-    fn handle_completion(comp: Completion<Self>) -> Self::Output {
-        let raw_fd = comp.result?;
-        let fd = SharedFd::new(raw_fd as RawFd);
-        let file = File::from(fd);
-        Ok(file)
+impl Drop for Open {
+    fn drop(&mut self) {
+        // Not opened, or opened after the op was dropped, the direct descriptor
+        // is closed with the slot.
+        if let Some(slot) = self.slot {
+            let _ = with_current(|driver| driver.unregister_file(slot));
+        }
     }
 }
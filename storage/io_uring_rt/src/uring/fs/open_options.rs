@@ -19,6 +19,8 @@ pub struct OpenOptions {
     // system-specific
     custom_flags: OFlags,
     pub(crate) mode: Mode,
+
+    pub(crate) fixed_file: bool,
 }
 
 impl Default for OpenOptions {
@@ -39,6 +41,8 @@ impl OpenOptions {
 
             custom_flags: OFlags::empty(),
             mode: Mode::from_bits(0o666).unwrap(), // Mode::RUSR | Mode::WUSR | Mode::RGRP | Mode::WGRP | Mode::ROTH | Mode::WOTH,
+
+            fixed_file: false,
         }
     }
 
@@ -82,8 +86,17 @@ impl OpenOptions {
         self
     }
 
-    pub async fn open<P: AsRef<Path>>(&self, path: P) -> std::io::Result<File> {
-        Op::open(path, self)?.complete().await
+    /// Open as a direct descriptor, which is only in the registered file table
+    /// of the runtime, see [`RuntimeBuilder::registered_files`].
+    ///
+    /// [`RuntimeBuilder::registered_files`]: crate::uring::rt::RuntimeBuilder::registered_files
+    pub fn fixed_file(&mut self, fixed_file: bool) -> &mut Self {
+        self.fixed_file = fixed_file;
+        self
+    }
+
+    pub async fn open<P: AsRef<Path>>(&self, path: P) -> crate::Result<File> {
+        Op::open(path, self).await
     }
 
     fn get_access_mode(&self) -> std::io::Result<OFlags> {
@@ -130,7 +143,11 @@ impl OpenOptions {
         let access = self.get_access_mode()?;
         let creation = self.get_creation_mode()?;
         let custom = self.custom_flags & !OFlags::ACCMODE;
-        let flags = OFlags::CLOEXEC | access | creation | custom;
+        let mut flags = OFlags::CLOEXEC | access | creation | custom;
+        if self.fixed_file {
+            // A direct descriptor is never inherited, kernel rejects the flag.
+            flags.remove(OFlags::CLOEXEC);
+        }
         // eprintln!(
         //     "Flags: access={:?}, creation={:?}, custom={:?}, combined={:?}",
         //     access, creation, custom, flags
@@ -1,10 +1,14 @@
-use std::{future::poll_fn, pin::Pin, task::ready};
+use std::{future::poll_fn, os::fd::BorrowedFd, pin::Pin, task::ready};
 
 use rustix_uring::{opcode, types};
 
-use super::{AsIoVecMut, shared_fd::SharedFd};
+use super::{
+    AsIoVecMut,
+    shared_fd::{SharedFd, with_fd},
+};
 use crate::uring::{
-    op::{CompleteAble, Completion, Op},
+    blocking::{SendPtr, unsupported},
+    op::{CompleteAble, Completion, Op, Prepared},
     prelude::BufResult,
 };
 
@@ -23,15 +27,31 @@ impl<T> Op<Read<T>>
 where
     T: AsIoVecMut,
 {
-    pub fn read_at(fd: &SharedFd, buf: T, offset: u64) -> std::io::Result<Self> {
-        Op::submit_with(Read::new(fd.clone(), buf), |read| {
-            let fd = read.fd.raw_fd();
-            let (ptr, len) = read.buf.as_mut().unwrap().as_io_vec_mut();
-
-            opcode::Read::new(types::Fd(fd), ptr, len as _)
-                .offset(offset)
-                .build()
+    pub(crate) fn read_at(fd: &SharedFd, buf: T, offset: u64) -> Prepared<Read<T>> {
+        let mut read = Read::new(fd.clone(), buf);
+        let (ptr, len) = read.buf.as_mut().unwrap().as_io_vec_mut();
+        let (raw_fd, ptr) = (fd.raw_fd(), SendPtr(ptr));
+
+        Op::prepare_with(read, |read| {
+            with_fd!(read.fd, |fd| {
+                opcode::Read::new(fd, ptr.get(), len as _)
+                    .offset(offset)
+                    .build()
+            })
         })
+        .fallback(
+            move || {
+                let fd = unsafe { BorrowedFd::borrow_raw(raw_fd.ok_or_else(unsupported)?) };
+                // Safety: the buffer is kept alive by the op until the read is done.
+                let buf = unsafe { std::slice::from_raw_parts_mut(ptr.get(), len) };
+                match rustix::io::pread(fd, &mut *buf, offset) {
+                    // Not seekable, e.g. a pipe.
+                    Err(rustix::io::Errno::SPIPE) => Ok(rustix::io::read(fd, buf)?),
+                    res => Ok(res?),
+                }
+            },
+            |_, n| n as u32,
+        )
     }
 }
 
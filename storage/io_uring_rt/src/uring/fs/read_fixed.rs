@@ -0,0 +1,47 @@
+use rustix_uring::opcode;
+
+use super::shared_fd::{SharedFd, with_fd};
+use crate::uring::{
+    buf::FixedBuf,
+    op::{CompleteAble, Completion, Op, Prepared},
+    prelude::BufResult,
+};
+
+pub struct ReadFixed {
+    fd: SharedFd,
+    buf: FixedBuf,
+}
+
+impl Op<ReadFixed> {
+    pub(crate) fn read_fixed_at(fd: &SharedFd, buf: FixedBuf, offset: u64) -> Prepared<ReadFixed> {
+        let read = ReadFixed {
+            fd: fd.clone(),
+            buf,
+        };
+
+        Op::prepare_with(read, |read| {
+            let ptr = read.buf.as_mut_ptr();
+            let len = read.buf.capacity() as u32;
+            let index = read.buf.buf_index();
+
+            with_fd!(read.fd, |fd| {
+                opcode::ReadFixed::new(fd, ptr, len, index)
+                    .offset(offset)
+                    .build()
+            })
+        })
+    }
+}
+
+impl CompleteAble for ReadFixed {
+    type Output = BufResult<FixedBuf>;
+
+    fn handle_completion(comp: Completion<Self>) -> Self::Output {
+        let mut buf = comp.data.buf;
+        let res = comp.result.map(|n| {
+            buf.set_len(n as usize);
+            n as usize
+        });
+        (res, buf)
+    }
+}
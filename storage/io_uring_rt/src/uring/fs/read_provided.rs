@@ -0,0 +1,157 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use futures::Stream;
+use rustix::io_uring::{IoringOp, io_uring_sqe};
+use rustix_uring::{opcode, squeue};
+
+use super::shared_fd::{SharedFd, with_fd};
+use crate::uring::{
+    buf::{BorrowedBuf, BufRing},
+    op::{CompleteAble, Completion, Cqe, MultiCQE, Op, Prepared},
+};
+
+/// A read into a buffer picked by kernel from a [`BufRing`].
+pub struct ReadProvided {
+    fd: SharedFd,
+    ring: BufRing,
+}
+
+/// Build a read of `ring`'s buffer group, kernel picks the buffer when data is
+/// ready.
+fn read_entry(read: &ReadProvided, len: u32, offset: u64) -> squeue::Entry {
+    let bgid = read.ring.bgid();
+    with_fd!(read.fd, |fd| {
+        opcode::Read::new(fd, std::ptr::null_mut(), len)
+            .offset(offset)
+            .buf_group(bgid)
+            .build()
+            .flags(squeue::Flags::BUFFER_SELECT)
+    })
+}
+
+impl Op<ReadProvided> {
+    pub(crate) fn read_provided_at(
+        fd: &SharedFd,
+        ring: &BufRing,
+        offset: u64,
+    ) -> Prepared<ReadProvided> {
+        let read = ReadProvided {
+            fd: fd.clone(),
+            ring: ring.clone(),
+        };
+        let len = ring.buf_len() as u32;
+
+        let ring = ring.clone();
+        Op::prepare_with(read, |read| read_entry(read, len, offset))
+            .on_ignored(move |flags| drop(ring.take(flags, 0)))
+    }
+}
+
+impl Op<ReadProvided, MultiCQE> {
+    /// `IORING_OP_READ_MULTISHOT`, since Linux 6.7.
+    pub(crate) fn read_multi(fd: &SharedFd, ring: &BufRing) -> io::Result<Self> {
+        let read = ReadProvided {
+            fd: fd.clone(),
+            ring: ring.clone(),
+        };
+
+        let mut op = Op::submit_multi_with(read, |read| {
+            // rustix_uring has no builder of it, it is a buffer select read
+            // with another opcode, reading the whole buffer from the current position.
+            let entry = read_entry(read, 0, u64::MAX);
+            // Safety: `Entry` is a transparent wrapper of the sqe.
+            let mut sqe: io_uring_sqe = unsafe { std::mem::transmute(entry) };
+            sqe.opcode = IoringOp::ReadMultishot;
+            unsafe { std::mem::transmute::<io_uring_sqe, squeue::Entry>(sqe) }
+        })?;
+
+        let ring = ring.clone();
+        op.on_ignored(move |flags| drop(ring.take(flags, 0)));
+        Ok(op)
+    }
+}
+
+impl CompleteAble for ReadProvided {
+    /// `None` at the end of file.
+    type Output = crate::Result<Option<BorrowedBuf>>;
+
+    fn handle_completion(comp: Completion<Self>) -> Self::Output {
+        match comp.result {
+            Ok(n) => Ok(comp
+                .data
+                .ring
+                .take(comp.flags, n)
+                .filter(|buf| !buf.is_empty())),
+            Err(e) => {
+                // Give back the buffer if kernel picked one anyway.
+                drop(comp.data.ring.take(comp.flags, 0));
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Stream of buffers read by a multishot read, returned by [`File::read_multi`].
+///
+/// It ends at the end of file, or after an error. Kernel stops the read with
+/// `ENOBUFS` if the ring runs out of buffers, so they should be dropped soon.
+///
+/// [`File::read_multi`]: super::File::read_multi
+pub struct ReadMulti {
+    op: Op<ReadProvided, MultiCQE>,
+    ring: BufRing,
+    done: bool,
+}
+
+impl ReadMulti {
+    pub(crate) fn new(op: Op<ReadProvided, MultiCQE>, ring: BufRing) -> Self {
+        Self {
+            op,
+            ring,
+            done: false,
+        }
+    }
+
+    /// Stop reading, buffers read meanwhile are still yielded.
+    pub fn cancel(&self) -> crate::Result<()> {
+        self.op.cancel()
+    }
+}
+
+impl Stream for ReadMulti {
+    type Item = crate::Result<BorrowedBuf>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if this.done {
+                return Poll::Ready(None);
+            }
+
+            let Some(Cqe { result, flags }) = ready!(Pin::new(&mut this.op).poll_next(cx)) else {
+                this.done = true;
+                continue;
+            };
+            match result {
+                Ok(n) => match this.ring.take(flags, n) {
+                    Some(buf) if !buf.is_empty() => return Poll::Ready(Some(Ok(buf))),
+                    // End of file.
+                    _ => this.done = true,
+                },
+                Err(e) => {
+                    drop(this.ring.take(flags, 0));
+                    // A cancelled read just ends.
+                    if e.raw_os_error() == Some(rustix::io::Errno::CANCELED.raw_os_error()) {
+                        this.done = true;
+                        continue;
+                    }
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+    }
+}
@@ -10,34 +10,46 @@ use std::{
 
 use rustix_uring::{opcode, types};
 
-use crate::uring::op::{CompleteAble, Completion, Op};
+use crate::uring::op::{CompleteAble, Completion, Op, Prepared};
 
 pub struct UnlinkAt {
     path: CString,
 }
 
 impl Op<UnlinkAt> {
-    pub fn unlink_dir<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    pub fn unlink_dir<P: AsRef<Path>>(path: P) -> Prepared<UnlinkAt> {
         Self::unlink_at(path, rustix::fs::AtFlags::REMOVEDIR)
     }
 
-    pub fn unlink_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    pub fn unlink_file<P: AsRef<Path>>(path: P) -> Prepared<UnlinkAt> {
         Self::unlink_at(path, rustix::fs::AtFlags::empty())
     }
 
-    fn unlink_at<P: AsRef<Path>>(path: P, flags: rustix::fs::AtFlags) -> io::Result<Self> {
-        let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
-        Op::submit_with(UnlinkAt { path }, |unlink| {
+    fn unlink_at<P: AsRef<Path>>(path: P, flags: rustix::fs::AtFlags) -> Prepared<UnlinkAt> {
+        let path = match CString::new(path.as_ref().as_os_str().as_bytes()) {
+            Ok(path) => path,
+            Err(e) => {
+                let path = CString::default();
+                return Prepared::failed(UnlinkAt { path }, e.into());
+            }
+        };
+
+        let blocking_path = path.clone();
+        Op::prepare_with(UnlinkAt { path }, |unlink| {
             let ptr = unlink.path.as_c_str().as_ptr();
             opcode::UnlinkAt::new(types::Fd(rustix::fs::CWD.as_raw_fd()), ptr)
                 .flags(flags)
                 .build()
         })
+        .fallback(
+            move || Ok(rustix::fs::unlinkat(rustix::fs::CWD, blocking_path, flags)?),
+            |_, ()| 0,
+        )
     }
 }
 
 impl CompleteAble for UnlinkAt {
-    type Output = std::io::Result<()>;
+    type Output = crate::Result<()>;
 
     fn handle_completion(comp: Completion<Self>) -> Self::Output {
         comp.result.map(|_| ())
@@ -7,7 +7,7 @@ use std::{
 use rustix::fs::CWD;
 use rustix_uring::{opcode, types};
 
-use crate::uring::op::{CompleteAble, Op};
+use crate::uring::op::{CompleteAble, Op, Prepared};
 
 #[derive(Debug)]
 pub struct Rename {
@@ -16,16 +16,27 @@ pub struct Rename {
 }
 
 impl Op<Rename> {
-    pub fn rename<P, Q>(from: P, to: Q) -> std::io::Result<Self>
+    pub fn rename<P, Q>(from: P, to: Q) -> Prepared<Rename>
     where
         P: AsRef<Path>,
         Q: AsRef<Path>,
     {
-        let from = CString::new(from.as_ref().as_os_str().as_bytes())?;
-        let to = CString::new(to.as_ref().as_os_str().as_bytes())?;
+        let paths = CString::new(from.as_ref().as_os_str().as_bytes())
+            .and_then(|from| Ok((from, CString::new(to.as_ref().as_os_str().as_bytes())?)));
+        let (from, to) = match paths {
+            Ok(paths) => paths,
+            Err(e) => {
+                let rename = Rename {
+                    from: CString::default(),
+                    to: CString::default(),
+                };
+                return Prepared::failed(rename, e.into());
+            }
+        };
 
+        let blocking_paths = (from.clone(), to.clone());
         let rename = Rename { from, to };
-        Op::submit_with(rename, |rename| {
+        Op::prepare_with(rename, |rename| {
             let from_ptr = rename.from.as_ptr();
             let to_ptr = rename.to.as_ptr();
 
@@ -37,11 +48,18 @@ impl Op<Rename> {
             )
             .build()
         })
+        .fallback(
+            move || {
+                let (from, to) = blocking_paths;
+                Ok(rustix::fs::rename(from, to)?)
+            },
+            |_, ()| 0,
+        )
     }
 }
 
 impl CompleteAble for Rename {
-    type Output = std::io::Result<()>;
+    type Output = crate::Result<()>;
 
     fn handle_completion(comp: crate::uring::op::Completion<Self>) -> Self::Output {
         comp.result.map(|_| ())
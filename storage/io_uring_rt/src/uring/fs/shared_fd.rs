@@ -1,23 +1,55 @@
 use std::{
     cell::RefCell,
     future::poll_fn,
-    os::fd::{AsRawFd, FromRawFd, RawFd},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
     pin::Pin,
     rc::Rc,
     task::{Poll, Waker, ready},
 };
 
-use crate::uring::op::Op;
+use crate::uring::{driver::with_current, op::Op};
 
 use super::close::Close;
 
+/// Build an entry on a [`SharedFd`], with `types::Fixed` if it is in the
+/// registered file table, or `types::Fd`.
+///
+/// ```text
+/// with_fd!(fsync.fd, |fd| opcode::Fsync::new(fd).build())
+/// ```
+macro_rules! with_fd {
+    ($fd:expr, |$target:ident| $build:expr) => {
+        match $fd.target() {
+            $crate::uring::fs::shared_fd::Target::Raw(fd) => {
+                let $target = rustix_uring::types::Fd(fd);
+                $build
+            }
+            $crate::uring::fs::shared_fd::Target::Fixed(slot) => {
+                let $target = rustix_uring::types::Fixed(slot);
+                $build
+            }
+        }
+    };
+}
+
+pub(crate) use with_fd;
+
 #[derive(Clone)]
 pub(crate) struct SharedFd {
     inner: Rc<Inner>,
 }
 
+/// The descriptor used in entries.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Target {
+    Raw(RawFd),
+
+    /// A slot of the registered file table, also known as a direct descriptor.
+    Fixed(u32),
+}
+
 struct Inner {
-    fd: RawFd,
+    target: Target,
     state: RefCell<State>,
 }
 
@@ -33,16 +65,48 @@ enum State {
 
 impl SharedFd {
     pub(crate) fn new<F: AsRawFd>(fd: F) -> Self {
+        Self::with_target(Target::Raw(fd.as_raw_fd()))
+    }
+
+    /// A file which is only in the registered file table.
+    pub(crate) fn new_fixed(slot: u32) -> Self {
+        Self::with_target(Target::Fixed(slot))
+    }
+
+    fn with_target(target: Target) -> Self {
         Self {
             inner: Rc::new(Inner {
-                fd: fd.as_raw_fd(),
+                target,
                 state: RefCell::new(State::Init),
             }),
         }
     }
 
-    pub(crate) fn raw_fd(&self) -> RawFd {
-        self.inner.fd
+    pub(crate) fn target(&self) -> Target {
+        self.inner.target
+    }
+
+    /// The raw fd, `None` if it is a direct descriptor.
+    pub(crate) fn raw_fd(&self) -> Option<RawFd> {
+        match self.inner.target {
+            Target::Raw(fd) => Some(fd),
+            Target::Fixed(_) => None,
+        }
+    }
+
+    /// Move the fd into a slot of the registered file table, and close the raw fd.
+    ///
+    /// Fails with `EBUSY` if it is shared, e.g. by in-flight ops.
+    pub(crate) fn register(&mut self) -> std::io::Result<()> {
+        let inner = Rc::get_mut(&mut self.inner).ok_or(rustix::io::Errno::BUSY)?;
+        let Target::Raw(fd) = inner.target else {
+            return Ok(());
+        };
+
+        let slot = with_current(|driver| driver.register_file(fd))?;
+        inner.target = Target::Fixed(slot);
+        drop(unsafe { OwnedFd::from_raw_fd(fd) });
+        Ok(())
     }
 
     pub(crate) async fn close(mut self) {
@@ -57,10 +121,19 @@ impl Inner {
     fn submit_close_op(&mut self) {
         let state = RefCell::get_mut(&mut self.state);
 
-        *state = match Op::close(self.fd) {
-            Ok(op) => State::Closing(op),
-            Err(_) => {
-                let _ = unsafe { std::fs::File::from_raw_fd(self.fd) };
+        *state = match self.target {
+            Target::Raw(fd) => match Op::close(fd) {
+                Ok(op) => State::Closing(op),
+                Err(_) => {
+                    let _ = unsafe { std::fs::File::from_raw_fd(fd) };
+                    State::Closed
+                }
+            },
+            Target::Fixed(slot) => {
+                // The slot is reusable at once, in-flight ops hold their own references.
+                if let Err(e) = with_current(|driver| driver.unregister_file(slot)) {
+                    tracing::warn!("failed to unregister file slot {}: {}", slot, e);
+                }
                 State::Closed
             }
         }
@@ -0,0 +1,446 @@
+use std::{
+    io::{self, SeekFrom},
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use crate::uring::op::{Op, Prepared};
+
+use super::{File, metadata::Statx, read::Read, write::Write};
+
+/// Default capacity of the buffer of a [`FileStream`].
+const DEFAULT_CAPACITY: usize = 8 * 1024;
+
+/// A [`File`] with a cursor, implementing the `AsyncRead`, `AsyncWrite` and
+/// `AsyncSeek` traits of tokio and futures, so it could be used by
+/// `tokio::io::copy`, codecs and other ecosystem code.
+///
+/// Reads fill an internal buffer ahead of the cursor. Writes are copied to it
+/// and written behind, so an error of a write is returned by the next call.
+/// Flush before it is dropped, otherwise a pending write is cancelled.
+pub struct FileStream {
+    file: File,
+    /// Position of the cursor.
+    pos: u64,
+    /// Owned by the op in flight if any, `buf[start..end]` is read ahead at `pos`.
+    buf: Option<Vec<u8>>,
+    start: usize,
+    end: usize,
+    capacity: usize,
+    state: State,
+    /// Seek started by tokio's `start_seek`.
+    seek: Option<SeekFrom>,
+}
+
+enum State {
+    Idle,
+    Reading(Prepared<Read<Vec<u8>>>),
+    /// Writing the buffer at the offset.
+    Writing(Prepared<Write<Vec<u8>>>, u64),
+    /// Getting the length, to seek from the end.
+    Statx(Prepared<Statx>),
+}
+
+impl FileStream {
+    /// A stream at the start of `file`, with a buffer of 8KiB.
+    pub fn new(file: File) -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY, file)
+    }
+
+    /// A stream at the start of `file`, with a buffer of `capacity` bytes, which
+    /// bounds the bytes of each read and write.
+    pub fn with_capacity(capacity: usize, file: File) -> Self {
+        assert!(capacity > 0, "capacity must be greater than 0");
+        Self {
+            file,
+            pos: 0,
+            buf: Some(vec![]),
+            start: 0,
+            end: 0,
+            capacity,
+            state: State::Idle,
+            seek: None,
+        }
+    }
+
+    /// Position of the cursor.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+
+    /// The file, data read ahead is dropped and a pending write is cancelled,
+    /// so flush it first.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    fn poll_read_inner(&mut self, cx: &mut Context<'_>, dst: &mut [u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_pending(cx))?;
+
+        if self.start == self.end {
+            let State::Reading(read) = &mut self.state else {
+                let mut buf = self.take_buf();
+                buf.resize(self.capacity, 0);
+                self.state = State::Reading(self.file.read_at(buf, self.pos));
+                return self.poll_read_inner(cx, dst);
+            };
+
+            let (res, buf) = ready!(Pin::new(read).poll(cx));
+            self.state = State::Idle;
+            self.buf = Some(buf);
+            // Nothing is read ahead if it failed, the next read retries.
+            let n = res?;
+            self.start = 0;
+            self.end = n;
+        }
+
+        let buf = self.buf.as_ref().unwrap();
+        let n = dst.len().min(self.end - self.start);
+        dst[..n].copy_from_slice(&buf[self.start..self.start + n]);
+        self.start += n;
+        self.pos += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_write_inner(&mut self, cx: &mut Context<'_>, src: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_pending(cx))?;
+
+        // The cursor is at `pos` whatever is read ahead.
+        self.start = 0;
+        self.end = 0;
+        if src.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let mut buf = self.take_buf();
+        buf.clear();
+        let n = src.len().min(self.capacity);
+        buf.extend_from_slice(&src[..n]);
+        self.state = State::Writing(self.file.write_at(buf, self.pos), self.pos);
+        self.pos += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    /// The buffer for a new op, after the pending write is finished.
+    ///
+    /// A read or seek abandoned while pending still owns its op, which is
+    /// dropped, so a new buffer is allocated.
+    fn take_buf(&mut self) -> Vec<u8> {
+        self.state = State::Idle;
+        self.buf.take().unwrap_or_default()
+    }
+
+    /// Finish the pending write, reads ahead are kept.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let State::Writing(write, offset) = &mut self.state else {
+                return Poll::Ready(Ok(()));
+            };
+
+            let (res, mut buf) = ready!(Pin::new(write).poll(cx));
+            let offset = *offset;
+            let written = match res {
+                Ok(0) => Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => Ok(n),
+                Err(e) => Err(io::Error::from(e)),
+            };
+            match written {
+                // A short write, write the rest.
+                Ok(n) if n < buf.len() => {
+                    buf.drain(..n);
+                    let write = self.file.write_at(buf, offset + n as u64);
+                    self.state = State::Writing(write, offset + n as u64);
+                }
+                res => {
+                    self.state = State::Idle;
+                    self.buf = Some(buf);
+                    return Poll::Ready(res.map(|_| ()));
+                }
+            }
+        }
+    }
+
+    fn poll_seek_inner(&mut self, cx: &mut Context<'_>, pos: SeekFrom) -> Poll<io::Result<u64>> {
+        ready!(self.poll_pending(cx))?;
+
+        let (base, delta) = match pos {
+            SeekFrom::Start(n) => (n, 0),
+            SeekFrom::Current(delta) => (self.pos, delta),
+            SeekFrom::End(delta) => {
+                let State::Statx(statx) = &mut self.state else {
+                    // A read ahead is dropped.
+                    self.state = State::Statx(Op::statx_using_fd(self.file.fd()));
+                    return self.poll_seek_inner(cx, pos);
+                };
+                let metadata = ready!(Pin::new(statx).poll(cx));
+                self.state = State::Idle;
+                (metadata?.size(), delta)
+            }
+        };
+
+        let pos = base.checked_add_signed(delta).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        // An abandoned read or seek is dropped.
+        self.state = State::Idle;
+        self.buf.get_or_insert_with(Vec::new);
+        self.pos = pos;
+        self.start = 0;
+        self.end = 0;
+        Poll::Ready(Ok(pos))
+    }
+}
+
+impl From<File> for FileStream {
+    fn from(file: File) -> Self {
+        Self::new(file)
+    }
+}
+
+impl tokio::io::AsyncRead for FileStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = ready!(
+            self.get_mut()
+                .poll_read_inner(cx, buf.initialize_unfilled())
+        )?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl tokio::io::AsyncWrite for FileStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write_inner(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_pending(cx)
+    }
+}
+
+impl tokio::io::AsyncSeek for FileStream {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        if this.seek.is_some() {
+            return Err(io::Error::other("other seek is pending"));
+        }
+        this.seek = Some(position);
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        let Some(position) = this.seek else {
+            return Poll::Ready(Ok(this.pos));
+        };
+        let res = ready!(this.poll_seek_inner(cx, position));
+        this.seek = None;
+        Poll::Ready(res)
+    }
+}
+
+impl futures::io::AsyncRead for FileStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_read_inner(cx, buf)
+    }
+}
+
+impl futures::io::AsyncWrite for FileStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write_inner(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_pending(cx)
+    }
+}
+
+impl futures::io::AsyncSeek for FileStream {
+    fn poll_seek(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        self.get_mut().poll_seek_inner(cx, pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, SeekFrom},
+        os::{fd::OwnedFd, unix::net::UnixStream},
+        pin::pin,
+    };
+
+    use static_assertions::assert_impl_all;
+    use tempfile::tempfile;
+
+    use crate::uring::{
+        fs::File,
+        rt::{Backend, Runtime, RuntimeBuilder, default_rt},
+    };
+
+    use super::FileStream;
+
+    assert_impl_all!(FileStream: tokio::io::AsyncRead, tokio::io::AsyncWrite, tokio::io::AsyncSeek);
+    assert_impl_all!(FileStream: futures::io::AsyncRead, futures::io::AsyncWrite, futures::io::AsyncSeek);
+
+    fn runtimes() -> Vec<Runtime> {
+        vec![
+            default_rt().unwrap(),
+            RuntimeBuilder::new()
+                .backend(Backend::ThreadPool)
+                .build()
+                .unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_tokio_io() {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+        for rt in runtimes() {
+            rt.block_on(async {
+                // Smaller than the data, so it takes many ops.
+                let mut stream =
+                    FileStream::with_capacity(4, File::from_std_fd(tempfile().unwrap()));
+                stream.write_all(b"hello world").await.unwrap();
+                stream.flush().await.unwrap();
+                assert_eq!(stream.position(), 11);
+
+                assert_eq!(stream.seek(SeekFrom::End(-5)).await.unwrap(), 6);
+                let mut tail = String::new();
+                stream.read_to_string(&mut tail).await.unwrap();
+                assert_eq!(tail, "world");
+
+                stream.seek(SeekFrom::Start(0)).await.unwrap();
+                let mut copied = vec![];
+                tokio::io::copy(&mut stream, &mut copied).await.unwrap();
+                assert_eq!(copied, b"hello world");
+
+                assert!(stream.seek(SeekFrom::Current(-12)).await.is_err());
+            });
+        }
+    }
+
+    #[test]
+    fn test_futures_io() {
+        use futures::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+        for rt in runtimes() {
+            rt.block_on(async {
+                let mut stream =
+                    FileStream::with_capacity(4, File::from_std_fd(tempfile().unwrap()));
+                stream.write_all(b"hello world").await.unwrap();
+
+                // Reads ahead are dropped by writes, which go to the cursor.
+                stream.seek(SeekFrom::Start(0)).await.unwrap();
+                let mut head = [0; 2];
+                stream.read_exact(&mut head).await.unwrap();
+                assert_eq!(&head, b"he");
+                stream.write_all(b"LL").await.unwrap();
+                stream.close().await.unwrap();
+
+                stream.seek(SeekFrom::Start(0)).await.unwrap();
+                let mut data = String::new();
+                stream.read_to_string(&mut data).await.unwrap();
+                assert_eq!(data, "heLLo world");
+            });
+        }
+    }
+
+    #[test]
+    fn test_failed_read() {
+        use tokio::io::AsyncReadExt;
+
+        let rt = RuntimeBuilder::new()
+            .backend(Backend::Simulated)
+            .build()
+            .unwrap();
+        let sim = rt.simulator().unwrap();
+        rt.block_on(async {
+            let file = File::from_std_fd(std::fs::File::open("/dev/null").unwrap());
+            let mut stream = FileStream::with_capacity(4, file);
+            let mut buf = [0; 4];
+
+            let mut read = Box::pin(stream.read(&mut buf));
+            assert!(futures::poll!(read.as_mut()).is_pending());
+            sim.complete(sim.submitted()[0].user_data, 4);
+            assert_eq!(read.await.unwrap(), 4);
+
+            let mut read = Box::pin(stream.read(&mut buf));
+            assert!(futures::poll!(read.as_mut()).is_pending());
+            sim.fail(sim.submitted()[0].user_data, rustix::io::Errno::IO);
+            assert!(read.await.is_err());
+
+            // The first chunk is not read again, a new read is submitted.
+            let mut read = Box::pin(stream.read(&mut buf));
+            assert!(futures::poll!(read.as_mut()).is_pending());
+            let submitted = sim.submitted();
+            assert_eq!(submitted.len(), 1);
+            assert_eq!(submitted[0].opcode, rustix_uring::opcode::Read::CODE);
+            sim.complete(submitted[0].user_data, 0);
+            assert_eq!(read.await.unwrap(), 0);
+            drop(stream);
+
+            for s in sim.submitted() {
+                sim.complete(s.user_data, 0);
+            }
+        });
+    }
+
+    #[test]
+    fn test_abandoned_read() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        default_rt().unwrap().block_on(async {
+            let (peer, socket) = UnixStream::pair().unwrap();
+            let mut peer = std::fs::File::from(OwnedFd::from(peer));
+            let mut stream = FileStream::new(File::from_std_fd(std::fs::File::from(
+                OwnedFd::from(socket),
+            )));
+
+            // Nothing to read, the read is dropped while it owns the buffer.
+            let mut buf = [0; 5];
+            assert!(futures::poll!(pin!(stream.read(&mut buf))).is_pending());
+
+            stream.write_all(b"hello").await.unwrap();
+            stream.flush().await.unwrap();
+            let mut written = [0; 5];
+            peer.read_exact(&mut written).unwrap();
+            assert_eq!(&written, b"hello");
+        });
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     future::poll_fn,
+    os::fd::BorrowedFd,
     pin::Pin,
     task::{Poll, ready},
 };
@@ -7,11 +8,15 @@ use std::{
 use rustix_uring::{opcode, types};
 
 use crate::uring::{
-    op::{CompleteAble, Op},
+    blocking::{SendPtr, unsupported},
+    op::{CompleteAble, Op, Prepared},
     prelude::BufResult,
 };
 
-use super::{AsIoVec, shared_fd::SharedFd};
+use super::{
+    AsIoVec,
+    shared_fd::{SharedFd, with_fd},
+};
 
 pub struct Write<T> {
     fd: SharedFd,
@@ -28,15 +33,30 @@ impl<T> Op<Write<T>>
 where
     T: AsIoVec,
 {
-    pub fn write_at(fd: &SharedFd, buf: T, offset: u64) -> std::io::Result<Self> {
-        Op::submit_with(Write::new(fd.clone(), buf), |write| {
-            let fd = write.fd.raw_fd();
-            let (ptr, len) = write.buf.as_io_vec();
-
-            opcode::Write::new(types::Fd(fd), ptr, len as _)
-                .offset(offset)
-                .build()
+    pub(crate) fn write_at(fd: &SharedFd, buf: T, offset: u64) -> Prepared<Write<T>> {
+        let (ptr, len) = buf.as_io_vec();
+        let (raw_fd, ptr) = (fd.raw_fd(), SendPtr(ptr));
+
+        Op::prepare_with(Write::new(fd.clone(), buf), |write| {
+            with_fd!(write.fd, |fd| {
+                opcode::Write::new(fd, ptr.get(), len as _)
+                    .offset(offset)
+                    .build()
+            })
         })
+        .fallback(
+            move || {
+                let fd = unsafe { BorrowedFd::borrow_raw(raw_fd.ok_or_else(unsupported)?) };
+                // Safety: the buffer is kept alive by the op until the write is done.
+                let buf = unsafe { std::slice::from_raw_parts(ptr.get(), len) };
+                match rustix::io::pwrite(fd, buf, offset) {
+                    // Not seekable, e.g. a pipe.
+                    Err(rustix::io::Errno::SPIPE) => Ok(rustix::io::write(fd, buf)?),
+                    res => Ok(res?),
+                }
+            },
+            |_, n| n as u32,
+        )
     }
 }
 
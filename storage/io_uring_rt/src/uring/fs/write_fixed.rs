@@ -0,0 +1,47 @@
+use rustix_uring::opcode;
+
+use super::shared_fd::{SharedFd, with_fd};
+use crate::uring::{
+    buf::FixedBuf,
+    op::{CompleteAble, Completion, Op, Prepared},
+    prelude::BufResult,
+};
+
+pub struct WriteFixed {
+    fd: SharedFd,
+    buf: FixedBuf,
+}
+
+impl Op<WriteFixed> {
+    pub(crate) fn write_fixed_at(
+        fd: &SharedFd,
+        buf: FixedBuf,
+        offset: u64,
+    ) -> Prepared<WriteFixed> {
+        let write = WriteFixed {
+            fd: fd.clone(),
+            buf,
+        };
+
+        Op::prepare_with(write, |write| {
+            let ptr = write.buf.as_mut_ptr();
+            let len = write.buf.len() as u32;
+            let index = write.buf.buf_index();
+
+            with_fd!(write.fd, |fd| {
+                opcode::WriteFixed::new(fd, ptr, len, index)
+                    .offset(offset)
+                    .build()
+            })
+        })
+    }
+}
+
+impl CompleteAble for WriteFixed {
+    type Output = BufResult<FixedBuf>;
+
+    fn handle_completion(comp: Completion<Self>) -> Self::Output {
+        let res = comp.result.map(|n| n as usize);
+        (res, comp.data.buf)
+    }
+}
@@ -0,0 +1,330 @@
+//! Chains of linked operations, submitted together with `IOSQE_IO_LINK`.
+//!
+//! Kernel starts an operation of a chain after the previous one completes, so a
+//! durable write is one round trip:
+//!
+//! ```no_run
+//! # use uring_rt::uring::{fs::{self, File}, link::link, rt::default_rt};
+//! default_rt().unwrap().block_on(async {
+//!     let file = File::create("data.tmp").await.unwrap();
+//!     let ((res, _buf), synced, renamed) = link((
+//!         file.write_at(b"hello".to_vec(), 0),
+//!         file.sync_data(),
+//!         fs::rename("data.tmp", "data"),
+//!     ))
+//!     .await;
+//! });
+//! ```
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use rustix_uring::squeue;
+
+use super::op::{Chained, CompleteAble, Prepared, submit_chain};
+
+/// Link `ops`, a tuple of prepared operations, resolves to a tuple of their
+/// outputs in the same order.
+///
+/// If an operation fails, the following ones are cancelled and complete with
+/// `ECANCELED`. A short read or write also counts as a failure, while kernel
+/// does not break the chain on failures of some operations, like rename.
+///
+/// Operations are always submitted to the ring, they do not fall back to the
+/// blocking pool even if kernel does not support them.
+pub fn link<L: LinkOps>(ops: L) -> Link<L> {
+    Link {
+        ops,
+        outputs: Default::default(),
+        hard: false,
+        submitted: false,
+    }
+}
+
+/// Future returned by [`link`].
+///
+/// The operations are submitted when first polled. Dropping it cancels the ones
+/// not completed yet.
+pub struct Link<L: LinkOps> {
+    ops: L,
+    outputs: L::Outputs,
+    hard: bool,
+    submitted: bool,
+}
+
+impl<L: LinkOps> Link<L> {
+    /// Link with `IOSQE_IO_HARDLINK`, so the chain is not broken by a failed
+    /// operation, the following ones still run in order.
+    pub fn hard(mut self) -> Self {
+        self.hard = true;
+        self
+    }
+}
+
+// Nothing is pinned, outputs are moved out when all are ready.
+impl<L: LinkOps> Unpin for Link<L> {}
+
+impl<L: LinkOps> Future for Link<L> {
+    type Output = L::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if !this.submitted {
+            this.submitted = true;
+            let flags = if this.hard {
+                squeue::Flags::IO_HARDLINK
+            } else {
+                squeue::Flags::IO_LINK
+            };
+            this.ops.submit(flags);
+        }
+
+        this.ops.poll_outputs(&mut this.outputs, cx)
+    }
+}
+
+/// Tuples of [`Prepared`] operations which could be linked, up to 8.
+pub trait LinkOps {
+    type Output;
+    /// Outputs completed so far.
+    #[doc(hidden)]
+    type Outputs: Default;
+
+    #[doc(hidden)]
+    fn submit(&mut self, flags: squeue::Flags);
+
+    #[doc(hidden)]
+    fn poll_outputs(
+        &mut self,
+        outputs: &mut Self::Outputs,
+        cx: &mut Context<'_>,
+    ) -> Poll<Self::Output>;
+}
+
+macro_rules! impl_link_ops {
+    ($($T:ident $i:tt),+) => {
+        impl<$($T),+> LinkOps for ($(Prepared<$T>,)+)
+        where
+            $($T: CompleteAble + Unpin,)+
+        {
+            type Output = ($($T::Output,)+);
+            type Outputs = ($(Option<$T::Output>,)+);
+
+            fn submit(&mut self, flags: squeue::Flags) {
+                submit_chain(&mut [$(&mut self.$i as &mut dyn Chained),+], flags);
+            }
+
+            fn poll_outputs(
+                &mut self,
+                outputs: &mut Self::Outputs,
+                cx: &mut Context<'_>,
+            ) -> Poll<Self::Output> {
+                let mut ready = true;
+                $(
+                    if outputs.$i.is_none() {
+                        match Pin::new(&mut self.$i).poll(cx) {
+                            Poll::Ready(output) => outputs.$i = Some(output),
+                            Poll::Pending => ready = false,
+                        }
+                    }
+                )+
+                if !ready {
+                    return Poll::Pending;
+                }
+
+                Poll::Ready(($(outputs.$i.take().unwrap(),)+))
+            }
+        }
+    };
+}
+
+impl_link_ops!(A 0);
+impl_link_ops!(A 0, B 1);
+impl_link_ops!(A 0, B 1, C 2);
+impl_link_ops!(A 0, B 1, C 2, D 3);
+impl_link_ops!(A 0, B 1, C 2, D 3, E 4);
+impl_link_ops!(A 0, B 1, C 2, D 3, E 4, F 5);
+impl_link_ops!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_link_ops!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tempfile::tempdir;
+
+    use crate::{
+        Error,
+        uring::{
+            fs::{self, File},
+            rt::{RuntimeBuilder, default_rt},
+        },
+    };
+
+    use super::link;
+
+    fn canceled() -> Option<i32> {
+        Some(rustix::io::Errno::CANCELED.raw_os_error())
+    }
+
+    #[test]
+    fn test_write_sync_rename() {
+        let dir = tempdir().unwrap();
+        let tmp = dir.path().join("data.tmp");
+        let path = dir.path().join("data");
+
+        default_rt().unwrap().block_on(async {
+            let file = File::create(&tmp).await.unwrap();
+            let ((res, buf), synced, renamed) = link((
+                file.write_at(b"hello".to_vec(), 0),
+                file.sync_data(),
+                fs::rename(&tmp, &path),
+            ))
+            .await;
+
+            assert_eq!(res.unwrap(), 5);
+            assert_eq!(buf, b"hello");
+            synced.unwrap();
+            renamed.unwrap();
+            file.close().await.unwrap();
+        });
+
+        assert!(!tmp.exists());
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_failed_link_cancels_rest() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data");
+        std::fs::write(&path, b"").unwrap();
+
+        default_rt().unwrap().block_on(async {
+            // Opened read only, so the write fails.
+            let file = File::open(&path).await.unwrap();
+            let ((res, _), synced, renamed) = link((
+                file.write_at(b"hello".to_vec(), 0),
+                file.sync_all(),
+                fs::rename(&path, dir.path().join("other")),
+            ))
+            .await;
+
+            assert_eq!(
+                res.unwrap_err().raw_os_error(),
+                Some(rustix::io::Errno::BADF.raw_os_error())
+            );
+            assert_eq!(synced.unwrap_err().raw_os_error(), canceled());
+            assert_eq!(renamed.unwrap_err().raw_os_error(), canceled());
+            file.close().await.unwrap();
+        });
+
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_invalid_link_cancels_rest() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data");
+
+        default_rt().unwrap().block_on(async {
+            let file = File::create(&path).await.unwrap();
+            let ((res, _), renamed, synced) = link((
+                file.write_at(b"hello".to_vec(), 0),
+                fs::rename("\0", "other"),
+                file.sync_all(),
+            ))
+            .await;
+
+            assert_eq!(res.unwrap(), 5);
+            assert_eq!(
+                renamed.unwrap_err().kind(),
+                std::io::ErrorKind::InvalidInput
+            );
+            assert_eq!(synced.unwrap_err().raw_os_error(), canceled());
+            file.close().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_hard_link() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data");
+        std::fs::write(&path, b"").unwrap();
+
+        default_rt().unwrap().block_on(async {
+            let read_only = File::open(&path).await.unwrap();
+            let file = File::create(dir.path().join("other")).await.unwrap();
+            let ((failed, _), (res, _)) = link((
+                read_only.write_at(b"hello".to_vec(), 0),
+                file.write_at(b"hello".to_vec(), 0),
+            ))
+            .hard()
+            .await;
+
+            assert!(failed.is_err());
+            assert_eq!(res.unwrap(), 5);
+            read_only.close().await.unwrap();
+            file.close().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_chain_exceeds_ring() {
+        let rt = RuntimeBuilder::new().entries(4).build().unwrap();
+        rt.block_on(async {
+            let file = File::from_std_fd(tempfile::tempfile().unwrap());
+            let (a, b, c, d, e) = link((
+                file.sync_data(),
+                file.sync_data(),
+                file.sync_data(),
+                file.sync_data(),
+                file.sync_data(),
+            ))
+            .await;
+
+            for res in [a, b, c, d, e] {
+                assert!(matches!(res, Err(Error::RingFull)));
+            }
+            file.close().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_chain_exceeds_op_table() {
+        let rt = RuntimeBuilder::new().max_ops(1).build().unwrap();
+        rt.block_on(async {
+            let file = File::from_std_fd(tempfile::tempfile().unwrap());
+            let (a, b) = link((file.sync_data(), file.sync_data())).await;
+
+            for res in [a, b] {
+                assert!(matches!(res, Err(Error::OpTableFull)));
+            }
+            file.close().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_link_with_timeout() {
+        let (rx, tx) = std::io::pipe().unwrap();
+
+        default_rt().unwrap().block_on(async {
+            let rx = File::from_std_fd(std::fs::File::from(std::os::fd::OwnedFd::from(rx)));
+            let tx = File::from_std_fd(std::fs::File::from(std::os::fd::OwnedFd::from(tx)));
+
+            let ((read, _), (written, _)) = link((
+                rx.read_at(vec![0_u8; 16], 0)
+                    .timeout(Duration::from_millis(10)),
+                tx.write_at(b"hello".to_vec(), 0),
+            ))
+            .await;
+
+            assert_eq!(read.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+            assert_eq!(written.unwrap_err().raw_os_error(), canceled());
+            rx.close().await.unwrap();
+            tx.close().await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,135 @@
+//! Counters of the ring, see [`Runtime::metrics`](super::rt::Runtime::metrics).
+
+use std::{collections::HashMap, time::Duration};
+
+use rustix::io_uring::IoringOp;
+
+/// Number of buckets of [`LatencyHistogram`], latencies from ~4s on are in the
+/// last one.
+const BUCKETS: usize = 24;
+
+/// A snapshot of the counters of a runtime, taken by
+/// [`Runtime::metrics`](super::rt::Runtime::metrics).
+///
+/// Counters only grow, rates come from the difference of two snapshots. They are
+/// all zero for the thread pool backend.
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeMetrics {
+    /// Ops pushed to the submission queue, linked timeouts and cancellations
+    /// are not counted.
+    pub submitted: u64,
+    /// Completions of ops, a multishot op completes many times.
+    pub completed: u64,
+    /// Ops dropped before they completed.
+    pub ignored: u64,
+    /// Completions with `ECANCELED`, by a cancellation, a timeout or a broken link.
+    pub cancelled: u64,
+    /// Completions whose op was removed, e.g. duplicated ones, they are dropped.
+    pub stale: u64,
+    /// Ops submitted whose last completion is not consumed yet.
+    pub in_flight: usize,
+    /// Ops waiting for a permit of the in-flight limit, see
+    /// [`RuntimeBuilder::max_in_flight`](super::rt::RuntimeBuilder::max_in_flight).
+    pub permit_waiters: usize,
+
+    /// Number of `io_uring_enter` calls made to submit entries.
+    pub submit_calls: u64,
+    /// Submissions forced by a full submission queue.
+    pub sq_full_flushes: u64,
+    /// Submissions retried because kernel returned `EBUSY` or `EAGAIN`.
+    pub submit_busy_retries: u64,
+    /// Completions dropped by kernel since the completion queue overflowed.
+    pub cq_overflow: u32,
+
+    /// Latency from submission to the last completion, by opcode.
+    pub latencies: HashMap<IoringOp, LatencyHistogram>,
+}
+
+impl RuntimeMetrics {
+    pub fn latency(&self, opcode: IoringOp) -> Option<&LatencyHistogram> {
+        self.latencies.get(&opcode)
+    }
+}
+
+/// A histogram of latencies, in buckets of powers of two microseconds.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum: Duration,
+}
+
+impl LatencyHistogram {
+    pub(crate) fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        // Bucket `i` holds latencies below `2^i` microseconds.
+        let i = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[i.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum += latency;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0)
+            .then(|| Duration::from_nanos((self.sum.as_nanos() / self.count as u128) as u64))
+    }
+
+    /// Upper bounds of the buckets with their counts, the last bound is
+    /// [`Duration::MAX`].
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, &n)| (Self::upper_bound(i), n))
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile, e.g. 0.99 for p99.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let target = ((self.count as f64 * q.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.buckets.iter().enumerate().find_map(|(i, &n)| {
+            seen += n;
+            (seen >= target).then(|| Self::upper_bound(i))
+        })
+    }
+
+    fn upper_bound(i: usize) -> Duration {
+        match i {
+            i if i == BUCKETS - 1 => Duration::MAX,
+            i => Duration::from_micros(1 << i),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::LatencyHistogram;
+
+    #[test]
+    fn test_histogram() {
+        let mut hist = LatencyHistogram::default();
+        assert_eq!(hist.quantile(0.5), None);
+
+        for _ in 0..98 {
+            hist.record(Duration::from_micros(3));
+        }
+        hist.record(Duration::from_millis(1));
+        hist.record(Duration::from_secs(60));
+
+        assert_eq!(hist.count(), 100);
+        assert_eq!(hist.quantile(0.5), Some(Duration::from_micros(4)));
+        assert_eq!(hist.quantile(0.99), Some(Duration::from_micros(1024)));
+        assert_eq!(hist.quantile(1.0), Some(Duration::MAX));
+        assert_eq!(hist.buckets().map(|(_, n)| n).sum::<u64>(), 100);
+    }
+}
@@ -1,9 +1,21 @@
 #![allow(unused)]
 
+mod blocking;
 mod driver;
 mod op;
 
+#[cfg(feature = "executor")]
+pub mod executor;
+
+pub mod buf;
 pub mod fs;
+pub mod link;
+pub mod metrics;
+pub mod multi_rt;
+pub mod remote;
 pub mod rt;
+pub mod sim;
+pub mod task;
+pub mod time;
 
 pub mod prelude;
@@ -0,0 +1,302 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    thread::JoinHandle,
+};
+
+use tokio::sync::oneshot;
+
+use super::rt::RuntimeBuilder;
+use crate::utils::pin_thread_on;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/// A thread-per-core runtime.
+///
+/// Each worker is a thread pinned on one core, which owns its own [`Runtime`],
+/// so every worker drives its own io_uring instance.
+///
+/// Tasks are sent to a chosen worker and run there as local tasks, so the
+/// future itself does not need to be `Send`, only the closure creating it.
+pub struct MultiRuntime {
+    workers: Vec<Worker>,
+}
+
+struct Worker {
+    core_id: usize,
+    sender: async_channel::Sender<Task>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MultiRuntime {
+    /// Start one worker per core in `cores`, worker `i` is pinned on `cores[i]`.
+    ///
+    /// Every worker builds its runtime with `builder` on its own thread.
+    pub fn new(cores: &[usize], builder: &RuntimeBuilder) -> crate::Result<Self> {
+        let workers = cores
+            .iter()
+            .enumerate()
+            .map(|(id, &core_id)| Worker::start(id, core_id, builder.clone()))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Self { workers })
+    }
+
+    pub fn num_workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// The core which the worker is pinned on.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `worker` is out of range.
+    pub fn core_id(&self, worker: usize) -> usize {
+        self.workers[worker].core_id
+    }
+
+    /// Send a task to the selected worker.
+    ///
+    /// `f` is called on the worker thread, and the future it returns is spawned
+    /// as a local task of the worker's runtime.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `worker` is out of range.
+    pub fn spawn_on<F, Fut>(&self, worker: usize, f: F) -> WorkerJoinHandle<Fut::Output>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+
+        let task: Task = Box::new(move || {
+            crate::spawn_local(async move {
+                let _ = tx.send(f().await);
+            })
+            .detach();
+        });
+
+        // If the worker has exited, the task is dropped with its sender,
+        // and the handle will report it.
+        let _ = self.workers[worker].sender.try_send(task);
+
+        WorkerJoinHandle { rx }
+    }
+
+    /// Send a task to every worker, `f` receives the worker index.
+    pub fn spawn_all<F, Fut>(&self, f: F) -> Vec<WorkerJoinHandle<Fut::Output>>
+    where
+        F: Fn(usize) -> Fut + Clone + Send + 'static,
+        Fut: Future + 'static,
+        Fut::Output: Send + 'static,
+    {
+        (0..self.workers.len())
+            .map(|worker| {
+                let f = f.clone();
+                self.spawn_on(worker, move || f(worker))
+            })
+            .collect()
+    }
+
+    /// Block the current thread until all tasks finished, results are in the same
+    /// order as `handles`.
+    pub fn gather<T, I>(&self, handles: I) -> crate::Result<Vec<T>>
+    where
+        I: IntoIterator<Item = WorkerJoinHandle<T>>,
+    {
+        futures::executor::block_on(futures::future::try_join_all(handles))
+    }
+}
+
+impl Worker {
+    fn start(id: usize, core_id: usize, builder: RuntimeBuilder) -> io::Result<Self> {
+        let (sender, receiver) = async_channel::unbounded::<Task>();
+        let (ready_tx, ready_rx) = std::sync::mpsc::sync_channel(1);
+
+        let thread = std::thread::Builder::new()
+            .name(format!("uring-worker-{id}"))
+            .spawn(move || {
+                let rt = match pin_thread_on(core_id).and_then(|_| Ok(builder.build()?)) {
+                    Ok(rt) => {
+                        let _ = ready_tx.send(Ok(()));
+                        rt
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+
+                rt.block_on(async move {
+                    while let Ok(task) = receiver.recv().await {
+                        task();
+                    }
+                });
+                tracing::debug!("worker {} exited", id);
+            })?;
+
+        let worker = Self {
+            core_id,
+            sender,
+            thread: Some(thread),
+        };
+
+        ready_rx
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::other("worker exited during startup")))?;
+
+        Ok(worker)
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.sender.close();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Handle of a task running on a worker of [`MultiRuntime`].
+///
+/// Resolves to an error if the task was dropped before completion, for example
+/// it panicked or the worker has exited.
+pub struct WorkerJoinHandle<T> {
+    rx: oneshot::Receiver<T>,
+}
+
+impl<T> Future for WorkerJoinHandle<T> {
+    type Output = crate::Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map_err(|_| io::Error::other("worker dropped the task").into())
+    }
+}
+
+/// Start a worker on every core the current thread is allowed to run on.
+pub fn default_multi_rt() -> crate::Result<MultiRuntime> {
+    let cpuset = rustix::thread::sched_getaffinity(None)?;
+    let cores = (0..rustix::thread::CpuSet::MAX_CPU)
+        .filter(|&core| cpuset.is_set(core))
+        .collect::<Vec<_>>();
+
+    MultiRuntime::new(&cores, &RuntimeBuilder::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        os::{
+            fd::{AsRawFd, BorrowedFd},
+            unix::fs::MetadataExt,
+        },
+    };
+
+    use tempfile::NamedTempFile;
+
+    use crate::uring::fs::File;
+
+    use crate::uring::rt::RuntimeBuilder;
+
+    use super::{MultiRuntime, default_multi_rt};
+
+    fn allowed_cores(n: usize) -> Vec<usize> {
+        let cpuset = rustix::thread::sched_getaffinity(None).unwrap();
+        (0..rustix::thread::CpuSet::MAX_CPU)
+            .filter(|&core| cpuset.is_set(core))
+            .take(n)
+            .collect()
+    }
+
+    #[test]
+    fn test_spawn_on_pinned_core() {
+        let cores = allowed_cores(2);
+        let rt = MultiRuntime::new(&cores, RuntimeBuilder::new().entries(32)).unwrap();
+        assert_eq!(rt.num_workers(), cores.len());
+
+        let handles = rt.spawn_all(|_| async { rustix::thread::sched_getcpu() });
+        let running_on = rt.gather(handles).unwrap();
+        assert_eq!(running_on, cores);
+    }
+
+    #[test]
+    fn test_read_on_workers() {
+        let mut tempfile = NamedTempFile::new().unwrap();
+        tempfile.write_all(b"hello world").unwrap();
+        let path = tempfile.path().to_path_buf();
+
+        let rt = default_multi_rt().unwrap();
+        let handles = rt.spawn_all(move |worker| {
+            let path = path.clone();
+            async move {
+                let file = File::open(path).await.unwrap();
+                let (res, buf) = file.read_at(vec![0_u8; 11], 0).await;
+                assert_eq!(res.unwrap(), 11);
+                (worker, buf)
+            }
+        });
+
+        let results = rt.gather(handles).unwrap();
+        assert_eq!(results.len(), rt.num_workers());
+        for (i, (worker, buf)) in results.into_iter().enumerate() {
+            assert_eq!(worker, i);
+            assert_eq!(&buf[..], b"hello world");
+        }
+    }
+
+    #[test]
+    fn test_fd_closed_on_workers() {
+        let mut tempfile = NamedTempFile::new().unwrap();
+        tempfile.write_all(b"hello world").unwrap();
+        let path = tempfile.path().to_path_buf();
+        let inode = tempfile.as_file().metadata().unwrap().ino();
+
+        let rt = default_multi_rt().unwrap();
+        let handles = rt.spawn_all(move |_| {
+            let path = path.clone();
+            async move {
+                let file = std::fs::File::open(path).unwrap();
+                let fd = file.as_raw_fd();
+                File::from_std_fd(file).close().await.unwrap();
+                fd
+            }
+        });
+
+        for fd in rt.gather(handles).unwrap() {
+            // The fd may be reused by others, but not for the file.
+            let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+            if let Ok(stat) = rustix::fs::fstat(fd) {
+                assert_ne!(stat.st_ino, inode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_task_panicked() {
+        let rt = MultiRuntime::new(&allowed_cores(1), RuntimeBuilder::new().entries(32)).unwrap();
+        let handle = rt.spawn_on(0, || async { panic!("boom") });
+        let res: crate::Result<Vec<()>> = rt.gather([handle]);
+        assert!(res.is_err());
+
+        // The worker is still alive.
+        let handle = rt.spawn_on(0, || async { 42 });
+        assert_eq!(rt.gather([handle]).unwrap(), vec![42]);
+    }
+
+    #[cfg(feature = "executor")]
+    #[test]
+    fn test_executor_workers() {
+        let rt =
+            MultiRuntime::new(&allowed_cores(1), RuntimeBuilder::new().executor(true)).unwrap();
+        let handle = rt.spawn_on(0, || async { 42 });
+        assert_eq!(rt.gather([handle]).unwrap(), vec![42]);
+    }
+}
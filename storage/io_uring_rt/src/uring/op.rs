@@ -1,19 +1,34 @@
 use std::{
     any::Any,
     cell::RefCell,
+    collections::VecDeque,
     io,
+    marker::PhantomData,
     pin::Pin,
     rc::{Rc, Weak},
     task::{Context, Poll, Waker},
+    time::Duration,
 };
 
-use futures::future::poll_fn;
+use futures::{Stream, future::poll_fn};
 use rustix::net::eth::TDLS;
-use rustix_uring::{cqueue::Flags, squeue};
+use rustix_uring::{
+    cqueue::{self, Flags},
+    opcode, squeue,
+    types::Timespec,
+};
 
-use crate::uring::driver::CONTEXT;
+use tokio::sync::{AcquireError, OwnedSemaphorePermit};
 
-use super::driver::Driver;
+use crate::{
+    Error,
+    uring::driver::{CONTEXT, UserData, Waiter},
+};
+
+use super::{
+    blocking::{self, Blocking},
+    driver::{self, Driver},
+};
 
 /// Lifecycle of an operation.
 ///
@@ -31,8 +46,15 @@ use super::driver::Driver;
 /// ```text
 ///     Submitted | Waiting --> Completed,  // IO Finished
 ///     Completed           --> panic, an op has been completed twice.
+///     Streaming           --> queue the completion.
 ///     Ignored             --> do nothing, because it was already ignored.
 /// ```
+///
+/// When a op was been dropped before completion, it becomes `Ignored` and a cancel
+/// request is submitted, so that kernel does not hold the data forever.
+///
+/// A multishot op is `Streaming` from the start, it is finished by the first
+/// completion without `IORING_CQE_F_MORE`.
 #[derive(Debug)]
 pub enum Lifecycle {
     Submitted,
@@ -41,7 +63,24 @@ pub enum Lifecycle {
 
     Completed(io::Result<u32>, Flags),
 
-    Ignored(Box<dyn Any>),
+    Streaming {
+        completions: VecDeque<Cqe>,
+        waker: Option<Waker>,
+        /// The last completion has been queued.
+        finished: bool,
+    },
+
+    Ignored(Box<dyn Any>, Option<OnIgnored>),
+}
+
+/// Called with the flags of completions nobody waits for, e.g. to give back the
+/// buffer picked by kernel.
+pub struct OnIgnored(Box<dyn FnMut(Flags)>);
+
+impl std::fmt::Debug for OnIgnored {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("OnIgnored")
+    }
 }
 
 impl Lifecycle {
@@ -58,20 +97,90 @@ impl Lifecycle {
                 false
             }
             Lifecycle::Completed(..) => unreachable!(),
-            Lifecycle::Ignored(..) => true, // finish this op
+            Lifecycle::Streaming {
+                mut completions,
+                waker,
+                finished,
+            } => {
+                assert!(
+                    !finished,
+                    "multishot op completed after the last completion"
+                );
+                completions.push_back(Cqe {
+                    result: result.map_err(Error::from),
+                    flags,
+                });
+                *self = Lifecycle::Streaming {
+                    completions,
+                    waker: None,
+                    finished: !cqueue::more(flags),
+                };
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+                false
+            }
+            // finish this op, a multishot one is finished by the last completion
+            Lifecycle::Ignored(data, mut on_ignored) => {
+                if let Some(OnIgnored(f)) = &mut on_ignored {
+                    f(flags);
+                }
+                *self = Lifecycle::Ignored(data, on_ignored);
+                !cqueue::more(flags)
+            }
+        }
+    }
+}
+
+/// Marker of ops which complete once.
+pub struct SingleCQE;
+
+/// Marker of multishot ops, which complete many times.
+pub struct MultiCQE;
+
+/// Kinds of ops, by how many times they complete.
+pub trait CqeKind {
+    fn lifecycle() -> Lifecycle;
+}
+
+impl CqeKind for SingleCQE {
+    fn lifecycle() -> Lifecycle {
+        Lifecycle::Submitted
+    }
+}
+
+impl CqeKind for MultiCQE {
+    fn lifecycle() -> Lifecycle {
+        Lifecycle::Streaming {
+            completions: VecDeque::new(),
+            waker: None,
+            finished: false,
         }
     }
 }
 
-pub struct Op<T: 'static> {
+pub struct Op<T: 'static, K = SingleCQE> {
     handle: Weak<RefCell<Driver>>,
     index: usize,
     data: Option<T>,
+
+    /// Timespec of the linked timeout, kernel reads it when submitted.
+    timeout: Option<Box<Timespec>>,
+    on_ignored: Option<OnIgnored>,
+
+    _kind: PhantomData<K>,
 }
 
 pub struct Completion<T> {
     pub(crate) data: T,
-    pub(crate) result: io::Result<u32>,
+    pub(crate) result: crate::Result<u32>,
+    pub(crate) flags: Flags,
+}
+
+/// One completion of a multishot op, the data is kept in the op.
+#[derive(Debug)]
+pub struct Cqe {
+    pub(crate) result: crate::Result<u32>,
     pub(crate) flags: Flags,
 }
 
@@ -83,7 +192,13 @@ where
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = &mut *self;
-        let driver = this.handle.upgrade().expect("Driver dropped");
+        let Some(driver) = this.handle.upgrade() else {
+            return Poll::Ready(Completion {
+                data: this.data.take().expect("polled after completion"),
+                result: Err(Error::NoRuntime),
+                flags: Flags::empty(),
+            });
+        };
         let mut driver = driver.borrow_mut();
 
         let lc = driver
@@ -106,8 +221,14 @@ where
                 Poll::Pending
             }
             Lifecycle::Completed(result, flags) => {
-                driver.ops.lifecycle.remove(this.index);
+                let expired = driver.ops.is_expired(this.index);
+                driver.ops.remove(this.index);
                 this.index = usize::MAX;
+
+                let mut result = result.map_err(Error::from);
+                if expired {
+                    result = result.map_err(timed_out);
+                }
                 Poll::Ready(Completion {
                     data: this.data.take().unwrap(),
                     result,
@@ -115,79 +236,527 @@ where
                 })
             }
 
-            // should not poll ignoerd op
-            Lifecycle::Ignored(..) => unreachable!(),
+            // should not poll ignoerd op, or poll a multishot op as a future
+            Lifecycle::Ignored(..) | Lifecycle::Streaming { .. } => unreachable!(),
         }
     }
 }
 
-impl<T> Drop for Op<T> {
-    fn drop(&mut self) {
-        let driver = self.handle.upgrade().expect("Driver dropped");
+impl<T> Stream for Op<T, MultiCQE>
+where
+    T: Unpin + 'static,
+{
+    type Item = Cqe;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.index == usize::MAX {
+            return Poll::Ready(None);
+        }
+
+        let Some(driver) = this.handle.upgrade() else {
+            this.index = usize::MAX;
+            return Poll::Ready(Some(Cqe {
+                result: Err(Error::NoRuntime),
+                flags: Flags::empty(),
+            }));
+        };
         let mut driver = driver.borrow_mut();
 
+        let lc = driver
+            .ops
+            .lifecycle
+            .get_mut(this.index)
+            .expect("Invalid index");
+
+        match lc {
+            Lifecycle::Streaming {
+                completions,
+                waker,
+                finished,
+            } => {
+                if let Some(cqe) = completions.pop_front() {
+                    return Poll::Ready(Some(cqe));
+                }
+                if *finished {
+                    driver.ops.remove(this.index);
+                    this.index = usize::MAX;
+                    return Poll::Ready(None);
+                }
+                if !waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+                    *waker = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<T, K> Drop for Op<T, K> {
+    fn drop(&mut self) {
+        let Some(handle) = self.handle.upgrade() else {
+            // It may be leaked by the shut down runtime, kernel may still use it.
+            if self.index != usize::MAX {
+                std::mem::forget(self.data.take());
+            }
+            return;
+        };
+        let mut on_ignored = self.on_ignored.take();
+        // Borrowed after `on_ignored` is taken, so a hook not kept by the driver
+        // is dropped once the borrow ends.
+        let mut driver = handle.borrow_mut();
+
         let lc = match driver.ops.lifecycle.get_mut(self.index) {
             Some(lc) => lc,
             None => return, // finished!
         };
 
+        let mut ignore = |flags| {
+            if let Some(OnIgnored(f)) = &mut on_ignored {
+                f(flags);
+            }
+        };
+
         match lc {
             Lifecycle::Submitted | Lifecycle::Waiting(_) => {
-                *lc = Lifecycle::Ignored(Box::new(self.data.take()));
+                let data = Box::new((self.data.take(), self.timeout.take()));
+                *lc = Lifecycle::Ignored(data, on_ignored);
+                driver.metrics.ignored += 1;
+                let _ = driver.cancel(self.index);
+            }
+            Lifecycle::Streaming {
+                completions,
+                finished: false,
+                ..
+            } => {
+                completions.drain(..).for_each(|cqe| ignore(cqe.flags));
+                *lc = Lifecycle::Ignored(Box::new(self.data.take()), on_ignored);
+                driver.metrics.ignored += 1;
+                let _ = driver.cancel(self.index);
             }
-            Lifecycle::Completed(..) => {
-                driver.ops.lifecycle.remove(self.index);
+            Lifecycle::Completed(_, flags) => {
+                ignore(*flags);
+                driver.ops.remove(self.index);
+            }
+            Lifecycle::Streaming { completions, .. } => {
+                completions.drain(..).for_each(|cqe| ignore(cqe.flags));
+                driver.ops.remove(self.index);
             }
             Lifecycle::Ignored(..) => unreachable!(),
         }
+        drop(driver);
+        driver::drop_released(&handle);
     }
 }
 
-impl<T> Op<T> {
-    pub fn new(data: T, driver: &mut Driver, handle: Weak<RefCell<Driver>>) -> Self {
-        Op {
-            handle,
-            index: driver.ops.lifecycle.insert(Lifecycle::Submitted),
-            data: Some(data),
-        }
+impl<T> Op<T, MultiCQE> {
+    /// Create a multishot operation and submit it, its completions are yielded
+    /// as a stream.
+    pub fn submit_multi_with<F>(mut data: T, f: F) -> crate::Result<Self>
+    where
+        F: FnOnce(&mut T) -> squeue::Entry,
+    {
+        let sqe = f(&mut data);
+        Op::push(data, sqe, None, None, None, false).map_err(|(e, _)| e.into())
     }
+}
 
+impl<T> Op<T> {
     /// Create a new operation and submit it to uring driver.
     ///
     /// Data's ownership is given to driver.
-    pub fn submit_with<F>(data: T, f: F) -> io::Result<Self>
+    pub fn submit_with<F>(data: T, f: F) -> crate::Result<Self>
+    where
+        F: FnOnce(&mut T) -> squeue::Entry,
+    {
+        Op::prepare_with(data, f).submit()
+    }
+
+    /// Create a new operation, which is submitted when it is first polled.
+    pub fn prepare_with<F>(mut data: T, f: F) -> Prepared<T>
     where
         F: FnOnce(&mut T) -> squeue::Entry,
     {
+        let sqe = f(&mut data);
+        Prepared {
+            state: PreparedState::Init {
+                data,
+                sqe,
+                on_push: None,
+                timeout: None,
+                on_ignored: None,
+                fallback: None,
+                background: false,
+            },
+            permit: Permit::default(),
+        }
+    }
+}
+
+impl<T, K: CqeKind> Op<T, K> {
+    /// If the table of ops is full, data is given back.
+    pub(crate) fn new(
+        data: T,
+        driver: &mut Driver,
+        handle: Weak<RefCell<Driver>>,
+    ) -> Result<Self, (io::Error, T)> {
+        match driver.ops.insert(K::lifecycle()) {
+            Ok(index) => Ok(Op {
+                handle,
+                index,
+                data: Some(data),
+                timeout: None,
+                on_ignored: None,
+                _kind: PhantomData,
+            }),
+            Err(e) => Err((e, data)),
+        }
+    }
+
+    /// Push the entry, or the one of `on_push` if any, and a linked timeout if
+    /// any. The op holds `permit` until it is removed.
+    ///
+    /// If failed, data is given back.
+    fn push(
+        mut data: T,
+        sqe: squeue::Entry,
+        on_push: Option<OnPush<T>>,
+        timeout: Option<Duration>,
+        permit: Option<OwnedSemaphorePermit>,
+        background: bool,
+    ) -> Result<Self, (io::Error, T)> {
         CONTEXT.with(|cx| {
             let handle = match cx.handle() {
                 Some(h) => h,
-                None => {
-                    return Err(io::Error::other(
-                        "Driver not initialized",
-                    ));
-                }
+                None => return Err((cx.no_driver(), data)),
             };
 
             let mut driver = handle.borrow_mut();
 
-            if driver.uring.submission().is_full() {
-                driver.submit()?;
+            let sqe = match on_push.map(|f| f(&mut data, &mut driver)) {
+                Some(Ok(sqe)) => sqe,
+                Some(Err(e)) => return Err((e, data)),
+                None => sqe,
+            };
+            let mut op = Op::new(data, &mut driver, Rc::downgrade(&handle))?;
+            if let Some(permit) = permit {
+                driver.ops.hold(op.index, permit);
             }
-
-            let mut op = Op::new(data, &mut driver, Rc::downgrade(&handle));
-            let sqe = f(op.data.as_mut().unwrap()).user_data(op.index as u64);
-            {
-                let mut sq = driver.uring.submission();
-                if unsafe { sq.push(&sqe).is_err() } {
-                    unreachable!("?");
-                }
+            if background {
+                driver.ops.set_background(op.index);
+            }
+            let res = match op.entries(&mut driver, sqe, timeout) {
+                (sqe, None) => driver.push(&sqe),
+                (sqe, Some(link)) => driver.push_all(&[sqe, link]),
+            };
+            if res.is_err() {
+                // Never pushed, so it would never be completed.
+                driver.ops.remove(op.index);
+                op.index = usize::MAX;
             }
+            drop(driver);
+            driver::drop_released(&handle);
 
-            driver.submit()?;
-            Ok(op)
+            match res {
+                Ok(()) => Ok(op),
+                Err(e) => Err((e, op.data.take().unwrap())),
+            }
         })
     }
+
+    /// The entry tagged with the index, and a linked timeout if any.
+    fn entries(
+        &mut self,
+        driver: &mut Driver,
+        sqe: squeue::Entry,
+        timeout: Option<Duration>,
+    ) -> (squeue::Entry, Option<squeue::Entry>) {
+        let sqe = sqe.user_data(driver.ops.user_data(self.index));
+        match timeout {
+            None => (sqe, None),
+            Some(timeout) => {
+                let timespec = Box::new(Timespec::from(timeout));
+                let link = opcode::LinkTimeout::new(&*timespec)
+                    .build()
+                    .user_data(driver.ops.link_timeout(self.index));
+                self.timeout = Some(timespec);
+                (sqe.flags(squeue::Flags::IO_LINK), Some(link))
+            }
+        }
+    }
+
+    /// Set the hook called with the flags of completions after the op is
+    /// dropped, or of completions which are not taken when it is dropped.
+    pub(crate) fn on_ignored(&mut self, f: impl FnMut(Flags) + 'static) {
+        self.on_ignored = Some(OnIgnored(Box::new(f)));
+    }
+
+    /// Ask kernel to cancel this operation.
+    ///
+    /// The operation is still completed, with `ECANCELED` if it was cancelled in time.
+    pub fn cancel(&self) -> crate::Result<()> {
+        let handle = self.handle.upgrade().ok_or(Error::NoRuntime)?;
+        let mut driver = handle.borrow_mut();
+
+        let res = match driver.ops.lifecycle.get(self.index) {
+            Some(
+                Lifecycle::Submitted
+                | Lifecycle::Waiting(_)
+                | Lifecycle::Streaming {
+                    finished: false, ..
+                },
+            ) => driver.cancel(self.index),
+            _ => Ok(()), // already completed
+        };
+        drop(driver);
+        driver::drop_released(&handle);
+        Ok(res?)
+    }
+}
+
+/// The op is cancelled by its expired timeout.
+fn timed_out(err: Error) -> Error {
+    match err {
+        Error::Cancelled => Error::TimedOut,
+        e => e,
+    }
+}
+
+/// An operation which is prepared but not submitted.
+///
+/// It is pushed to the submission queue when first polled, and resolves to the
+/// output of [`CompleteAble`].
+pub struct Prepared<T: 'static> {
+    state: PreparedState<T>,
+    permit: Permit,
+}
+
+/// Runs the op on the blocking pool, holding the permit of the limit if any.
+type Fallback<T> = Box<dyn FnOnce(T, Option<OwnedSemaphorePermit>) -> Blocking<T>>;
+
+/// Builds the entry when the op is pushed, see [`Prepared::on_push`].
+type OnPush<T> = Box<dyn FnOnce(&mut T, &mut Driver) -> io::Result<squeue::Entry>>;
+
+enum PreparedState<T: 'static> {
+    Init {
+        data: T,
+        sqe: squeue::Entry,
+        on_push: Option<OnPush<T>>,
+        timeout: Option<Duration>,
+        on_ignored: Option<OnIgnored>,
+        /// Run it on the blocking pool instead, if kernel does not support it.
+        fallback: Option<Fallback<T>>,
+        /// See [`Prepared::background`].
+        background: bool,
+    },
+    Submitted(Op<T>),
+    /// Running on the blocking pool.
+    Blocking(Blocking<T>),
+    /// Failed to submit, resolves to the error.
+    Failed(T, io::Error),
+    Finished,
+}
+
+impl<T> Prepared<T> {
+    /// An operation which resolves to `err` without being submitted, e.g. its
+    /// arguments are invalid.
+    pub(crate) fn failed(data: T, err: io::Error) -> Self {
+        Self {
+            state: PreparedState::Failed(data, err),
+            permit: Permit::default(),
+        }
+    }
+
+    /// Bound the operation with a deadline, by a linked timeout.
+    ///
+    /// If it is not completed in time, it is cancelled by kernel and resolves to
+    /// [`Error::TimedOut`].
+    ///
+    /// It has no effect if the operation has been submitted, and it resolves to
+    /// [`Error::Unsupported`] if the operation would run on the blocking pool.
+    pub fn timeout(mut self, duration: Duration) -> Self {
+        if let PreparedState::Init { timeout, .. } = &mut self.state {
+            *timeout = Some(duration);
+        }
+        self
+    }
+
+    /// Build the entry with `f` when the operation is pushed instead, e.g. to
+    /// take a resource of the driver only while it is in flight. The entry it was
+    /// prepared with tells its opcode until then.
+    ///
+    /// If `f` fails, the operation resolves to the error. It is not called if the
+    /// operation runs on the blocking pool.
+    pub(crate) fn on_push(
+        mut self,
+        f: impl FnOnce(&mut T, &mut Driver) -> io::Result<squeue::Entry> + 'static,
+    ) -> Self {
+        if let PreparedState::Init { on_push, .. } = &mut self.state {
+            *on_push = Some(Box::new(f));
+        }
+        self
+    }
+
+    /// See [`Op::on_ignored`].
+    pub(crate) fn on_ignored(mut self, f: impl FnMut(Flags) + 'static) -> Self {
+        if let PreparedState::Init { on_ignored, .. } = &mut self.state {
+            *on_ignored = Some(OnIgnored(Box::new(f)));
+        }
+        self
+    }
+
+    /// If kernel does not support the opcode, run `f` on the blocking pool when it
+    /// is polled, instead of submitting it. `complete` gets the result of `f` with
+    /// the data on the current thread, and returns the result of the completion.
+    ///
+    /// Linked or explicitly submitted operations are always submitted.
+    pub(crate) fn fallback<R, F, G>(mut self, f: F, complete: G) -> Self
+    where
+        R: Send + 'static,
+        F: FnOnce() -> io::Result<R> + Send + 'static,
+        G: FnOnce(&mut T, R) -> u32 + 'static,
+    {
+        if let PreparedState::Init { fallback, .. } = &mut self.state {
+            *fallback = Some(Box::new(move |data, permit| {
+                blocking::spawn(data, f, complete, permit)
+            }));
+        }
+        self
+    }
+
+    /// Do not wait for the in-flight limit, for internal operations which would
+    /// hold a permit forever, e.g. reads of an eventfd, or which release others,
+    /// e.g. cancellations and timers.
+    pub(crate) fn unlimited(mut self) -> Self {
+        self.permit.unlimited = true;
+        self
+    }
+
+    /// The runtime does not wait in the ring for the operation alone, for reads
+    /// of an eventfd which only wake it, so it could still wait for timers.
+    pub(crate) fn background(mut self) -> Self {
+        if let PreparedState::Init { background, .. } = &mut self.state {
+            *background = true;
+        }
+        self
+    }
+
+    /// Submit the operation now, it does not wait for the in-flight limit.
+    pub fn submit(self) -> crate::Result<Op<T>> {
+        self.try_submit().map_err(|(e, _)| e.into())
+    }
+
+    fn try_submit(self) -> Result<Op<T>, (io::Error, T)> {
+        match self.state {
+            PreparedState::Init {
+                data,
+                sqe,
+                on_push,
+                timeout,
+                on_ignored,
+                background,
+                ..
+            } => Op::push(data, sqe, on_push, timeout, None, background).map(|mut op| {
+                op.on_ignored = on_ignored;
+                op
+            }),
+            PreparedState::Submitted(op) => Ok(op),
+            PreparedState::Failed(data, e) => Err((e, data)),
+            PreparedState::Blocking(..) => panic!("operation is running on the blocking pool"),
+            PreparedState::Finished => panic!("operation has been finished"),
+        }
+    }
+}
+
+impl<T> Future for Prepared<T>
+where
+    T: CompleteAble + Unpin,
+{
+    type Output = T::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        if let PreparedState::Init { .. } = &this.state {
+            std::task::ready!(this.permit.poll_acquire(cx));
+        }
+
+        loop {
+            match std::mem::replace(&mut this.state, PreparedState::Finished) {
+                // A blocking call could not be cancelled at the deadline.
+                PreparedState::Init {
+                    data,
+                    fallback: Some(_),
+                    sqe,
+                    timeout: Some(_),
+                    ..
+                } if !is_supported(&sqe) => {
+                    this.permit.permit = None;
+                    let err = Error::Unsupported("a timeout on the blocking pool");
+                    this.state = PreparedState::Failed(data, err.into());
+                }
+                PreparedState::Init {
+                    data,
+                    fallback: Some(fallback),
+                    sqe,
+                    ..
+                } if !is_supported(&sqe) => {
+                    this.state = PreparedState::Blocking(fallback(data, this.permit.permit.take()));
+                }
+                PreparedState::Init {
+                    data,
+                    sqe,
+                    on_push,
+                    timeout,
+                    on_ignored,
+                    background,
+                    ..
+                } => match Op::push(
+                    data,
+                    sqe,
+                    on_push,
+                    timeout,
+                    this.permit.permit.take(),
+                    background,
+                ) {
+                    Ok(mut op) => {
+                        op.on_ignored = on_ignored;
+                        this.state = PreparedState::Submitted(op);
+                    }
+                    Err((e, data)) => this.state = PreparedState::Failed(data, e),
+                },
+                PreparedState::Blocking(mut blocking) => {
+                    let Poll::Ready((data, result)) = blocking.poll(cx) else {
+                        this.state = PreparedState::Blocking(blocking);
+                        return Poll::Pending;
+                    };
+                    return Poll::Ready(T::handle_completion(Completion {
+                        data,
+                        result: result.map_err(Error::from),
+                        flags: Flags::empty(),
+                    }));
+                }
+                PreparedState::Failed(data, e) => {
+                    return Poll::Ready(T::handle_completion(Completion {
+                        data,
+                        result: Err(e.into()),
+                        flags: Flags::empty(),
+                    }));
+                }
+                PreparedState::Submitted(mut op) => {
+                    return match Pin::new(&mut op).poll(cx) {
+                        Poll::Ready(comp) => Poll::Ready(T::handle_completion(comp)),
+                        Poll::Pending => {
+                            this.state = PreparedState::Submitted(op);
+                            Poll::Pending
+                        }
+                    };
+                }
+                PreparedState::Finished => panic!("polled after completion"),
+            }
+        }
+    }
 }
 
 impl<T> Op<T>
@@ -204,6 +773,222 @@ where
     }
 }
 
+/// A permit of the in-flight limit, acquired before the op is pushed, see
+/// [`RuntimeBuilder::max_in_flight`](super::rt::RuntimeBuilder::max_in_flight).
+#[derive(Default)]
+struct Permit {
+    acquire: Option<(Acquire, Waiter)>,
+    permit: Option<OwnedSemaphorePermit>,
+    /// Not limited, see [`Prepared::unlimited`].
+    unlimited: bool,
+}
+
+type Acquire = Pin<Box<dyn Future<Output = Result<OwnedSemaphorePermit, AcquireError>>>>;
+
+impl Permit {
+    /// Ready once acquired, or if the current runtime has no limit.
+    fn poll_acquire(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.permit.is_some() || self.unlimited {
+            return Poll::Ready(());
+        }
+
+        let (acquire, _) = match &mut self.acquire {
+            Some(acquire) => acquire,
+            None => {
+                let Some(limit) = CONTEXT.with(|cx| cx.limit()) else {
+                    return Poll::Ready(());
+                };
+                if let Ok(permit) = limit.permits.clone().try_acquire_owned() {
+                    self.permit = Some(permit);
+                    return Poll::Ready(());
+                }
+                let acquire = Box::pin(limit.permits.clone().acquire_owned());
+                self.acquire.insert((acquire, limit.waiter()))
+            }
+        };
+
+        let res = std::task::ready!(acquire.as_mut().poll(cx));
+        self.acquire = None;
+        // The semaphore is never closed.
+        self.permit = res.ok();
+        Poll::Ready(())
+    }
+}
+
+/// Whether the driver of the current runtime supports the opcode of `sqe`, it
+/// is submitted and fails without a runtime. Nothing is supported by the thread
+/// pool backend.
+fn is_supported(sqe: &squeue::Entry) -> bool {
+    CONTEXT.with(|cx| match cx.handle() {
+        Some(driver) => driver.borrow().is_supported(driver::opcode(sqe)),
+        None => !cx.is_thread_pool(),
+    })
+}
+
+/// A prepared operation in a chain of linked operations, see [`submit_chain`].
+pub(crate) trait Chained {
+    /// Build the entry of [`Prepared::on_push`] if any, it resolves to the error
+    /// if failed.
+    fn prepare(&mut self, driver: &mut Driver);
+
+    /// Create the op on `driver`, and append its entries to `sqes` with `flags`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it has been submitted.
+    fn link_into(
+        &mut self,
+        driver: &mut Driver,
+        handle: &Weak<RefCell<Driver>>,
+        flags: squeue::Flags,
+        sqes: &mut Vec<squeue::Entry>,
+    );
+
+    /// The entries were not pushed, forget the op created by [`Chained::link_into`].
+    fn unlink(&mut self, driver: &mut Driver);
+
+    /// Resolve to `err` instead of being submitted, the driver must not be borrowed.
+    fn fail(&mut self, err: io::Error);
+
+    fn is_failed(&self) -> bool;
+}
+
+impl<T> Chained for Prepared<T> {
+    fn prepare(&mut self, driver: &mut Driver) {
+        let PreparedState::Init {
+            data, sqe, on_push, ..
+        } = &mut self.state
+        else {
+            return;
+        };
+        let Some(f) = on_push.take() else {
+            return;
+        };
+        match f(data, driver) {
+            Ok(entry) => *sqe = entry,
+            Err(e) => {
+                self.state = match std::mem::replace(&mut self.state, PreparedState::Finished) {
+                    PreparedState::Init { data, .. } => PreparedState::Failed(data, e),
+                    _ => unreachable!(),
+                };
+            }
+        }
+    }
+
+    fn link_into(
+        &mut self,
+        driver: &mut Driver,
+        handle: &Weak<RefCell<Driver>>,
+        flags: squeue::Flags,
+        sqes: &mut Vec<squeue::Entry>,
+    ) {
+        match std::mem::replace(&mut self.state, PreparedState::Finished) {
+            PreparedState::Init {
+                data,
+                sqe,
+                timeout,
+                on_ignored,
+                ..
+            } => {
+                let Ok(mut op) = Op::new(data, driver, handle.clone()) else {
+                    unreachable!("room of the chain is checked by submit_chain");
+                };
+                op.on_ignored = on_ignored;
+                let (sqe, link) = op.entries(driver, sqe, timeout);
+                sqes.push(sqe.flags(flags));
+                if let Some(link) = link {
+                    sqes.push(link.flags(flags));
+                }
+                self.state = PreparedState::Submitted(op);
+            }
+            _ => panic!("operation has been submitted"),
+        }
+    }
+
+    fn unlink(&mut self, driver: &mut Driver) {
+        if let PreparedState::Submitted(op) = &mut self.state {
+            driver.ops.remove(op.index);
+            op.index = usize::MAX;
+        }
+    }
+
+    fn fail(&mut self, err: io::Error) {
+        self.state = match std::mem::replace(&mut self.state, PreparedState::Finished) {
+            PreparedState::Init { data, .. } => PreparedState::Failed(data, err),
+            PreparedState::Submitted(mut op) => PreparedState::Failed(op.data.take().unwrap(), err),
+            state => state,
+        };
+    }
+
+    fn is_failed(&self) -> bool {
+        matches!(self.state, PreparedState::Failed(..))
+    }
+}
+
+/// Push `ops` together, every one but the last is linked to the next with `flags`.
+///
+/// If failed, every op resolves to the error. An op which has failed already is
+/// left out, and ops after it resolve to `ECANCELED` like kernel does, unless
+/// they are hard links.
+pub(crate) fn submit_chain(ops: &mut [&mut dyn Chained], flags: squeue::Flags) {
+    // Entries built when pushed may fail, like ops which have failed already.
+    let _ = driver::with_current(|driver| {
+        ops.iter_mut().for_each(|op| op.prepare(driver));
+        Ok(())
+    });
+    if !flags.contains(squeue::Flags::IO_HARDLINK)
+        && let Some(failed) = ops.iter().position(|op| op.is_failed())
+    {
+        for op in &mut ops[failed + 1..] {
+            op.fail(rustix::io::Errno::CANCELED.into());
+        }
+        return submit_chain(&mut ops[..failed], flags);
+    }
+
+    let mut ops = ops
+        .iter_mut()
+        .filter(|op| !op.is_failed())
+        .collect::<Vec<_>>();
+    if ops.is_empty() {
+        return;
+    }
+
+    let res = CONTEXT.with(|cx| {
+        let handle = cx.handle().ok_or_else(|| cx.no_driver())?;
+        let mut driver = handle.borrow_mut();
+        if !driver.ops.has_room(ops.len()) {
+            return Err(Error::OpTableFull.into());
+        }
+
+        let mut sqes = Vec::with_capacity(ops.len());
+        let last = ops.len() - 1;
+        for (i, op) in ops.iter_mut().enumerate() {
+            let flags = if i == last {
+                squeue::Flags::empty()
+            } else {
+                flags
+            };
+            op.link_into(&mut driver, &Rc::downgrade(&handle), flags, &mut sqes);
+        }
+
+        let res = driver.push_all(&sqes);
+        if res.is_err() {
+            // Never pushed, so they would never be completed.
+            ops.iter_mut().for_each(|op| op.unlink(&mut driver));
+        }
+        drop(driver);
+        driver::drop_released(&handle);
+        res
+    });
+
+    if let Err(e) = res {
+        let e = Error::from(e);
+        for op in ops {
+            op.fail(e.duplicate().into());
+        }
+    }
+}
+
 pub trait CompleteAble: Sized {
     type Output;
 
@@ -212,13 +997,203 @@ pub trait CompleteAble: Sized {
 
 #[cfg(test)]
 mod tests {
-    use rustix_uring::opcode;
+    use std::{os::fd::IntoRawFd, time::Duration};
+
+    use futures::StreamExt;
+    use rustix_uring::{cqueue, opcode};
 
-    use super::Op;
+    use crate::{
+        Error,
+        uring::{
+            fs::shared_fd::SharedFd,
+            rt::{Backend, RuntimeBuilder, default_rt},
+            task::spawn_local,
+            time::Timer,
+        },
+    };
+
+    use super::{CompleteAble, Completion, MultiCQE, Op};
 
     #[test]
     fn test_not_context_submit() {
         let op = Op::submit_with((), |_| opcode::Nop::new().build());
-        assert!(op.is_err());
+        assert!(matches!(op, Err(Error::NoRuntime)));
+    }
+
+    #[test]
+    fn test_op_span() {
+        use std::{
+            fmt::{Debug, Write},
+            sync::{Arc, Mutex},
+        };
+
+        use tracing::{
+            Subscriber,
+            field::{Field, Visit},
+            span,
+        };
+        use tracing_subscriber::{
+            Registry,
+            layer::{Context, Layer, SubscriberExt},
+        };
+
+        /// Fields of spans, by their ids.
+        #[derive(Clone, Default)]
+        struct Spans(Arc<Mutex<Vec<(u64, String)>>>);
+
+        struct Fields<'a>(&'a mut String);
+
+        impl Visit for Fields<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                let _ = write!(self.0, "{}={:?} ", field.name(), value);
+            }
+        }
+
+        impl<S: Subscriber> Layer<S> for Spans {
+            fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, _: Context<'_, S>) {
+                let mut fields = format!("{} ", attrs.metadata().name());
+                attrs.record(&mut Fields(&mut fields));
+                self.0.lock().unwrap().push((id.into_u64(), fields));
+            }
+
+            fn on_record(&self, id: &span::Id, values: &span::Record<'_>, _: Context<'_, S>) {
+                let mut spans = self.0.lock().unwrap();
+                if let Some((_, fields)) = spans.iter_mut().find(|(i, _)| *i == id.into_u64()) {
+                    values.record(&mut Fields(fields));
+                }
+            }
+        }
+
+        let spans = Spans::default();
+        let subscriber = Registry::default().with(spans.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            default_rt().unwrap().block_on(async {
+                let mut file = tempfile::tempfile().unwrap();
+                std::io::Write::write_all(&mut file, b"hello").unwrap();
+                let fd = SharedFd::new(file.into_raw_fd());
+
+                let (res, _) = Op::read_at(&fd, vec![0_u8; 16], 1).await;
+                assert_eq!(res.unwrap(), 4);
+                let (res, _) = Op::read_at(&SharedFd::new(-1), vec![0_u8; 16], 0).await;
+                assert!(res.is_err());
+            });
+        });
+
+        let spans = spans.0.lock().unwrap();
+        let reads = spans
+            .iter()
+            .map(|(_, fields)| fields)
+            .filter(|fields| fields.starts_with("op opcode=Read"))
+            .collect::<Vec<_>>();
+        assert_eq!(reads.len(), 2, "{spans:?}");
+        assert!(reads[0].contains("offset=1 len=16"), "{}", reads[0]);
+        assert!(reads[0].contains("result=4 elapsed="), "{}", reads[0]);
+        assert!(reads[1].contains("fd=-1"), "{}", reads[1]);
+        let ebadf = rustix::io::Errno::BADF.raw_os_error();
+        assert!(reads[1].contains(&format!("errno={ebadf}")), "{}", reads[1]);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let (rx, tx) = std::io::pipe().unwrap();
+
+        default_rt().unwrap().block_on(async move {
+            let fd = SharedFd::new(rx.into_raw_fd());
+            let op = Op::read_at(&fd, vec![0_u8; 16], 0).submit().unwrap();
+            op.cancel().unwrap();
+
+            let (res, buf) = op.complete().await;
+            let err = res.unwrap_err();
+            assert!(matches!(err, Error::Cancelled));
+            assert_eq!(
+                err.raw_os_error(),
+                Some(rustix::io::Errno::CANCELED.raw_os_error())
+            );
+            assert_eq!(buf.len(), 16);
+            drop(tx);
+        });
+    }
+
+    #[test]
+    fn test_multishot_stream() {
+        default_rt().unwrap().block_on(async {
+            let mut op = Op::<Timer, MultiCQE>::multishot_timer(Duration::from_millis(5)).unwrap();
+            for _ in 0..3 {
+                let cqe = op.next().await.unwrap();
+                assert!(cqueue::more(cqe.flags));
+            }
+
+            op.cancel().unwrap();
+            let mut last = None;
+            while let Some(cqe) = op.next().await {
+                last = Some(cqe);
+            }
+            let last = last.unwrap();
+            assert!(!cqueue::more(last.flags));
+            assert_eq!(
+                last.result.unwrap_err().raw_os_error(),
+                Some(rustix::io::Errno::CANCELED.raw_os_error())
+            );
+            assert!(op.next().await.is_none());
+        });
+    }
+
+    /// A nop, resolving to its result.
+    struct Nop;
+
+    impl CompleteAble for Nop {
+        type Output = crate::Result<u32>;
+
+        fn handle_completion(comp: Completion<Self>) -> Self::Output {
+            comp.result
+        }
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        let rt = RuntimeBuilder::new()
+            .backend(Backend::Simulated)
+            .max_in_flight(2)
+            .build()
+            .unwrap();
+        let sim = rt.simulator().unwrap();
+
+        rt.block_on(async {
+            let handles = (0..4)
+                .map(|_| spawn_local(Op::prepare_with(Nop, |_| opcode::Nop::new().build())))
+                .collect::<Vec<_>>();
+            tokio::task::yield_now().await;
+            assert_eq!(sim.submitted().len(), 2);
+            assert_eq!(rt.metrics().permit_waiters, 2);
+
+            // Explicit submissions are not limited.
+            let op = Op::submit_with((), |_| opcode::Nop::new().build()).unwrap();
+            assert_eq!(rt.metrics().in_flight, 3);
+            sim.complete(sim.submitted()[2].user_data, 0);
+            op.await.result.unwrap();
+
+            // Each completion lets a waiter in.
+            for i in 0..4 {
+                let submitted = sim.submitted();
+                assert_eq!(submitted.len(), 2.min(4 - i));
+                sim.complete(submitted[0].user_data, i as i32);
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(rt.metrics().permit_waiters, 0);
+            for (i, handle) in handles.into_iter().enumerate() {
+                assert_eq!(handle.await.unwrap().unwrap(), i as u32);
+            }
+        });
+    }
+
+    #[test]
+    fn test_drop_multishot() {
+        let rt = default_rt().unwrap();
+        rt.block_on(async {
+            let mut op = Op::<Timer, MultiCQE>::multishot_timer(Duration::from_millis(5)).unwrap();
+            op.next().await.unwrap();
+        });
+        // The driver waits for the last completion of the dropped op.
+        drop(rt);
     }
 }
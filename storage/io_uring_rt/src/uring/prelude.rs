@@ -1,2 +1,2 @@
-pub type BufResult<T> = (std::io::Result<usize>, T);
+pub type BufResult<T> = (crate::Result<usize>, T);
 pub use crate::uring::rt::default_rt;
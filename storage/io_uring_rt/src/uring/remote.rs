@@ -0,0 +1,285 @@
+//! Send work to a runtime from other threads, see [`RemoteHandle`].
+//!
+//! Tasks are queued in an inbox, and an eventfd is written to wake the runtime,
+//! which keeps a read of it in flight on the ring. So the runtime is woken even
+//! if it is parked in `io_uring_enter`.
+//!
+//! A runtime which reaps completions when it parks also uses the inbox to be
+//! woken from other threads, see [`Inbox::register`].
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    os::fd::{IntoRawFd, OwnedFd},
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll, Waker},
+};
+
+use rustix::event::{EventfdFlags, eventfd};
+use tokio::sync::oneshot;
+
+use super::{fs::shared_fd::SharedFd, op::Op, task::spawn_local};
+
+type Task = Box<dyn FnOnce() + Send>;
+
+pub(crate) struct Inbox {
+    /// `None` once the runtime is dropped.
+    queue: Mutex<Option<VecDeque<Task>>>,
+    eventfd: OwnedFd,
+    /// The eventfd has been written and not read yet.
+    notified: AtomicBool,
+    /// Woken with the eventfd, the read is only reaped when the runtime parks
+    /// outside the ring.
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Inbox {
+    pub(crate) fn new() -> io::Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            queue: Mutex::new(Some(VecDeque::new())),
+            eventfd: eventfd(0, EventfdFlags::CLOEXEC)?,
+            notified: AtomicBool::new(false),
+            waker: Mutex::new(None),
+        }))
+    }
+
+    fn push(&self, task: Task) {
+        match self.queue.lock().unwrap().as_mut() {
+            Some(queue) => queue.push_back(task),
+            // Dropped, so the handle resolves to an error.
+            None => return,
+        }
+        self.notify();
+    }
+
+    /// Wake the runtime, it may be parked in the ring.
+    pub(crate) fn notify(&self) {
+        if !self.notified.swap(true, Ordering::SeqCst) {
+            let _ = rustix::io::write(&self.eventfd, &1_u64.to_ne_bytes());
+            if let Some(waker) = self.waker.lock().unwrap().as_ref() {
+                waker.wake_by_ref();
+            }
+        }
+    }
+
+    /// Wake `waker` too when notified, for a runtime which reaps completions
+    /// when it parks, since it does not wait in the ring for the read alone.
+    pub(crate) fn register(&self, waker: &Waker) {
+        let mut current = self.waker.lock().unwrap();
+        if !current.as_ref().is_some_and(|w| w.will_wake(waker)) {
+            *current = Some(waker.clone());
+        }
+    }
+
+    /// Drop queued tasks and refuse new ones, called when the runtime is dropped.
+    pub(crate) fn close(&self) {
+        let queue = self.queue.lock().unwrap().take();
+        drop(queue);
+        // Release the read, it may be blocking a thread of the pool.
+        let _ = rustix::io::write(&self.eventfd, &1_u64.to_ne_bytes());
+    }
+
+    /// Spawn queued tasks and wait for more, until the runtime is dropped.
+    pub(crate) async fn serve(self: Arc<Self>) {
+        let fd = match self.eventfd.try_clone() {
+            Ok(fd) => SharedFd::new(fd.into_raw_fd()),
+            Err(e) => {
+                tracing::error!("failed to serve remote handles: {}", e);
+                return;
+            }
+        };
+
+        loop {
+            let tasks = match self.queue.lock().unwrap().as_mut() {
+                Some(queue) => std::mem::take(queue),
+                None => return,
+            };
+            tasks.into_iter().for_each(|task| task());
+
+            let (res, _) = Op::read_at(&fd, vec![0_u8; 8], 0)
+                .unlimited()
+                .background()
+                .await;
+            self.notified.store(false, Ordering::SeqCst);
+            if let Err(e) = res {
+                tracing::error!("failed to read eventfd: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// A handle to a [`Runtime`](super::rt::Runtime) which is `Send` and `Sync`, so
+/// other threads, e.g. of a multi-threaded tokio runtime, could run IO on it.
+///
+/// Tasks are run while the runtime is driven by `block_on` on its thread, e.g.
+/// `rt.block_on(std::future::pending::<()>())` for a dedicated IO thread.
+#[derive(Clone)]
+pub struct RemoteHandle {
+    inbox: Arc<Inbox>,
+}
+
+impl RemoteHandle {
+    pub(crate) fn new(inbox: Arc<Inbox>) -> Self {
+        Self { inbox }
+    }
+
+    /// Send `f` to the runtime.
+    ///
+    /// `f` is called on the runtime thread, and the future it returns is spawned
+    /// there as a local task, so only the closure and the output are `Send`.
+    pub fn spawn<F, Fut>(&self, f: F) -> RemoteJoinHandle<Fut::Output>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.inbox.push(Box::new(move || {
+            spawn_local(async move {
+                let _ = tx.send(f().await);
+            })
+            .detach();
+        }));
+        RemoteJoinHandle { rx }
+    }
+}
+
+/// Handle of a task sent by [`RemoteHandle::spawn`], it is `Send`.
+///
+/// Resolves to an error if the task was dropped before completion, for example
+/// it panicked or the runtime was dropped.
+pub struct RemoteJoinHandle<T> {
+    rx: oneshot::Receiver<T>,
+}
+
+impl<T> Future for RemoteJoinHandle<T> {
+    type Output = crate::Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map_err(|_| io::Error::other("remote task was dropped").into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, sync::mpsc, thread, time::Duration};
+
+    use static_assertions::assert_impl_all;
+    use tempfile::tempfile;
+
+    use crate::uring::{
+        fs::File,
+        rt::{Backend, RuntimeBuilder},
+    };
+
+    use super::{RemoteHandle, RemoteJoinHandle};
+
+    assert_impl_all!(RemoteHandle: Send, Sync);
+    assert_impl_all!(RemoteJoinHandle<usize>: Send);
+
+    /// A runtime on its own thread, serving remote handles until it is stopped.
+    fn io_thread(builder: RuntimeBuilder) -> (RemoteHandle, Stop) {
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let rt = builder.build().unwrap();
+            let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+            tx.send((rt.remote_handle().unwrap(), stop_tx)).unwrap();
+            rt.block_on(async {
+                let _ = stop_rx.await;
+            });
+        });
+
+        let (remote, stop) = rx.recv().unwrap();
+        (remote, Stop { stop, thread })
+    }
+
+    struct Stop {
+        stop: tokio::sync::oneshot::Sender<()>,
+        thread: thread::JoinHandle<()>,
+    }
+
+    impl Stop {
+        /// Stopped by a task sent to it, so the runtime is woken by the eventfd.
+        fn stop(self, remote: &RemoteHandle) {
+            let stop = self.stop;
+            drop(remote.spawn(move || async move {
+                let _ = stop.send(());
+            }));
+            self.thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_remote_read() {
+        let mut builders = vec![RuntimeBuilder::new()];
+        // Parks in `io_uring_enter`.
+        builders.push(RuntimeBuilder::new().enable_io(false).clone());
+        builders.push(RuntimeBuilder::new().backend(Backend::ThreadPool).clone());
+        #[cfg(feature = "executor")]
+        builders.push(RuntimeBuilder::new().executor(true).clone());
+
+        for builder in builders {
+            let (remote, stop) = io_thread(builder);
+
+            let tokio_rt = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(2)
+                .build()
+                .unwrap();
+            tokio_rt.block_on(async {
+                let mut file = tempfile().unwrap();
+                file.write_all(b"hello world").unwrap();
+
+                let handles = (0..4_u64)
+                    .map(|i| {
+                        let file = file.try_clone().unwrap();
+                        let remote = remote.clone();
+                        tokio::spawn(async move {
+                            remote
+                                .spawn(move || async move {
+                                    let file = File::from_std_fd(file);
+                                    let (res, buf) = file.read_at(vec![0; 5], i).await;
+                                    res.map(|_| buf)
+                                })
+                                .await
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for (i, handle) in handles.into_iter().enumerate() {
+                    let buf = handle.await.unwrap().unwrap().unwrap();
+                    assert_eq!(&buf[..], &b"hello world"[i..i + 5]);
+                }
+            });
+
+            stop.stop(&remote);
+        }
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        let rt = RuntimeBuilder::new().max_in_flight(1).build().unwrap();
+        let _remote = rt.remote_handle().unwrap();
+
+        rt.block_on(async {
+            // The read of the eventfd is in flight.
+            tokio::task::yield_now().await;
+
+            let mut file = tempfile().unwrap();
+            file.write_all(b"hello").unwrap();
+            let file = File::from_std_fd(file);
+            let read = file.read_at(vec![0; 5], 0);
+            let (res, buf) = tokio::time::timeout(Duration::from_secs(1), read)
+                .await
+                .expect("the eventfd read holds the only permit");
+            assert_eq!(res.unwrap(), 5);
+            assert_eq!(&buf[..], b"hello");
+        });
+    }
+}
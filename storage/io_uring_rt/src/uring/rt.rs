@@ -1,9 +1,22 @@
 use std::time::Duration;
-use std::{cell::RefCell, future::poll_fn, mem::ManuallyDrop, rc::Rc};
+use std::{
+    cell::{OnceCell, RefCell},
+    future::poll_fn,
+    mem::ManuallyDrop,
+    rc::Rc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Wake, Waker},
+    thread::ThreadId,
+};
 
 use tokio::io::unix::AsyncFd;
+use tokio::sync::Semaphore;
 use tokio::task::LocalSet;
 
+use rustix::io_uring::IoringOp;
 use rustix_uring::Builder as IoUringBuilder;
 use tokio::runtime::Builder as TokioRtBuiler;
 use tokio::runtime::Runtime as TokioRuntime;
@@ -12,12 +25,62 @@ use tracing::field::debug;
 
 use super::driver::CONTEXT;
 use super::driver::Handle;
-use crate::uring::driver::Driver;
+#[cfg(feature = "executor")]
+use super::executor::Executor;
+use super::metrics::RuntimeMetrics;
+use super::remote::{Inbox, RemoteHandle};
+use super::sim::Simulator;
+use super::task::{JoinHandle, TaskSet};
+use crate::uring::driver::{Driver, Limit, Ops, drop_released};
+
+/// How long dropping a [`Runtime`] waits for in-flight ops, see [`Runtime::shutdown`].
+pub(crate) const DROP_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct Runtime {
-    rt: TokioRuntime,
-    local: LocalSet,
-    driver: Rc<RefCell<Driver>>,
+    /// `None` once it is shut down.
+    scheduler: Option<Scheduler>,
+    /// `None` for the thread pool backend.
+    driver: Option<Rc<RefCell<Driver>>>,
+
+    tasks: Rc<TaskSet>,
+    /// `block_on` returns after spawned tasks finish.
+    wait_for_tasks: bool,
+    /// Set by the first [`Runtime::remote_handle`], or by `block_on` if it reaps
+    /// completions when it parks.
+    remote: OnceCell<Arc<Inbox>>,
+    /// The limit of ops in flight of the thread pool backend, the driver has its
+    /// own.
+    pool_limit: Option<Limit>,
+}
+
+/// What runs the ops of a [`Runtime`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    IoUring,
+
+    /// Ops run on a blocking thread pool, for kernels without io_uring, or where
+    /// it is disabled, e.g. by seccomp.
+    ///
+    /// `File`, `OpenOptions` and `fs` functions work the same, while ring
+    /// specific features, like registered files and buffers, fail with
+    /// [`std::io::ErrorKind::Unsupported`].
+    ThreadPool,
+
+    /// Entries are recorded instead of being submitted, and completed by the
+    /// [`Simulator`] of the runtime, to test ops deterministically.
+    Simulated,
+}
+
+/// What runs the tasks of a [`Runtime`].
+enum Scheduler {
+    Tokio {
+        rt: TokioRuntime,
+        local: LocalSet,
+    },
+
+    /// The built-in executor, which parks on the ring.
+    #[cfg(feature = "executor")]
+    Executor(Executor),
 }
 
 impl Runtime {
@@ -26,34 +89,79 @@ impl Runtime {
         uring_buidler: &IoUringBuilder,
         entries: u32,
     ) -> std::io::Result<Self> {
-        let rt = TokioRtBuiler::new_current_thread()
-            .on_thread_park(|| {
-                CONTEXT.with(|c| {
-                    let _ = c
-                        .handle()
-                        .expect("not found io uring context, is it init?")
-                        .borrow_mut()
-                        .uring
-                        .submit();
-                });
-            })
-            .enable_all()
-            .build()?;
+        let driver = Driver::new(uring_buidler, entries)?;
+        Self::with_driver(Some(driver), true, true)
+    }
+
+    pub fn builder() -> RuntimeBuilder {
+        RuntimeBuilder::new()
+    }
+
+    fn with_driver(
+        driver: Option<Driver>,
+        enable_time: bool,
+        enable_io: bool,
+    ) -> std::io::Result<Self> {
+        let mut rt = TokioRtBuiler::new_current_thread();
+        rt.on_thread_park(|| {
+            CONTEXT.with(|c| {
+                if let Some(driver) = c.handle() {
+                    driver.borrow_mut().park_until_woken();
+                    drop_released(&driver);
+                }
+            });
+        });
+        if enable_time {
+            rt.enable_time();
+        }
+        if enable_io {
+            rt.enable_io();
+        }
+        let rt = rt.build()?;
 
         let local = LocalSet::new();
-        let driver = Rc::new(RefCell::new(Driver::new(uring_buidler, entries)?));
+        let driver = driver.map(|driver| Rc::new(RefCell::new(driver)));
 
-        wake_uring_task(
-            &rt,
-            &local,
-            Handle {
-                inner: driver.clone(),
-            },
-        );
+        if let Some(driver) = &driver
+            && !driver.borrow().reap_on_park
+        {
+            wake_uring_task(
+                &rt,
+                &local,
+                Handle {
+                    inner: driver.clone(),
+                },
+            );
+        }
+
+        Ok(Self {
+            scheduler: Some(Scheduler::Tokio { rt, local }),
+            driver,
+            tasks: Rc::default(),
+            wait_for_tasks: false,
+            remote: OnceCell::new(),
+            pool_limit: None,
+        })
+    }
+
+    #[cfg(feature = "executor")]
+    fn with_executor(mut driver: Driver) -> std::io::Result<Self> {
+        driver.reap_on_park = true;
 
-        Ok(Self { rt, local, driver })
+        Ok(Self {
+            scheduler: Some(Scheduler::Executor(Executor::new()?)),
+            driver: Some(Rc::new(RefCell::new(driver))),
+            tasks: Rc::default(),
+            wait_for_tasks: false,
+            remote: OnceCell::new(),
+            pool_limit: None,
+        })
     }
 
+    /// Run `fut` to completion, with tasks spawned meanwhile.
+    ///
+    /// If [`RuntimeBuilder::wait_for_tasks`] is set, it also waits for all
+    /// spawned tasks to finish before it returns.
     pub fn block_on<Fut: Future>(&self, fut: Fut) -> Fut::Output {
         tracing::debug!("into block task");
 
@@ -65,15 +173,236 @@ impl Runtime {
             }
         }
 
-        CONTEXT.with(|c| c.set(self.driver.clone()));
+        CONTEXT.with(|c| c.set(self.driver.clone(), self.pool_limit.clone()));
         let _g = ContextGuard;
+        let _tasks = self.tasks.enter();
+
+        let fut = async {
+            let output = fut.await;
+            if self.wait_for_tasks {
+                self.tasks.idle().await;
+            }
+            output
+        };
+
+        match self.scheduler.as_ref().expect("runtime is shut down") {
+            Scheduler::Tokio { rt, local } => {
+                tokio::pin!(fut);
+
+                let fut = poll_fn(|cx| fut.as_mut().poll(cx));
+                let fut = local.run_until(fut);
+
+                match &self.driver {
+                    // Tokio parks in the ring then, so it does not see wakes.
+                    Some(driver) if driver.borrow().reap_on_park => {
+                        let woken = driver.borrow().woken.clone();
+                        // Wakes from other threads, e.g. of the blocking pool, while
+                        // it waits in the ring.
+                        let inbox = self
+                            .inbox()
+                            .inspect_err(|e| tracing::warn!("failed to create the inbox: {}", e))
+                            .ok();
+                        tokio::pin!(fut);
+                        rt.block_on(poll_fn(|cx| {
+                            // Woken by tokio, wakes from now on are new.
+                            woken.store(false, Ordering::SeqCst);
+                            if let Some(inbox) = &inbox {
+                                inbox.register(cx.waker());
+                            }
+                            let waker = Waker::from(Arc::new(ParkWaker {
+                                inner: cx.waker().clone(),
+                                woken: woken.clone(),
+                                inbox: inbox.clone(),
+                                owner: std::thread::current().id(),
+                            }));
+                            fut.as_mut().poll(&mut Context::from_waker(&waker))
+                        }))
+                    }
+                    _ => rt.block_on(fut),
+                }
+            }
+            #[cfg(feature = "executor")]
+            Scheduler::Executor(executor) => {
+                let driver = self.driver.as_ref().expect("executor runs on the ring");
+                executor.block_on(driver, fut)
+            }
+        }
+    }
+
+    /// Spawn a task on the runtime, it runs while the runtime is driven by
+    /// [`Runtime::block_on`], see [`spawn_local`](super::task::spawn_local).
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_untracked(self.tasks.track(future))
+    }
+
+    /// Spawn a task which `block_on` does not wait for.
+    fn spawn_untracked<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        match self.scheduler.as_ref().expect("runtime is shut down") {
+            Scheduler::Tokio { local, .. } => JoinHandle::tokio(local.spawn_local(future)),
+            #[cfg(feature = "executor")]
+            Scheduler::Executor(executor) => executor.spawn(future),
+        }
+    }
+
+    /// A handle to send tasks to the runtime from other threads.
+    pub fn remote_handle(&self) -> crate::Result<RemoteHandle> {
+        Ok(RemoteHandle::new(self.inbox()?))
+    }
+
+    /// The inbox of remote tasks and wakes, served once created.
+    fn inbox(&self) -> std::io::Result<Arc<Inbox>> {
+        if let Some(inbox) = self.remote.get() {
+            return Ok(inbox.clone());
+        }
+
+        let inbox = Inbox::new()?;
+        self.spawn_untracked(inbox.clone().serve()).detach();
+        let _ = self.remote.set(inbox.clone());
+        Ok(inbox)
+    }
+
+    /// Number of `io_uring_enter` calls made to submit entries.
+    pub fn submit_calls(&self) -> u64 {
+        self.driver
+            .as_ref()
+            .map_or(0, |driver| driver.borrow().submit_calls)
+    }
+
+    /// A snapshot of the counters of the ring, it only copies them.
+    pub fn metrics(&self) -> RuntimeMetrics {
+        self.driver
+            .as_ref()
+            .map(|driver| driver.borrow_mut().snapshot())
+            .unwrap_or_default()
+    }
+
+    /// Whether `opcode` is supported by kernel, probed when the runtime is built.
+    ///
+    /// Ops like mkdir, rename, unlink and statx run on a blocking thread pool if
+    /// they are not supported, e.g. `rustix_uring::opcode::MkDirAt::CODE`.
+    ///
+    /// Nothing is supported by the thread pool backend.
+    pub fn is_supported(&self, opcode: IoringOp) -> bool {
+        self.driver
+            .as_ref()
+            .is_some_and(|driver| driver.borrow().is_supported(opcode))
+    }
+
+    /// The backend chosen when the runtime is built, see [`RuntimeBuilder::backend`].
+    pub fn backend(&self) -> Backend {
+        match &self.driver {
+            Some(driver) if driver.borrow().is_simulated() => Backend::Simulated,
+            Some(_) => Backend::IoUring,
+            None => Backend::ThreadPool,
+        }
+    }
+
+    /// The simulator completing entries, if the backend is [`Backend::Simulated`].
+    pub fn simulator(&self) -> Option<Simulator> {
+        self.driver
+            .as_ref()
+            .filter(|driver| driver.borrow().is_simulated())
+            .map(Simulator::new)
+    }
+
+    /// Drop all tasks, then cancel the ops still in flight and wait for them to
+    /// complete until `timeout`.
+    ///
+    /// No more ops could be submitted meanwhile. Ops which do not complete in
+    /// time are leaked with their buffers, since kernel may still use them.
+    /// Dropping the runtime does the same with a timeout of 1s.
+    pub fn shutdown(mut self, timeout: Duration) -> ShutdownReport {
+        self.shutdown_inner(timeout)
+    }
+
+    fn shutdown_inner(&mut self, timeout: Duration) -> ShutdownReport {
+        if let Some(inbox) = self.remote.get() {
+            inbox.close();
+        }
+
+        match self.scheduler.take() {
+            #[cfg(feature = "executor")]
+            Some(Scheduler::Executor(executor)) => {
+                // Tasks may hold files and ops, which need the driver when dropped.
+                CONTEXT.with(|c| c.set(self.driver.clone(), None));
+                executor.shutdown();
+                CONTEXT.with(|c| c.unset());
+            }
+            // Files are closed synchronously without the context.
+            scheduler => drop(scheduler),
+        }
+
+        match &self.driver {
+            Some(driver) => {
+                let report = driver.borrow_mut().shutdown(timeout);
+                drop_released(driver);
+                report
+            }
+            None => ShutdownReport::default(),
+        }
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        if self.scheduler.is_none() {
+            return;
+        }
+
+        let report = self.shutdown_inner(DROP_TIMEOUT);
+        if !report.leaked.is_empty() {
+            tracing::warn!("{} ops are leaked by the runtime", report.leaked.len());
+        }
+    }
+}
+
+/// What happened to ops in flight by [`Runtime::shutdown`].
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownReport {
+    /// Ops cancelled, or completed anyway, before the timeout.
+    pub cancelled: Vec<OpReport>,
+    /// Ops not completed before the timeout, their data is leaked.
+    pub leaked: Vec<OpReport>,
+}
 
-        tokio::pin!(fut);
+/// An op in flight when the runtime is shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpReport {
+    pub opcode: IoringOp,
+    /// Time since it was submitted, when the runtime is shut down.
+    pub elapsed: Duration,
+}
 
-        let fut = poll_fn(|cx| fut.as_mut().poll(cx));
-        let fut = self.local.run_until(fut);
+/// Wakes a runtime which parks in the ring, see [`Driver::park`].
+struct ParkWaker {
+    inner: Waker,
+    woken: Arc<AtomicBool>,
+    /// Wakes from other threads write its eventfd, which has a read in flight.
+    inbox: Option<Arc<Inbox>>,
+    owner: ThreadId,
+}
 
-        self.rt.block_on(fut)
+impl Wake for ParkWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+        if std::thread::current().id() != self.owner
+            && let Some(inbox) = &self.inbox
+        {
+            inbox.notify();
+        }
+        self.inner.wake_by_ref();
     }
 }
 
@@ -84,25 +413,418 @@ fn wake_uring_task(rt: &TokioRuntime, local: &LocalSet, driver: Handle) {
     local.spawn_local(async move {
         loop {
             let mut guard = handle.readable().await.unwrap();
-            guard.get_inner().inner.borrow_mut().tick();
+            let driver = &guard.get_inner().inner;
+            driver.borrow_mut().tick();
+            drop_released(driver);
             guard.clear_ready();
         }
     });
 }
 
-pub fn default_rt() -> std::io::Result<Runtime> {
-    Runtime::new(&rustix_uring::IoUring::builder(), 256)
+/// When entries pushed to the submission queue are submitted to kernel.
+///
+/// Whatever the policy is, entries are submitted when the submission queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmitPolicy {
+    /// Submit every op right after it is pushed, it costs one syscall per op.
+    Immediate,
+
+    /// Submit all pushed ops when the runtime parks.
+    OnPark,
+
+    /// Submit once the number of pushed ops reaches the threshold, and when the
+    /// runtime parks.
+    Threshold(usize),
+}
+
+/// Builder of [`Runtime`], which exposes io_uring setup flags and tokio drivers.
+///
+/// Combinations of flags are checked in [`RuntimeBuilder::build`].
+///
+/// ```rust
+/// use uring_rt::uring::rt::RuntimeBuilder;
+///
+/// let rt = RuntimeBuilder::new()
+///     .entries(1024)
+///     .coop_taskrun(true)
+///     .single_issuer(true)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct RuntimeBuilder {
+    entries: u32,
+    cq_entries: Option<u32>,
+
+    sqpoll_idle: Option<Duration>,
+    sqpoll_cpu: Option<u32>,
+    iopoll: bool,
+    coop_taskrun: bool,
+    single_issuer: bool,
+    defer_taskrun: bool,
+
+    enable_time: bool,
+    enable_io: bool,
+
+    submit_policy: SubmitPolicy,
+    registered_files: u32,
+    disabled_opcodes: Vec<IoringOp>,
+    max_in_flight: Option<usize>,
+    max_ops: Option<usize>,
+    backend: Option<Backend>,
+    wait_for_tasks: bool,
+
+    #[cfg(feature = "executor")]
+    executor: bool,
+}
+
+impl Default for RuntimeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RuntimeBuilder {
+    /// Max entries of the submission queue accepted by kernel.
+    pub const MAX_ENTRIES: u32 = 32768;
+
+    /// Max entries of the completion queue accepted by kernel.
+    pub const MAX_CQ_ENTRIES: u32 = 2 * Self::MAX_ENTRIES;
+
+    pub fn new() -> Self {
+        Self {
+            entries: 256,
+            cq_entries: None,
+
+            sqpoll_idle: None,
+            sqpoll_cpu: None,
+            iopoll: false,
+            coop_taskrun: false,
+            single_issuer: false,
+            defer_taskrun: false,
+
+            enable_time: true,
+            enable_io: true,
+
+            submit_policy: SubmitPolicy::Immediate,
+            registered_files: 0,
+            disabled_opcodes: vec![],
+            max_in_flight: None,
+            max_ops: None,
+            backend: None,
+            wait_for_tasks: false,
+
+            #[cfg(feature = "executor")]
+            executor: false,
+        }
+    }
+
+    /// Entries of the submission queue.
+    pub fn entries(&mut self, entries: u32) -> &mut Self {
+        self.entries = entries;
+        self
+    }
+
+    /// Entries of the completion queue, default is twice of the submission queue.
+    pub fn cq_entries(&mut self, entries: u32) -> &mut Self {
+        self.cq_entries = Some(entries);
+        self
+    }
+
+    /// Use a kernel thread to poll the submission queue, it goes to sleep after `idle`.
+    pub fn sqpoll(&mut self, idle: Duration) -> &mut Self {
+        self.sqpoll_idle = Some(idle);
+        self
+    }
+
+    /// Bind the kernel polling thread on a cpu, requires [`RuntimeBuilder::sqpoll`].
+    pub fn sqpoll_cpu(&mut self, cpu: u32) -> &mut Self {
+        self.sqpoll_cpu = Some(cpu);
+        self
+    }
+
+    /// Busy-wait for completions, only usable for files opened with `O_DIRECT`.
+    ///
+    /// Completions are reaped when the runtime parks, so the thread blocks in kernel
+    /// while any op is in flight.
+    pub fn iopoll(&mut self, iopoll: bool) -> &mut Self {
+        self.iopoll = iopoll;
+        self
+    }
+
+    pub fn coop_taskrun(&mut self, coop_taskrun: bool) -> &mut Self {
+        self.coop_taskrun = coop_taskrun;
+        self
+    }
+
+    pub fn single_issuer(&mut self, single_issuer: bool) -> &mut Self {
+        self.single_issuer = single_issuer;
+        self
+    }
+
+    /// Defer kernel work until completions are reaped, requires
+    /// [`RuntimeBuilder::single_issuer`].
+    ///
+    /// Completions are reaped when the runtime parks, like [`RuntimeBuilder::iopoll`].
+    pub fn defer_taskrun(&mut self, defer_taskrun: bool) -> &mut Self {
+        self.defer_taskrun = defer_taskrun;
+        self
+    }
+
+    /// Enable the tokio time driver, so `tokio::time` could be used.
+    pub fn enable_time(&mut self, enable: bool) -> &mut Self {
+        self.enable_time = enable;
+        self
+    }
+
+    /// Enable the tokio IO driver.
+    ///
+    /// It is used to be notified of completions through the ring fd. Without it,
+    /// completions are reaped when the runtime parks.
+    pub fn enable_io(&mut self, enable: bool) -> &mut Self {
+        self.enable_io = enable;
+        self
+    }
+
+    /// When pushed entries are submitted to kernel, default is [`SubmitPolicy::Immediate`].
+    pub fn submit_policy(&mut self, policy: SubmitPolicy) -> &mut Self {
+        self.submit_policy = policy;
+        self
+    }
+
+    /// Register a sparse file table with `n` slots, default is 0 which registers
+    /// none.
+    ///
+    /// Files are put into it by [`File::register`](super::fs::File::register) or
+    /// opened as direct descriptors by [`OpenOptions::fixed_file`](super::fs::OpenOptions::fixed_file).
+    pub fn registered_files(&mut self, n: u32) -> &mut Self {
+        self.registered_files = n;
+        self
+    }
+
+    /// Treat `opcode` as unsupported by kernel, e.g. to test the blocking fallback.
+    ///
+    /// See [`Runtime::is_supported`].
+    pub fn disable_opcode(&mut self, opcode: IoringOp) -> &mut Self {
+        self.disabled_opcodes.push(opcode);
+        self
+    }
+
+    /// Limit the number of ops in flight, by default there is no limit.
+    ///
+    /// Ops wait for one in flight to finish before they are pushed, so a burst
+    /// of them does not grow the memory. Ops submitted explicitly by `submit`, or
+    /// in a link, are not limited. Ops on the blocking pool, e.g. of the thread
+    /// pool backend, are limited the same.
+    pub fn max_in_flight(&mut self, n: usize) -> &mut Self {
+        self.max_in_flight = Some(n);
+        self
+    }
+
+    /// Bound the table of ops to `n` ops, by default it grows as needed.
+    ///
+    /// The table is allocated up front and never reallocates, so pushing an op
+    /// takes a predictable time. Ops pushed while it is full fail with
+    /// [`Error::OpTableFull`](crate::Error::OpTableFull), set
+    /// [`RuntimeBuilder::max_in_flight`] to at most `n` to wait instead. Ops are
+    /// removed once their completion is consumed, files closed while it is full
+    /// are closed synchronously.
+    pub fn max_ops(&mut self, n: usize) -> &mut Self {
+        self.max_ops = Some(n);
+        self
+    }
+
+    /// Force the backend, by default it is io_uring, and falls back to the thread
+    /// pool if io_uring is not permitted or not implemented by kernel.
+    ///
+    /// Settings of the ring are ignored by the thread pool backend.
+    pub fn backend(&mut self, backend: Backend) -> &mut Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Make [`Runtime::block_on`] wait for spawned tasks to finish before it
+    /// returns, default is false which leaves them to the next `block_on`.
+    pub fn wait_for_tasks(&mut self, enable: bool) -> &mut Self {
+        self.wait_for_tasks = enable;
+        self
+    }
+
+    /// Run tasks on the built-in executor instead of tokio, tasks are spawned by
+    /// [`spawn_local`](super::task::spawn_local).
+    ///
+    /// Tokio drivers are not available, so [`RuntimeBuilder::enable_time`] and
+    /// [`RuntimeBuilder::enable_io`] are ignored, use [`crate::time`] for timers.
+    #[cfg(feature = "executor")]
+    pub fn executor(&mut self, enable: bool) -> &mut Self {
+        self.executor = enable;
+        self
+    }
+
+    pub fn build(&self) -> crate::Result<Runtime> {
+        let mut rt = self.build_runtime()?;
+        rt.wait_for_tasks = self.wait_for_tasks;
+        if rt.driver.is_none() {
+            rt.pool_limit = self.max_in_flight.map(Limit::new);
+        }
+        Ok(rt)
+    }
+
+    fn build_runtime(&self) -> crate::Result<Runtime> {
+        self.check()?;
+
+        let driver = match self.backend {
+            Some(Backend::IoUring) => Some(self.build_driver()?),
+            Some(Backend::ThreadPool) => None,
+            Some(Backend::Simulated) => {
+                let mut driver = self.build_driver()?;
+                driver.simulate();
+                // Completions are delivered by the simulator, not reaped.
+                driver.reap_on_park = false;
+                Some(driver)
+            }
+            None => match self.build_driver() {
+                Ok(driver) => Some(driver),
+                Err(e)
+                    if [rustix::io::Errno::PERM, rustix::io::Errno::NOSYS]
+                        .iter()
+                        .any(|errno| e.raw_os_error() == Some(errno.raw_os_error())) =>
+                {
+                    tracing::warn!("io_uring is unavailable, fall back to thread pool: {}", e);
+                    None
+                }
+                Err(e) => return Err(e.into()),
+            },
+        };
+
+        #[cfg(feature = "executor")]
+        if self.executor {
+            let driver = driver.ok_or(crate::Error::Unsupported(
+                "the executor on the thread pool backend",
+            ))?;
+            return Ok(Runtime::with_executor(driver)?);
+        }
+
+        Ok(Runtime::with_driver(
+            driver,
+            self.enable_time,
+            self.enable_io,
+        )?)
+    }
+
+    fn build_driver(&self) -> std::io::Result<Driver> {
+        let mut builder = rustix_uring::IoUring::builder();
+        if let Some(cq_entries) = self.cq_entries {
+            builder.setup_cqsize(cq_entries);
+        }
+        if let Some(idle) = self.sqpoll_idle {
+            builder.setup_sqpoll(idle.as_millis() as u32);
+        }
+        if let Some(cpu) = self.sqpoll_cpu {
+            builder.setup_sqpoll_cpu(cpu);
+        }
+        if self.iopoll {
+            builder.setup_iopoll();
+        }
+        if self.coop_taskrun {
+            builder.setup_coop_taskrun();
+        }
+        if self.single_issuer {
+            builder.setup_single_issuer();
+        }
+        if self.defer_taskrun {
+            builder.setup_defer_taskrun();
+        }
+
+        let mut driver = Driver::new(&builder, self.entries)?;
+        if let Some(n) = self.max_ops {
+            driver.ops = Ops::bounded(n);
+        }
+        driver.reap_on_park = self.iopoll || self.defer_taskrun || !self.enable_io;
+        driver.submit_policy = self.submit_policy;
+        driver.disabled = self.disabled_opcodes.clone();
+        driver.ops.limit = self.max_in_flight.map(Limit::new);
+        if self.registered_files > 0 {
+            driver.register_files(self.registered_files)?;
+        }
+        Ok(driver)
+    }
+
+    fn check(&self) -> std::io::Result<()> {
+        let invalid = |msg: &str| {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                msg.to_string(),
+            ))
+        };
+
+        if self.entries == 0 || self.entries > Self::MAX_ENTRIES {
+            return invalid("entries must be in 1..=32768");
+        }
+        if let Some(cq_entries) = self.cq_entries {
+            if cq_entries < self.entries {
+                return invalid("cq entries must not be less than entries");
+            }
+            if cq_entries > Self::MAX_CQ_ENTRIES {
+                return invalid("cq entries must not be greater than 65536");
+            }
+        }
+        if let Some(idle) = self.sqpoll_idle
+            && idle.as_millis() > u32::MAX as u128
+        {
+            return invalid("sqpoll idle timeout is too long");
+        }
+        if self.sqpoll_cpu.is_some() && self.sqpoll_idle.is_none() {
+            return invalid("sqpoll cpu requires sqpoll");
+        }
+        if self.sqpoll_idle.is_some() && (self.coop_taskrun || self.defer_taskrun) {
+            return invalid("sqpoll can not be used with coop taskrun or defer taskrun");
+        }
+        if self.defer_taskrun && !self.single_issuer {
+            return invalid("defer taskrun requires single issuer");
+        }
+        if let Some(n) = self.max_in_flight
+            && (n == 0 || n > Semaphore::MAX_PERMITS)
+        {
+            return invalid("max in flight must be in 1..=usize::MAX >> 3");
+        }
+        if let Some(n) = self.max_ops
+            && (n == 0 || n >= u32::MAX as usize)
+        {
+            return invalid("max ops must be in 1..u32::MAX");
+        }
+        if self.submit_policy == SubmitPolicy::Threshold(0) {
+            return invalid("submit threshold must be greater than 0");
+        }
+
+        Ok(())
+    }
+}
+
+pub fn default_rt() -> crate::Result<Runtime> {
+    RuntimeBuilder::new().build()
 }
 
 #[cfg(test)]
 mod tests {
     use std::{io::Write, rc::Rc, time::Duration};
 
+    use rustix_uring::opcode;
     use tempfile::tempfile;
 
-    use crate::uring::fs::File;
+    use crate::{
+        Error,
+        uring::{driver::OPS_CAPACITY, fs::File, time::sleep},
+    };
 
-    use super::{Runtime, default_rt};
+    use super::{Backend, Runtime, RuntimeBuilder, SubmitPolicy, default_rt};
+
+    /// A file of the read end of a pipe, reads on it wait for the write end.
+    fn pipe() -> (File, std::io::PipeWriter) {
+        let (rx, tx) = std::io::pipe().unwrap();
+        let rx = std::fs::File::from(std::os::fd::OwnedFd::from(rx));
+        (File::from_std_fd(rx), tx)
+    }
 
     #[test]
     fn test_block_on() {
@@ -123,6 +845,446 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_backend() {
+        let rt = RuntimeBuilder::new()
+            .backend(Backend::IoUring)
+            .build()
+            .unwrap();
+        assert_eq!(rt.backend(), Backend::IoUring);
+        assert_eq!(default_rt().unwrap().backend(), Backend::IoUring);
+
+        let rt = RuntimeBuilder::new()
+            .backend(Backend::ThreadPool)
+            .build()
+            .unwrap();
+        assert_eq!(rt.backend(), Backend::ThreadPool);
+        rt.block_on(async {
+            tokio::time::sleep(Duration::from_micros(100)).await;
+        });
+        assert_eq!(rt.submit_calls(), 0);
+    }
+
+    #[test]
+    fn test_unsupported_opcode() {
+        let rt = RuntimeBuilder::new()
+            .disable_opcode(opcode::Timeout::CODE)
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let res = sleep(Duration::from_millis(1)).await;
+            assert!(matches!(
+                res,
+                Err(Error::UnsupportedOpcode(op)) if op == opcode::Timeout::CODE
+            ));
+        });
+
+        // Timers have no fallback on the pool.
+        let rt = RuntimeBuilder::new()
+            .backend(Backend::ThreadPool)
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let res = sleep(Duration::from_millis(1)).await;
+            assert!(matches!(res, Err(Error::Unsupported(_))));
+        });
+    }
+
+    #[test]
+    fn test_builder_check() {
+        let err = |b: &RuntimeBuilder| b.build().err().unwrap().kind();
+
+        assert_eq!(
+            err(RuntimeBuilder::new().entries(0)),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            err(RuntimeBuilder::new().entries(64).cq_entries(32)),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            err(RuntimeBuilder::new().sqpoll_cpu(0)),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            err(RuntimeBuilder::new()
+                .sqpoll(Duration::from_millis(10))
+                .coop_taskrun(true)),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            err(RuntimeBuilder::new().defer_taskrun(true)),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            err(RuntimeBuilder::new().submit_policy(SubmitPolicy::Threshold(0))),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            err(RuntimeBuilder::new().max_in_flight(0)),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            err(RuntimeBuilder::new().max_ops(0)),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn test_builder_flags() {
+        let rt = RuntimeBuilder::new()
+            .entries(64)
+            .cq_entries(128)
+            .coop_taskrun(true)
+            .single_issuer(true)
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let mut file = tempfile().unwrap();
+            file.write_all(b"hello world").unwrap();
+            let file = File::from_std_fd(file);
+
+            let (res, buf) = file.read_at(vec![0; 11], 0).await;
+            assert_eq!(res.unwrap(), 11);
+            assert_eq!(&buf[..], b"hello world");
+        });
+    }
+
+    #[test]
+    fn test_submit_policy() {
+        let mut std_file = tempfile().unwrap();
+        std_file.write_all(&[1; 4096 * 16]).unwrap();
+
+        let submit_calls = |policy: SubmitPolicy| {
+            let rt = RuntimeBuilder::new().submit_policy(policy).build().unwrap();
+            let file = File::from_std_fd(std_file.try_clone().unwrap());
+            rt.block_on(async {
+                let reads = (0..16).map(|i| file.read_at(vec![0; 4096], i * 4096));
+                for (res, buf) in futures::future::join_all(reads).await {
+                    assert_eq!(res.unwrap(), 4096);
+                    assert!(buf.iter().all(|b| *b == 1));
+                }
+            });
+            let calls = rt.submit_calls();
+            rt.block_on(file.close()).unwrap();
+            calls
+        };
+
+        assert_eq!(submit_calls(SubmitPolicy::Immediate), 16);
+        assert_eq!(submit_calls(SubmitPolicy::Threshold(4)), 4);
+        assert_eq!(submit_calls(SubmitPolicy::OnPark), 1);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut std_file = tempfile().unwrap();
+        std_file.write_all(b"hello world").unwrap();
+        let rt = default_rt().unwrap();
+        let file = File::from_std_fd(std_file);
+
+        rt.block_on(async {
+            for i in 0..4 {
+                let (res, _) = file.read_at(vec![0; 5], i).await;
+                assert_eq!(res.unwrap(), 5);
+            }
+
+            // Never completes, so it is cancelled when dropped.
+            let (rx, _tx) = std::io::pipe().unwrap();
+            let rx = File::from_std_fd(std::fs::File::from(std::os::fd::OwnedFd::from(rx)));
+            let read = rx.read_at(vec![0; 5], 0).submit().unwrap();
+            assert_eq!(rt.metrics().in_flight, 1);
+            drop(read);
+            // The cancelled read holds the file until it completes.
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            rx.close().await.unwrap();
+        });
+
+        let metrics = rt.metrics();
+        // Reads and the close.
+        assert_eq!(metrics.submitted, 6);
+        assert_eq!(metrics.completed, 6);
+        assert_eq!(metrics.ignored, 1);
+        assert_eq!(metrics.cancelled, 1);
+        assert_eq!(metrics.in_flight, 0);
+        assert_eq!(metrics.cq_overflow, 0);
+
+        let reads = metrics.latency(opcode::Read::CODE).unwrap();
+        assert_eq!(reads.count(), 5);
+        assert!(reads.quantile(0.5).is_some());
+        assert!(metrics.latency(opcode::Write::CODE).is_none());
+
+        let metrics = RuntimeBuilder::new()
+            .backend(Backend::ThreadPool)
+            .build()
+            .unwrap()
+            .metrics();
+        assert_eq!(metrics.submitted, 0);
+    }
+
+    #[test]
+    fn test_thread_pool_limit() {
+        let rt = RuntimeBuilder::new()
+            .backend(Backend::ThreadPool)
+            .max_in_flight(1)
+            .build()
+            .unwrap();
+        let (rx, mut tx) = pipe();
+        let mut file = tempfile().unwrap();
+        file.write_all(b"hello").unwrap();
+        let file = File::from_std_fd(file);
+
+        rt.block_on(async {
+            // Holds the only permit until the pipe is written.
+            let blocked = tokio::task::spawn_local(async move { rx.read_at(vec![0; 16], 0).await });
+            tokio::task::yield_now().await;
+            let read = tokio::task::spawn_local(async move { file.read_at(vec![0; 5], 0).await });
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert!(!read.is_finished());
+
+            tx.write_all(b"world").unwrap();
+            assert_eq!(blocked.await.unwrap().0.unwrap(), 5);
+            assert_eq!(read.await.unwrap().0.unwrap(), 5);
+        });
+    }
+
+    #[test]
+    fn test_thread_pool_timeout() {
+        let rt = RuntimeBuilder::new()
+            .backend(Backend::ThreadPool)
+            .build()
+            .unwrap();
+        let (rx, _tx) = pipe();
+
+        rt.block_on(async {
+            // Fails rather than blocking past the deadline.
+            let (res, buf) = rx
+                .read_at(vec![0; 16], 0)
+                .timeout(Duration::from_millis(10))
+                .await;
+            assert!(matches!(res, Err(Error::Unsupported(_))));
+            assert_eq!(buf.len(), 16);
+        });
+    }
+
+    #[test]
+    fn test_max_ops() {
+        let rt = RuntimeBuilder::new().max_ops(1).build().unwrap();
+        let (rx, mut tx) = pipe();
+        let mut file = tempfile().unwrap();
+        file.write_all(b"hello").unwrap();
+        let file = File::from_std_fd(file);
+
+        rt.block_on(async {
+            // Takes the only slot until the pipe is written.
+            let rx = Rc::new(rx);
+            let blocked = tokio::task::spawn_local({
+                let rx = rx.clone();
+                async move { rx.read_at(vec![0; 16], 0).await }
+            });
+            tokio::task::yield_now().await;
+            let (res, _) = file.read_at(vec![0; 5], 0).await;
+            assert!(matches!(res, Err(Error::OpTableFull)));
+
+            tx.write_all(b"world").unwrap();
+            assert_eq!(blocked.await.unwrap().0.unwrap(), 5);
+            assert_eq!(file.read_at(vec![0; 5], 0).await.0.unwrap(), 5);
+        });
+    }
+
+    #[test]
+    fn test_shrink_after_burst() {
+        let rt = default_rt().unwrap();
+        let mut file = tempfile().unwrap();
+        file.write_all(b"hello").unwrap();
+        let file = File::from_std_fd(file);
+        let burst = || async {
+            let reads = (0..2048).map(|_| file.read_at(vec![0; 5], 0));
+            for (res, _) in futures::future::join_all(reads).await {
+                assert_eq!(res.unwrap(), 5);
+            }
+        };
+
+        rt.block_on(burst());
+        let driver = rt.driver.as_ref().unwrap();
+        let (first, trimmed) = {
+            let driver = driver.borrow();
+            assert!(driver.ops.lifecycle.capacity() >= 2048);
+            (driver.ops.user_data(0), driver.ops.user_data(1000))
+        };
+        driver.borrow_mut().park();
+        let capacity = driver.borrow().ops.lifecycle.capacity();
+        assert!((OPS_CAPACITY..2048).contains(&capacity));
+
+        // Keys of removed ops are still not reused.
+        assert_eq!(driver.borrow().ops.user_data(0), first);
+        rt.block_on(burst());
+        assert_ne!(driver.borrow().ops.user_data(1000), trimmed);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let rt = default_rt().unwrap();
+        let (rx, _tx) = pipe();
+        rt.block_on(async {
+            tokio::task::spawn_local(async move { rx.read_at(vec![0; 16], 0).await });
+            tokio::task::yield_now().await;
+            assert_eq!(rt.metrics().in_flight, 1);
+        });
+
+        let report = rt.shutdown(Duration::from_secs(5));
+        assert!(report.leaked.is_empty());
+        assert_eq!(report.cancelled.len(), 1);
+        assert_eq!(report.cancelled[0].opcode, opcode::Read::CODE);
+    }
+
+    #[test]
+    fn test_shutdown_timeout() {
+        let rt = default_rt().unwrap();
+        let (rx, _tx) = pipe();
+        rt.block_on(async {
+            tokio::task::spawn_local(async move { rx.read_at(vec![0; 16], 0).await });
+            tokio::task::yield_now().await;
+        });
+
+        // Nothing is reaped without waiting.
+        let report = rt.shutdown(Duration::ZERO);
+        assert!(report.cancelled.is_empty());
+        assert_eq!(report.leaked.len(), 1);
+    }
+
+    #[test]
+    fn test_drop_with_blocked_read() {
+        let rt = default_rt().unwrap();
+        let (rx, tx) = pipe();
+        rt.block_on(async {
+            tokio::task::spawn_local(async move { rx.read_at(vec![0; 16], 0).await });
+            tokio::task::yield_now().await;
+        });
+        drop(rt);
+        drop(tx);
+    }
+
+    #[test]
+    fn test_reap_on_park() {
+        for builder in [
+            RuntimeBuilder::new().enable_io(false).clone(),
+            RuntimeBuilder::new()
+                .single_issuer(true)
+                .defer_taskrun(true)
+                .clone(),
+        ] {
+            let rt = builder.build().unwrap();
+            rt.block_on(async {
+                let mut file = tempfile().unwrap();
+                file.write_all(b"hello world").unwrap();
+                let file = Rc::new(File::from_std_fd(file));
+
+                let handles = (0..4)
+                    .map(|i| {
+                        let file = file.clone();
+                        tokio::task::spawn_local(async move { file.read_at(vec![0; 5], i).await })
+                    })
+                    .collect::<Vec<_>>();
+
+                for (i, h) in handles.into_iter().enumerate() {
+                    let (res, buf) = h.await.unwrap();
+                    assert_eq!(res.unwrap(), 5);
+                    assert_eq!(&buf[..], &b"hello world"[i..i + 5]);
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn test_reap_on_park_blocking_wake() {
+        for builder in [
+            RuntimeBuilder::new().enable_io(false).clone(),
+            RuntimeBuilder::new()
+                .single_issuer(true)
+                .defer_taskrun(true)
+                .clone(),
+        ] {
+            let rt = builder
+                .clone()
+                .disable_opcode(opcode::OpenAt::CODE)
+                .build()
+                .unwrap();
+            let (rx, mut tx) = pipe();
+            let tempdir = tempfile::tempdir().unwrap();
+            let fifo = tempdir.path().join("fifo");
+            rustix::fs::mknodat(
+                rustix::fs::CWD,
+                &fifo,
+                rustix::fs::FileType::Fifo,
+                rustix::fs::Mode::RUSR | rustix::fs::Mode::WUSR,
+                0,
+            )
+            .unwrap();
+
+            // Opening the fifo on the pool blocks until it is opened for writing.
+            let (opened_tx, opened_rx) = std::sync::mpsc::channel();
+            let writer = std::thread::spawn({
+                let fifo = fifo.clone();
+                move || {
+                    std::thread::sleep(Duration::from_millis(50));
+                    let _fifo = std::fs::OpenOptions::new().write(true).open(fifo).unwrap();
+                    // Do not hang if the runtime missed the wake.
+                    let _ = opened_rx.recv_timeout(Duration::from_secs(3));
+                    tx.write_all(b"hello").unwrap();
+                }
+            });
+
+            rt.block_on(async {
+                // Parks in the ring while the read is in flight.
+                let read =
+                    tokio::task::spawn_local(async move { rx.read_at(vec![0; 16], 0).await });
+                tokio::task::yield_now().await;
+
+                let start = std::time::Instant::now();
+                File::open(&fifo).await.unwrap();
+                assert!(start.elapsed() < Duration::from_secs(1));
+                opened_tx.send(()).unwrap();
+
+                let (res, _) = read.await.unwrap();
+                assert_eq!(res.unwrap(), 5);
+            });
+            writer.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_reap_on_park_sleep_with_remote() {
+        for builder in [
+            RuntimeBuilder::new().enable_io(false).clone(),
+            RuntimeBuilder::new()
+                .single_issuer(true)
+                .defer_taskrun(true)
+                .clone(),
+        ] {
+            let rt = builder.build().unwrap();
+            let remote = rt.remote_handle().unwrap();
+            rt.block_on(async {
+                // Not waited for in the ring, which would miss the timer.
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                // Woken by the remote task then.
+                let (tx, rx) = std::sync::mpsc::channel();
+                std::thread::spawn(move || {
+                    let handle = remote.spawn(|| async { 1 });
+                    tx.send(futures::executor::block_on(handle).unwrap())
+                        .unwrap();
+                });
+                let res = loop {
+                    if let Ok(res) = rx.try_recv() {
+                        break res;
+                    }
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                };
+                assert_eq!(res, 1);
+            });
+        }
+    }
+
     #[test]
     fn test_write_and_read() {
         tracing_subscriber::fmt()
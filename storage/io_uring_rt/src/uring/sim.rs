@@ -0,0 +1,431 @@
+//! A simulated backend, see [`Simulator`].
+//!
+//! Entries are recorded by the driver instead of being submitted, and tests
+//! complete them in any order, with any result, so op lifecycles could be
+//! tested without depending on kernel timing.
+
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+};
+
+use rustix::io::Errno;
+use rustix::io_uring::IoringOp;
+use rustix_uring::cqueue::{self, Flags};
+
+use super::driver::{self, Driver};
+
+/// An entry pushed to the simulated backend, which is not finally completed yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Submission {
+    /// The key of the op, or a tag of an internal entry, e.g. the cancellation
+    /// of a dropped op.
+    pub user_data: u64,
+    pub opcode: IoringOp,
+    pub fd: i32,
+}
+
+impl Submission {
+    pub(crate) fn new(sqe: &rustix_uring::squeue::Entry) -> Self {
+        let raw = driver::raw_sqe(sqe);
+        Self {
+            user_data: sqe.get_user_data().u64_(),
+            opcode: raw.opcode,
+            fd: raw.fd,
+        }
+    }
+}
+
+/// Completes the entries of a runtime built with
+/// [`Backend::Simulated`](super::rt::Backend::Simulated), see
+/// [`Runtime::simulator`](super::rt::Runtime::simulator).
+///
+/// Completions are delivered when they are given, so wakers of the ops are
+/// woken before it returns.
+#[derive(Clone)]
+pub struct Simulator {
+    driver: Weak<RefCell<Driver>>,
+}
+
+impl Simulator {
+    pub(crate) fn new(driver: &Rc<RefCell<Driver>>) -> Self {
+        Self {
+            driver: Rc::downgrade(driver),
+        }
+    }
+
+    /// Entries pushed and not finally completed, in the order they were pushed.
+    pub fn submitted(&self) -> Vec<Submission> {
+        let driver = self.driver();
+        let driver = driver.borrow();
+        driver.recorded().to_vec()
+    }
+
+    /// Complete the entry with `user_data`, `result` is the number of bytes or a
+    /// negative errno.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no such entry is pending.
+    pub fn complete(&self, user_data: u64, result: i32) {
+        self.complete_with_flags(user_data, result, Flags::empty());
+    }
+
+    /// Fail the entry with `user_data`.
+    pub fn fail(&self, user_data: u64, errno: Errno) {
+        self.complete(user_data, -errno.raw_os_error());
+    }
+
+    /// Complete the entry with `IORING_CQE_F_MORE`, so a multishot op keeps
+    /// running.
+    pub fn complete_more(&self, user_data: u64, result: i32) {
+        self.complete_with_flags(user_data, result, Flags::MORE);
+    }
+
+    /// Complete the entry with `flags`, it is still pending if they have
+    /// `IORING_CQE_F_MORE`.
+    pub fn complete_with_flags(&self, user_data: u64, result: i32, flags: Flags) {
+        let handle = self.driver();
+        let mut driver = handle.borrow_mut();
+
+        let recorded = driver.recorded_mut();
+        let Some(i) = recorded.iter().position(|s| s.user_data == user_data) else {
+            panic!("no entry with user_data {user_data} is pending");
+        };
+        if !cqueue::more(flags) {
+            recorded.remove(i);
+        }
+
+        driver.complete_cqe(user_data, result, flags);
+        drop(driver);
+        driver::drop_released(&handle);
+    }
+
+    fn driver(&self) -> Rc<RefCell<Driver>> {
+        self.driver.upgrade().expect("runtime is dropped")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::pin,
+        rc::Rc,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        task::{Context, Poll, Wake, Waker},
+        time::Duration,
+    };
+
+    use futures::StreamExt;
+    use rustix::io::Errno;
+    use rustix_uring::{cqueue::Flags, opcode};
+
+    use crate::{
+        Error,
+        uring::{
+            driver::with_current,
+            fs::{File, OpenOptions},
+            op::{MultiCQE, Op},
+            rt::{Backend, Runtime, RuntimeBuilder},
+        },
+    };
+
+    use super::Simulator;
+
+    /// Counts its wakes.
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl Wake for Counter {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl Counter {
+        fn count(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    fn sim_rt() -> (Runtime, Simulator) {
+        let rt = RuntimeBuilder::new()
+            .backend(Backend::Simulated)
+            .build()
+            .unwrap();
+        let sim = rt.simulator().unwrap();
+        (rt, sim)
+    }
+
+    fn nop<T: Unpin>(data: T) -> Op<T> {
+        Op::submit_with(data, |_| opcode::Nop::new().build()).unwrap()
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let (rt, sim) = sim_rt();
+        assert_eq!(rt.backend(), Backend::Simulated);
+
+        rt.block_on(async {
+            let first = nop(());
+            let second = nop(());
+            let submitted = sim.submitted();
+            assert_eq!(submitted.len(), 2);
+            assert!(submitted.iter().all(|s| s.opcode == opcode::Nop::CODE));
+
+            sim.complete(submitted[1].user_data, 7);
+            sim.fail(submitted[0].user_data, Errno::NOENT);
+            assert_eq!(second.await.result.unwrap(), 7);
+            let err = first.await.result.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        });
+        assert_eq!(rt.metrics().in_flight, 0);
+    }
+
+    #[test]
+    fn test_complete_after_drop() {
+        let (rt, sim) = sim_rt();
+        rt.block_on(async {
+            let data = Rc::new(());
+            let op = nop(data.clone());
+            let user_data = sim.submitted()[0].user_data;
+            drop(op);
+
+            // Ignored, kernel may still use the data, and a cancel is pushed.
+            assert_eq!(Rc::strong_count(&data), 2);
+            let cancel = sim.submitted()[1];
+            assert_eq!(cancel.opcode, opcode::AsyncCancel2::CODE);
+            assert_eq!(rt.metrics().ignored, 1);
+
+            sim.complete(cancel.user_data, 0);
+            sim.fail(user_data, Errno::CANCELED);
+            assert_eq!(Rc::strong_count(&data), 1);
+            assert!(sim.submitted().is_empty());
+        });
+        assert_eq!(rt.metrics().in_flight, 0);
+    }
+
+    #[test]
+    fn test_complete_after_drop_file() {
+        let (rt, sim) = sim_rt();
+        rt.block_on(async {
+            let file = File::from_std_fd(std::fs::File::open("/dev/null").unwrap());
+            let mut read = Box::pin(file.read_at(vec![0; 16], 0));
+            assert!(futures::poll!(read.as_mut()).is_pending());
+            let user_data = sim.submitted()[0].user_data;
+            drop(read);
+            drop(file);
+
+            let cancel = sim.submitted()[1];
+            sim.complete(cancel.user_data, 0);
+            // The ignored read holds the last reference, so the file is closed.
+            sim.fail(user_data, Errno::CANCELED);
+            let submitted = sim.submitted();
+            assert_eq!(submitted[0].opcode, opcode::Close::CODE);
+            for s in submitted {
+                sim.complete(s.user_data, 0);
+            }
+            assert!(sim.submitted().is_empty());
+        });
+        assert_eq!(rt.metrics().in_flight, 0);
+    }
+
+    #[test]
+    fn test_cancelled_before_timeout_completion() {
+        let (rt, sim) = sim_rt();
+        rt.block_on(async {
+            let file = File::from_std_fd(std::fs::File::open("/dev/null").unwrap());
+            let mut read = Box::pin(file.read_at(vec![0; 16], 0).timeout(Duration::from_secs(1)));
+            assert!(futures::poll!(read.as_mut()).is_pending());
+            let submitted = sim.submitted();
+            assert_eq!(submitted[1].opcode, opcode::LinkTimeout::CODE);
+
+            // Held until the timeout is completed.
+            sim.fail(submitted[0].user_data, Errno::CANCELED);
+            assert!(futures::poll!(read.as_mut()).is_pending());
+            sim.fail(submitted[1].user_data, Errno::TIME);
+            let (res, _) = read.await;
+            assert!(matches!(res, Err(Error::TimedOut)));
+        });
+    }
+
+    #[test]
+    fn test_cancelled_after_timeout_completion() {
+        let (rt, sim) = sim_rt();
+        rt.block_on(async {
+            let file = File::from_std_fd(std::fs::File::open("/dev/null").unwrap());
+            let mut read = Box::pin(file.read_at(vec![0; 16], 0).timeout(Duration::from_secs(1)));
+            assert!(futures::poll!(read.as_mut()).is_pending());
+            let submitted = sim.submitted();
+
+            sim.fail(submitted[1].user_data, Errno::TIME);
+            assert!(futures::poll!(read.as_mut()).is_pending());
+            sim.fail(submitted[0].user_data, Errno::CANCELED);
+            let (res, _) = read.await;
+            assert!(matches!(res, Err(Error::TimedOut)));
+        });
+    }
+
+    /// A runtime with a single slot for direct descriptors.
+    fn sim_rt_with_slot() -> (Runtime, Simulator) {
+        let rt = RuntimeBuilder::new()
+            .backend(Backend::Simulated)
+            .registered_files(1)
+            .build()
+            .unwrap();
+        let sim = rt.simulator().unwrap();
+        (rt, sim)
+    }
+
+    /// Options of a direct descriptor, which takes the only slot.
+    fn fixed() -> OpenOptions {
+        OpenOptions::new().read(true).fixed_file(true).clone()
+    }
+
+    async fn open_fixed(sim: &Simulator) -> File {
+        let opts = fixed();
+        let mut open = pin!(opts.open("/dev/null"));
+        assert!(futures::poll!(open.as_mut()).is_pending());
+        let submitted = sim.submitted();
+        assert_eq!(submitted.len(), 1);
+        sim.complete(submitted[0].user_data, 0);
+        let file = open.await.unwrap();
+        assert!(file.is_registered());
+        file
+    }
+
+    #[test]
+    fn test_drop_open_before_push() {
+        let (rt, sim) = sim_rt_with_slot();
+        rt.block_on(async {
+            drop(Op::open("/dev/null", &fixed()));
+            assert!(sim.submitted().is_empty());
+
+            drop(open_fixed(&sim).await);
+            for s in sim.submitted() {
+                sim.complete(s.user_data, 0);
+            }
+        });
+    }
+
+    #[test]
+    fn test_drop_open_in_flight() {
+        let (rt, sim) = sim_rt_with_slot();
+        rt.block_on(async {
+            let opts = fixed();
+            let mut open = Box::pin(opts.open("/dev/null"));
+            assert!(futures::poll!(open.as_mut()).is_pending());
+            let user_data = sim.submitted()[0].user_data;
+            drop(open);
+
+            // Opened after it was dropped, the slot is cleared and given back.
+            let cancel = sim.submitted()[1];
+            sim.complete(cancel.user_data, 0);
+            sim.complete(user_data, 0);
+            assert!(sim.submitted().is_empty());
+
+            drop(open_fixed(&sim).await);
+            for s in sim.submitted() {
+                sim.complete(s.user_data, 0);
+            }
+        });
+    }
+
+    #[test]
+    fn test_replace_waker() {
+        let (rt, sim) = sim_rt();
+        rt.block_on(async {
+            let mut op = pin!(nop(()));
+            let first = Arc::new(Counter::default());
+            let second = Arc::new(Counter::default());
+
+            let waker = Waker::from(first.clone());
+            assert!(
+                op.as_mut()
+                    .poll(&mut Context::from_waker(&waker))
+                    .is_pending()
+            );
+            // Polled twice by the same task.
+            assert!(
+                op.as_mut()
+                    .poll(&mut Context::from_waker(&waker))
+                    .is_pending()
+            );
+            // Moved to another task.
+            let waker = Waker::from(second.clone());
+            assert!(
+                op.as_mut()
+                    .poll(&mut Context::from_waker(&waker))
+                    .is_pending()
+            );
+
+            sim.complete(sim.submitted()[0].user_data, 1);
+            assert_eq!((first.count(), second.count()), (0, 1));
+            let Poll::Ready(completion) = op.poll(&mut Context::from_waker(&waker)) else {
+                panic!("not ready after completion");
+            };
+            assert_eq!(completion.result.unwrap(), 1);
+        });
+    }
+
+    #[test]
+    fn test_multishot() {
+        let (rt, sim) = sim_rt();
+        rt.block_on(async {
+            let mut op =
+                Op::<(), MultiCQE>::submit_multi_with((), |_| opcode::Nop::new().build()).unwrap();
+            let user_data = sim.submitted()[0].user_data;
+
+            sim.complete_more(user_data, 1);
+            sim.complete_more(user_data, 2);
+            sim.fail(user_data, Errno::CANCELED);
+            assert!(sim.submitted().is_empty());
+
+            let results = op.by_ref().map(|cqe| cqe.result).collect::<Vec<_>>().await;
+            assert_eq!(results.len(), 3);
+            assert_eq!(results[0].as_ref().unwrap(), &1);
+            assert_eq!(results[1].as_ref().unwrap(), &2);
+            assert!(matches!(results[2], Err(Error::Cancelled)));
+        });
+    }
+
+    #[test]
+    fn test_stale_completion() {
+        let (rt, sim) = sim_rt();
+        rt.block_on(async {
+            let op = nop(());
+            let stale = sim.submitted()[0].user_data;
+            sim.complete(stale, 1);
+            op.await.result.unwrap();
+
+            // Reuses the slot, with another generation.
+            let op = nop(());
+            let user_data = sim.submitted()[0].user_data;
+            assert_ne!(user_data, stale);
+
+            // A duplicated completion of the first op.
+            with_current(|driver| {
+                driver.complete_cqe(stale, 2, Flags::empty());
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(rt.metrics().stale, 1);
+
+            sim.complete(user_data, 3);
+            assert_eq!(op.await.result.unwrap(), 3);
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "is pending")]
+    fn test_complete_unknown() {
+        let (rt, sim) = sim_rt();
+        rt.block_on(async {
+            sim.complete(42, 0);
+        });
+    }
+}
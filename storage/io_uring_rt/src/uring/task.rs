@@ -0,0 +1,305 @@
+//! Tasks spawned on the current [`Runtime`](super::rt::Runtime).
+//!
+//! They run on the thread of the runtime, by tokio or the built-in executor,
+//! so they could submit ops like the future given to `block_on`.
+
+use std::{
+    cell::{Cell, RefCell},
+    future::{Future, poll_fn},
+    io,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll, Waker},
+};
+
+use crate::Error;
+
+#[cfg(feature = "executor")]
+use super::executor;
+
+thread_local! {
+    /// Tasks of the runtime running `block_on` on this thread.
+    static CURRENT: RefCell<Option<Rc<TaskSet>>> = const { RefCell::new(None) };
+}
+
+/// Tasks spawned on a runtime and not finished yet.
+#[derive(Default)]
+pub(crate) struct TaskSet {
+    live: Cell<usize>,
+    /// Woken when the last task finishes.
+    idle: RefCell<Option<Waker>>,
+}
+
+/// Held by a task, it is dropped when the task finishes or is dropped.
+struct Tracked(Rc<TaskSet>);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let live = self.0.live.get() - 1;
+        self.0.live.set(live);
+        if live == 0
+            && let Some(waker) = self.0.idle.borrow_mut().take()
+        {
+            waker.wake();
+        }
+    }
+}
+
+impl TaskSet {
+    pub(crate) fn track<F: Future>(
+        self: &Rc<Self>,
+        future: F,
+    ) -> impl Future<Output = F::Output> + use<F> {
+        self.live.set(self.live.get() + 1);
+        let tracked = Tracked(self.clone());
+        async move {
+            let _tracked = tracked;
+            future.await
+        }
+    }
+
+    /// Wait until no task is live.
+    pub(crate) async fn idle(&self) {
+        poll_fn(|cx| {
+            if self.live.get() == 0 {
+                return Poll::Ready(());
+            }
+            *self.idle.borrow_mut() = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Make `spawn` use this set until the guard is dropped.
+    pub(crate) fn enter(self: &Rc<Self>) -> EnterGuard {
+        let prev = CURRENT.with(|c| c.borrow_mut().replace(self.clone()));
+        EnterGuard(prev)
+    }
+}
+
+pub(crate) struct EnterGuard(Option<Rc<TaskSet>>);
+
+impl Drop for EnterGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|c| *c.borrow_mut() = self.0.take());
+    }
+}
+
+/// Spawn a task on the current runtime.
+///
+/// It takes `Send` futures, so code written for multi-threaded runtimes works,
+/// while the task still runs on the thread of the runtime like [`spawn_local`].
+///
+/// # Panics
+///
+/// Panics if called outside of [`Runtime::block_on`](super::rt::Runtime::block_on).
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_inner(future, "`spawn` called outside of a uring runtime")
+}
+
+/// Spawn a `!Send` task on the current runtime, it runs on the thread of the
+/// runtime.
+///
+/// # Panics
+///
+/// Panics if called outside of [`Runtime::block_on`](super::rt::Runtime::block_on).
+pub fn spawn_local<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    spawn_inner(future, "`spawn_local` called outside of a uring runtime")
+}
+
+fn spawn_inner<F>(future: F, outside: &str) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    let tasks = CURRENT.with(|c| c.borrow().clone()).expect(outside);
+    let future = tasks.track(future);
+
+    #[cfg(feature = "executor")]
+    let future = match executor::try_spawn(future) {
+        Ok(handle) => return handle,
+        Err(future) => future,
+    };
+
+    // Inside `block_on`, tokio runs the `LocalSet` of the runtime.
+    JoinHandle::tokio(tokio::task::spawn_local(future))
+}
+
+/// Handle of a spawned task, it resolves to the output of the task.
+///
+/// Dropping it detaches the task, which keeps running. It resolves to
+/// [`Error::Cancelled`] if the task is aborted, or an error if it panicked or
+/// the runtime was dropped.
+pub struct JoinHandle<T> {
+    inner: Inner<T>,
+}
+
+enum Inner<T> {
+    Tokio(tokio::task::JoinHandle<T>),
+    #[cfg(feature = "executor")]
+    Executor(executor::RawHandle<T>),
+}
+
+impl<T> JoinHandle<T> {
+    pub(crate) fn tokio(handle: tokio::task::JoinHandle<T>) -> Self {
+        Self {
+            inner: Inner::Tokio(handle),
+        }
+    }
+
+    #[cfg(feature = "executor")]
+    pub(crate) fn executor(handle: executor::RawHandle<T>) -> Self {
+        Self {
+            inner: Inner::Executor(handle),
+        }
+    }
+
+    /// Drop the task, the handle resolves to [`Error::Cancelled`] unless it has
+    /// finished.
+    ///
+    /// A task aborting itself is dropped when it yields.
+    pub fn abort(&self) {
+        match &self.inner {
+            Inner::Tokio(handle) => handle.abort(),
+            #[cfg(feature = "executor")]
+            Inner::Executor(handle) => handle.abort(),
+        }
+    }
+
+    /// Let the task run in the background, its output is dropped.
+    pub fn detach(self) {}
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = crate::Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match &mut self.inner {
+            Inner::Tokio(handle) => Pin::new(handle).poll(cx).map_err(|e| {
+                if e.is_cancelled() {
+                    Error::Cancelled
+                } else {
+                    io::Error::other("task panicked").into()
+                }
+            }),
+            #[cfg(feature = "executor")]
+            Inner::Executor(handle) => handle.poll(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc, time::Duration};
+
+    use crate::{
+        Error,
+        uring::{
+            rt::{Runtime, RuntimeBuilder, default_rt},
+            time::sleep,
+        },
+    };
+
+    use super::{spawn, spawn_local};
+
+    fn runtimes() -> Vec<Runtime> {
+        let mut runtimes = vec![default_rt().unwrap()];
+        #[cfg(feature = "executor")]
+        runtimes.push(RuntimeBuilder::new().executor(true).build().unwrap());
+        runtimes
+    }
+
+    #[test]
+    fn test_spawn() {
+        for rt in runtimes() {
+            rt.block_on(async {
+                let local = spawn_local(async { Rc::new(1) });
+                let sent = spawn(async { 2 });
+                assert_eq!(*local.await.unwrap() + sent.await.unwrap(), 3);
+            });
+        }
+    }
+
+    #[test]
+    fn test_abort() {
+        for rt in runtimes() {
+            rt.block_on(async {
+                let handle = spawn_local(async {
+                    sleep(Duration::from_secs(10)).await.unwrap();
+                });
+                sleep(Duration::from_millis(1)).await.unwrap();
+                handle.abort();
+                assert!(matches!(handle.await, Err(Error::Cancelled)));
+
+                // Aborted before it is polled.
+                let handle = spawn_local(async { 42 });
+                handle.abort();
+                assert!(matches!(handle.await, Err(Error::Cancelled)));
+            });
+        }
+    }
+
+    #[test]
+    fn test_detach() {
+        for rt in runtimes() {
+            let done = Rc::new(Cell::new(false));
+            let flag = done.clone();
+            rt.block_on(async move {
+                spawn_local(async move {
+                    sleep(Duration::from_millis(5)).await.unwrap();
+                    flag.set(true);
+                })
+                .detach();
+                sleep(Duration::from_millis(20)).await.unwrap();
+            });
+            assert!(done.get());
+        }
+    }
+
+    #[test]
+    fn test_runtime_spawn() {
+        for rt in runtimes() {
+            // Runs once the runtime is driven.
+            let handle = rt.spawn(async { 42 });
+            assert_eq!(rt.block_on(handle).unwrap(), 42);
+        }
+    }
+
+    #[test]
+    fn test_wait_for_tasks() {
+        let mut builders = vec![RuntimeBuilder::new()];
+        #[cfg(feature = "executor")]
+        builders.push({
+            let mut builder = RuntimeBuilder::new();
+            builder.executor(true);
+            builder
+        });
+
+        for mut builder in builders {
+            let rt = builder.wait_for_tasks(true).build().unwrap();
+            let done = Rc::new(Cell::new(false));
+            let flag = done.clone();
+            rt.block_on(async move {
+                spawn_local(async move {
+                    sleep(Duration::from_millis(10)).await.unwrap();
+                    flag.set(true);
+                });
+            });
+            assert!(done.get());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "outside of a uring runtime")]
+    fn test_spawn_outside() {
+        drop(spawn_local(async {}));
+    }
+}
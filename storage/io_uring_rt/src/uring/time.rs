@@ -0,0 +1,364 @@
+//! Timers completed by io_uring, with `IORING_OP_TIMEOUT`.
+//!
+//! They complete on the same ring as other operations, so they do not need the
+//! tokio time driver.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::Stream;
+use pin_project::pin_project;
+use rustix::time::{ClockId, clock_gettime};
+use rustix_uring::{
+    opcode,
+    types::{TimeoutFlags, Timespec},
+};
+
+use super::op::{CompleteAble, Completion, Cqe, MultiCQE, Op, Prepared};
+use crate::Error;
+
+/// `IORING_TIMEOUT_MULTISHOT`, since Linux 6.4.
+const TIMEOUT_MULTISHOT: u32 = 1 << 6;
+
+/// Data of a timeout operation, kernel reads the timespec when submitted.
+pub(crate) struct Timer {
+    timespec: Box<Timespec>,
+}
+
+impl Op<Timer> {
+    /// A timeout expiring at `deadline`, on the monotonic clock.
+    pub(crate) fn timer_at(deadline: Instant) -> Prepared<Timer> {
+        let timer = Timer {
+            timespec: Box::new(monotonic_timespec(deadline)),
+        };
+
+        Op::prepare_with(timer, |timer| {
+            opcode::Timeout::new(&*timer.timespec)
+                .flags(TimeoutFlags::ABS)
+                .build()
+        })
+        .unlimited()
+    }
+
+    /// A timeout expiring after `duration`, counted from submission.
+    pub(crate) fn timer(duration: Duration) -> Prepared<Timer> {
+        let timer = Timer {
+            timespec: Box::new(Timespec::from(duration)),
+        };
+
+        Op::prepare_with(timer, |timer| {
+            opcode::Timeout::new(&*timer.timespec).build()
+        })
+        .unlimited()
+    }
+}
+
+impl Op<Timer, MultiCQE> {
+    /// A timeout expiring every `period` since submission, until cancelled.
+    pub(crate) fn multishot_timer(period: Duration) -> crate::Result<Self> {
+        let timer = Timer {
+            timespec: Box::new(Timespec::from(period)),
+        };
+
+        Op::submit_multi_with(timer, |timer| {
+            opcode::Timeout::new(&*timer.timespec)
+                .flags(TimeoutFlags::from_bits_retain(TIMEOUT_MULTISHOT))
+                .build()
+        })
+    }
+}
+
+impl CompleteAble for Timer {
+    type Output = crate::Result<()>;
+
+    fn handle_completion(comp: Completion<Self>) -> Self::Output {
+        expired(comp.result)
+    }
+}
+
+/// A timeout completes with `ETIME` when it expires.
+fn expired(result: crate::Result<u32>) -> crate::Result<()> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) if e.raw_os_error() == Some(rustix::io::Errno::TIME.raw_os_error()) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Convert `deadline` to an absolute timespec of `CLOCK_MONOTONIC`, which is
+/// also the clock of [`Instant`].
+fn monotonic_timespec(deadline: Instant) -> Timespec {
+    let now = Instant::now();
+    let ts = clock_gettime(ClockId::Monotonic);
+    let now_ts = Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32);
+
+    Timespec::from(now_ts + deadline.saturating_duration_since(now))
+}
+
+/// Wait until `duration` has elapsed.
+pub fn sleep(duration: Duration) -> Sleep {
+    match Instant::now().checked_add(duration) {
+        Some(deadline) => sleep_until(deadline),
+        None => Sleep {
+            deadline: None,
+            op: Op::timer(duration),
+        },
+    }
+}
+
+/// Wait until `deadline` is reached, it completes immediately if the deadline
+/// is in the past.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline: Some(deadline),
+        op: Op::timer_at(deadline),
+    }
+}
+
+/// Future returned by [`sleep`] and [`sleep_until`].
+///
+/// The timeout is submitted when first polled, and cancelled when dropped.
+pub struct Sleep {
+    deadline: Option<Instant>,
+    op: Prepared<Timer>,
+}
+
+impl Sleep {
+    /// The instant at which the sleep completes, `None` if it is too far away
+    /// to be represented.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = crate::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.op).poll(cx)
+    }
+}
+
+/// Create an interval yielding every `period`, the first tick completes
+/// immediately.
+///
+/// # Panics
+///
+/// Panics if `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+    interval_at(Instant::now(), period)
+}
+
+/// Create an interval yielding every `period`, starting at `start`.
+///
+/// # Panics
+///
+/// Panics if `period` is zero.
+pub fn interval_at(start: Instant, period: Duration) -> Interval {
+    assert!(!period.is_zero(), "`period` must be non-zero");
+
+    Interval {
+        next: start,
+        period,
+        state: IntervalState::Idle,
+    }
+}
+
+/// Ticks at a fixed period, returned by [`interval`] and [`interval_at`].
+///
+/// The first tick is an absolute timeout at the start, the following ones are
+/// completions of a multishot timeout armed by then. Kernel re-arms it after each
+/// expiry, since a multishot timeout could not be absolute, so ticks may drift
+/// slightly behind the instants they are scheduled at. Missed ticks are yielded
+/// as fast as possible.
+pub struct Interval {
+    next: Instant,
+    period: Duration,
+    state: IntervalState,
+}
+
+enum IntervalState {
+    Idle,
+    Starting(Sleep),
+    Ticking(Op<Timer, MultiCQE>),
+}
+
+impl Interval {
+    /// Wait for the next tick, and return the instant it was scheduled at.
+    pub async fn tick(&mut self) -> crate::Result<Instant> {
+        std::future::poll_fn(|cx| self.poll_tick(cx)).await
+    }
+
+    pub fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<crate::Result<Instant>> {
+        loop {
+            match &mut self.state {
+                IntervalState::Idle => {
+                    self.state = IntervalState::Starting(sleep_until(self.next));
+                }
+                IntervalState::Starting(sleep) => {
+                    std::task::ready!(Pin::new(sleep).poll(cx))?;
+                    self.state = IntervalState::Ticking(Op::multishot_timer(self.period)?);
+                    return Poll::Ready(Ok(self.advance()));
+                }
+                IntervalState::Ticking(op) => match std::task::ready!(Pin::new(op).poll_next(cx)) {
+                    Some(Cqe { result, .. }) => {
+                        expired(result)?;
+                        return Poll::Ready(Ok(self.advance()));
+                    }
+                    // The timeout was stopped, e.g. cancelled, start over.
+                    None => self.state = IntervalState::Idle,
+                },
+            }
+        }
+    }
+
+    fn advance(&mut self) -> Instant {
+        let tick = self.next;
+        self.next = tick + self.period;
+        tick
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+/// Require `future` to complete within `duration`.
+///
+/// If it does not, the future is dropped and [`Error::TimedOut`] is returned.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+/// Like [`timeout`], but with a deadline.
+pub fn timeout_at<F: Future>(deadline: Instant, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep_until(deadline),
+    }
+}
+
+/// Future returned by [`timeout`] and [`timeout_at`].
+#[pin_project]
+pub struct Timeout<F> {
+    #[pin]
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = crate::Result<F::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        if let Poll::Ready(output) = this.future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+
+        std::task::ready!(Pin::new(this.sleep).poll(cx))?;
+        Poll::Ready(Err(Error::TimedOut))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        Error,
+        uring::{
+            fs::File,
+            rt::{RuntimeBuilder, default_rt},
+        },
+    };
+
+    use super::{interval, sleep, sleep_until, timeout};
+
+    #[test]
+    fn test_sleep() {
+        default_rt().unwrap().block_on(async {
+            let start = Instant::now();
+            sleep(Duration::from_millis(20)).await.unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(20));
+        });
+    }
+
+    #[test]
+    fn test_sleep_until_past() {
+        default_rt().unwrap().block_on(async {
+            let start = Instant::now();
+            sleep_until(start - Duration::from_secs(1)).await.unwrap();
+            assert!(start.elapsed() < Duration::from_secs(1));
+        });
+    }
+
+    #[test]
+    fn test_interval() {
+        default_rt().unwrap().block_on(async {
+            let period = Duration::from_millis(10);
+            let ticks = 10;
+            let start = Instant::now();
+            let mut interval = interval(period);
+
+            // The first tick completes immediately.
+            for _ in 0..=ticks {
+                interval.tick().await.unwrap();
+            }
+            let elapsed = start.elapsed();
+            assert!(elapsed >= ticks * period, "{elapsed:?}");
+            assert!(elapsed < ticks * period * 3 / 2, "{elapsed:?}");
+        });
+    }
+
+    #[test]
+    fn test_timeout() {
+        default_rt().unwrap().block_on(async {
+            let res = timeout(Duration::from_millis(10), std::future::pending::<()>()).await;
+            assert!(matches!(res, Err(Error::TimedOut)));
+
+            let res = timeout(Duration::from_secs(10), async { 42 }).await;
+            assert_eq!(res.unwrap(), 42);
+        });
+    }
+
+    #[test]
+    fn test_timeout_with_max_in_flight() {
+        let rt = RuntimeBuilder::new().max_in_flight(1).build().unwrap();
+        let pipe = || {
+            let (rx, tx) = std::io::pipe().unwrap();
+            let rx = std::fs::File::from(std::os::fd::OwnedFd::from(rx));
+            (File::from_std_fd(rx), tx)
+        };
+        let (rx1, _tx1) = pipe();
+        let (rx2, _tx2) = pipe();
+
+        rt.block_on(async {
+            // Holds the only permit.
+            tokio::task::spawn_local(async move { rx1.read_at(vec![0; 16], 0).await });
+            tokio::task::yield_now().await;
+
+            let res = timeout(Duration::from_millis(10), rx2.read_at(vec![0; 16], 0)).await;
+            assert!(matches!(res, Err(Error::TimedOut)));
+        });
+    }
+
+    #[test]
+    fn test_drop_sleep() {
+        let start = Instant::now();
+        default_rt().unwrap().block_on(async {
+            let sleep = sleep(Duration::from_secs(10));
+            let res = timeout(Duration::from_millis(10), sleep).await;
+            assert!(res.is_err());
+        });
+        // The long sleep was cancelled, so the driver was not waiting for it.
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -10,7 +10,7 @@ pub mod slab;
 /// Pin the current thread to a seleted core.
 ///
 /// ```rust
-/// use zahorikv::worker::pin_thread_on;
+/// use uring_rt::utils::pin_thread_on;
 ///
 /// std::thread::spawn(|| {
 ///     // Get the current core id.
@@ -11,6 +11,8 @@ pub struct Slab<T> {
     entries: Vec<Entry<T>>,
     len: usize,
     next: usize,
+    /// Max number of entries of a bounded slab, it never reallocates.
+    bound: Option<usize>,
 }
 
 impl<T> Default for Slab<T> {
@@ -29,9 +31,28 @@ impl<T> Slab<T> {
             entries: Vec::with_capacity(capacity),
             next: 0,
             len: 0,
+            bound: None,
         }
     }
 
+    /// A slab holding at most `capacity` entries, allocated up front, so it never
+    /// reallocates. Inserting into a full one fails, see [`Slab::try_insert`].
+    pub fn bounded(capacity: usize) -> Self {
+        Self {
+            bound: Some(capacity),
+            ..Self::with_capacity(capacity)
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.bound.is_some_and(|bound| self.len == bound)
+    }
+
+    /// Number of entries a bounded slab could still hold, `None` if unbounded.
+    pub fn remaining(&self) -> Option<usize> {
+        self.bound.map(|bound| bound - self.len)
+    }
+
     pub fn capacity(&self) -> usize {
         self.entries.capacity()
     }
@@ -64,10 +85,24 @@ impl<T> Slab<T> {
         }
     }
 
+    /// # Panics
+    ///
+    /// Panics if a bounded slab is full.
     pub fn insert(&mut self, elem: T) -> usize {
+        match self.try_insert(elem) {
+            Ok(key) => key,
+            Err(_) => panic!("slab is full"),
+        }
+    }
+
+    /// Like [`Slab::insert`], but give `elem` back if a bounded slab is full.
+    pub fn try_insert(&mut self, elem: T) -> Result<usize, T> {
+        if self.is_full() {
+            return Err(elem);
+        }
         let key = self.next;
         self.insert_at(key, elem);
-        key
+        Ok(key)
     }
 
     fn insert_at(&mut self, key: usize, elem: T) {
@@ -107,6 +142,88 @@ impl<T> Slab<T> {
     }
 }
 
+impl<T> Slab<T> {
+    /// Occupied entries with their keys, in the order of keys.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(key, entry)| match entry {
+                Entry::Occupied(elem) => Some((key, elem)),
+                Entry::Vacant(_) => None,
+            })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut T)> {
+        self.entries
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(key, entry)| match entry {
+                Entry::Occupied(elem) => Some((key, elem)),
+                Entry::Vacant(_) => None,
+            })
+    }
+
+    /// Remove all entries, and yield them in the order of keys.
+    ///
+    /// Entries not yielded are dropped with the iterator.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.len = 0;
+        self.next = 0;
+        self.entries.drain(..).filter_map(|entry| match entry {
+            Entry::Occupied(elem) => Some(elem),
+            Entry::Vacant(_) => None,
+        })
+    }
+
+    /// Remove the entries for which `f` returns false.
+    pub fn retain(&mut self, mut f: impl FnMut(usize, &mut T) -> bool) {
+        for key in 0..self.entries.len() {
+            if let Entry::Occupied(elem) = &mut self.entries[key]
+                && !f(key, elem)
+            {
+                self.remove(key);
+            }
+        }
+    }
+
+    /// Drop the trailing vacant entries, then give back the spare capacity,
+    /// e.g. after a burst.
+    ///
+    /// A bounded slab keeps its capacity. Keys of occupied entries are kept.
+    pub fn shrink_to_fit(&mut self) {
+        self.shrink_to(0);
+    }
+
+    /// Like [`Slab::shrink_to_fit`], but keeps a capacity of at least
+    /// `min_capacity`.
+    pub fn shrink_to(&mut self, min_capacity: usize) {
+        let used = self
+            .entries
+            .iter()
+            .rposition(|entry| matches!(entry, Entry::Occupied(_)))
+            .map_or(0, |key| key + 1);
+        if used < self.entries.len() {
+            self.entries.truncate(used);
+            self.relink();
+        }
+        if self.bound.is_none() {
+            self.entries.shrink_to(min_capacity);
+        }
+    }
+
+    /// Rebuild the list of vacant entries, lower keys are reused first.
+    fn relink(&mut self) {
+        self.next = self.entries.len();
+        for key in (0..self.entries.len()).rev() {
+            if let Entry::Vacant(next) = &mut self.entries[key] {
+                *next = self.next;
+                self.next = key;
+            }
+        }
+    }
+}
+
 impl<T> ops::Index<usize> for Slab<T> {
     type Output = T;
 
@@ -176,4 +293,76 @@ mod tests {
         assert_eq!(arena[key], 20);
         assert_eq!(arena.capacity(), 20);
     }
+
+    #[test]
+    fn test_iter() {
+        let mut slab = Slab::new();
+        for i in 0..5 {
+            slab.insert(i);
+        }
+        slab.remove(1);
+        slab.remove(3);
+
+        let items = slab.iter().map(|(k, v)| (k, *v)).collect::<Vec<_>>();
+        assert_eq!(items, [(0, 0), (2, 2), (4, 4)]);
+
+        slab.iter_mut().for_each(|(_, v)| *v *= 10);
+        slab.retain(|k, _| k != 2);
+        assert_eq!(slab.len(), 2);
+        assert_eq!(slab.iter().map(|(_, v)| *v).collect::<Vec<_>>(), [0, 40]);
+
+        assert_eq!(slab.drain().collect::<Vec<_>>(), [0, 40]);
+        assert!(slab.is_empty());
+        assert_eq!(slab.insert(7), 0);
+    }
+
+    #[test]
+    fn test_shrink() {
+        let mut slab = Slab::new();
+        for i in 0..100 {
+            slab.insert(i);
+        }
+        for key in (1..100).filter(|&k| k != 5) {
+            slab.remove(key);
+        }
+
+        slab.shrink_to_fit();
+        assert!(slab.capacity() < 100);
+        assert_eq!(slab[5], 5);
+        // Vacant keys below are reused first, then it grows.
+        let keys = (0..5).map(|i| slab.insert(i)).collect::<Vec<_>>();
+        assert_eq!(keys, [1, 2, 3, 4, 6]);
+        assert_eq!(slab.len(), 7);
+    }
+
+    #[test]
+    fn test_shrink_to() {
+        let mut slab = Slab::new();
+        for i in 0..100 {
+            slab.insert(i);
+        }
+        slab.drain().for_each(drop);
+
+        slab.shrink_to(10);
+        assert!((10..100).contains(&slab.capacity()));
+        assert_eq!(slab.insert(0), 0);
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut slab = Slab::bounded(2);
+        let capacity = slab.capacity();
+        let key = slab.try_insert(1).unwrap();
+        assert_eq!(slab.remaining(), Some(1));
+        slab.try_insert(2).unwrap();
+        assert!(slab.is_full());
+        assert_eq!(slab.remaining(), Some(0));
+        assert_eq!(slab.try_insert(3), Err(3));
+        assert_eq!(Slab::<i32>::new().remaining(), None);
+
+        slab.remove(key);
+        assert_eq!(slab.try_insert(3), Ok(key));
+        slab.shrink_to_fit();
+        assert_eq!(slab.capacity(), capacity);
+    }
 }